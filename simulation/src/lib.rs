@@ -0,0 +1,421 @@
+//! Host side simulation of several nodes sharing a virtual UWB medium
+//!
+//! Every node runs the control loops and the `Dw1000Wrapper` of the firmware on top of a
+//! `FakeRadio`. The medium models the time of flight between the nodes, packet loss, clock drift
+//! and timestamp noise and computes the distance from the exchanged timestamps just like
+//! `dw1000::ranging::compute_distance_mm` or, for double-sided ranging, `twr::Timestamps`.
+//!
+//! Frames of the double-sided exchange are sent at a delayed TX time like on the DW1000, which
+//! ignores its lower 9 bits, and leave the antenna after the TX antenna delay. Their embedded TX
+//! timestamps come from `twr::tx_timestamp`.
+
+mod node;
+mod rng;
+
+pub use bike_distance_indicator::role::Role;
+pub use node::{NodeConfig, PAN_ID, TARGET_DISTANCE, TOLERANCE};
+
+use bike_distance_indicator::indicator::DistanceRange;
+use bike_distance_indicator::radio::{FakeRequest, RangingFrame, SentFrame};
+use bike_distance_indicator::twr::{
+    self, RangingMode, RequestTimestamps, Timestamps, REPLY_SLOT, TIMESTAMP_MASK,
+};
+use node::{Node, Payload};
+use rng::Rng;
+
+/// Resolution of the DW1000 timestamps
+pub const TICKS_PER_SECOND: f64 = 499.2e6 * 128.0;
+const SPEED_OF_LIGHT: f64 = 299_792_458.0;
+/// The DW1000 ignores the lower 9 bits of a delayed TX time
+const DELAYED_TX_RESOLUTION: f64 = 512.0;
+/// TX antenna delay of all nodes in ticks
+const ANTENNA_DELAY: u64 = 16_300;
+
+pub type NodeId = usize;
+
+#[derive(Debug, Clone, Copy)]
+pub struct MediumConfig {
+    /// Probability that a frame is lost for a single receiver
+    pub packet_loss: f64,
+    /// Standard deviation of the RX timestamps in s
+    pub timestamp_noise: f64,
+    /// Air time of a frame in s
+    pub frame_duration: f64,
+    /// Delay between receiving a frame and sending the reply in local time in s
+    pub reply_delay: f64,
+    pub seed: u64,
+}
+
+impl Default for MediumConfig {
+    fn default() -> Self {
+        MediumConfig {
+            packet_loss: 0.0,
+            timestamp_noise: 0.0,
+            frame_duration: 200e-6,
+            reply_delay: 2e-3,
+            seed: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Frame {
+    source: NodeId,
+    payload: Payload,
+    /// True TX time in s
+    tx_time: f64,
+}
+
+enum Event {
+    Control(NodeId),
+    Arrival(NodeId, Frame),
+    TxDone(NodeId),
+}
+
+pub struct Simulation {
+    config: MediumConfig,
+    nodes: Vec<Node>,
+    events: Vec<(f64, u64, Event)>,
+    sequence: u64,
+    now: f64,
+    rng: Rng,
+}
+
+impl Simulation {
+    pub fn new(config: MediumConfig) -> Self {
+        Simulation {
+            config,
+            nodes: Vec::new(),
+            events: Vec::new(),
+            sequence: 0,
+            now: 0.0,
+            rng: Rng::new(config.seed),
+        }
+    }
+
+    pub fn add_node(&mut self, config: NodeConfig) -> NodeId {
+        let id = self.nodes.len();
+        self.nodes.push(Node::new(config));
+        self.schedule(self.now, Event::Control(id));
+        id
+    }
+
+    pub fn set_position(&mut self, node: NodeId, position: f64) {
+        let config = &mut self.nodes[node].config;
+        config.position = position - config.velocity * self.now;
+    }
+
+    /// Let the node move with `velocity` in m/s from now on
+    pub fn set_velocity(&mut self, node: NodeId, velocity: f64) {
+        let position = self.nodes[node].position(self.now);
+        let config = &mut self.nodes[node].config;
+        config.velocity = velocity;
+        config.position = position - velocity * self.now;
+    }
+
+    pub fn set_ranging_mode(&mut self, node: NodeId, mode: RangingMode) {
+        self.nodes[node].dw1000.set_ranging_mode(mode);
+    }
+
+    /// State of charge of the node's battery in % that it reports to its peers
+    pub fn set_battery(&mut self, node: NodeId, battery: Option<u8>) {
+        self.nodes[node].dw1000.set_battery(battery);
+    }
+
+    /// Lowest battery state of charge in % the peers of the node reported
+    pub fn partner_battery(&self, node: NodeId) -> Option<u8> {
+        self.nodes[node].dw1000.get_partner_battery()
+    }
+
+    pub fn set_packet_loss(&mut self, packet_loss: f64) {
+        self.config.packet_loss = packet_loss;
+    }
+
+    pub fn now(&self) -> f64 {
+        self.now
+    }
+
+    /// Current range shown by the node
+    pub fn range(&self, node: NodeId) -> DistanceRange {
+        self.nodes[node].range
+    }
+
+    /// Role of the node, `None` while it is negotiated
+    pub fn role(&self, node: NodeId) -> Option<Role> {
+        self.nodes[node].role()
+    }
+
+    /// All changes of the range shown by the node
+    pub fn ranges(&self, node: NodeId) -> &[DistanceRange] {
+        &self.nodes[node].ranges
+    }
+
+    /// Filtered distances in cm after every valid ranging response
+    pub fn distances(&self, node: NodeId) -> &[u64] {
+        &self.nodes[node].distances
+    }
+
+    /// Filtered distance in cm the node measured to the node with short address `address`
+    pub fn peer_distance(&self, node: NodeId, address: u16) -> Option<u64> {
+        self.nodes[node]
+            .dw1000
+            .get_peer(address)
+            .and_then(|peer| peer.get_filtered_distance())
+    }
+
+    pub fn run_for(&mut self, duration: f64) {
+        let end = self.now + duration;
+
+        while let Some(index) = self.next_event(end) {
+            let (time, _, event) = self.events.swap_remove(index);
+            self.now = time;
+
+            match event {
+                Event::Control(id) => {
+                    let period = self.nodes[id].control(self.now);
+                    self.transmit(id, self.now);
+                    self.schedule(self.now + period, Event::Control(id));
+                }
+                Event::Arrival(id, frame) => self.receive(id, frame),
+                Event::TxDone(id) => self.nodes[id].interrupt(self.now),
+            }
+        }
+
+        self.now = end;
+    }
+
+    fn next_event(&self, end: f64) -> Option<usize> {
+        self.events
+            .iter()
+            .enumerate()
+            .filter(|(_, (time, _, _))| *time <= end)
+            .min_by(|(_, a), (_, b)| a.0.partial_cmp(&b.0).unwrap().then(a.1.cmp(&b.1)))
+            .map(|(index, _)| index)
+    }
+
+    fn schedule(&mut self, time: f64, event: Event) {
+        self.events.push((time, self.sequence, event));
+        self.sequence += 1;
+    }
+
+    fn receive(&mut self, id: NodeId, frame: Frame) {
+        if self.rng.uniform() < self.config.packet_loss {
+            return;
+        }
+
+        let time_of_flight = self.time_of_flight(frame.source, id);
+        let noise = self.rng.gaussian() * self.config.timestamp_noise * TICKS_PER_SECOND;
+        let rx_time = self.nodes[id].local_time(frame.tx_time + time_of_flight) + noise;
+        let source = self.nodes[frame.source].mac_address();
+        let node = &mut self.nodes[id];
+
+        let ranging_frame = match frame.payload {
+            Payload::Ping { .. } => RangingFrame::Ping(source),
+            Payload::Request {
+                ping_tx,
+                ping_reply,
+                request_tx,
+            } => RangingFrame::RangingRequest(FakeRequest {
+                source,
+                timestamps: Some(RequestTimestamps {
+                    ping_tx: timestamp(ping_tx),
+                    ping_reply: ping_reply as u64,
+                    request_tx: timestamp(request_tx),
+                    request_rx: timestamp(rx_time),
+                }),
+            }),
+            Payload::Response {
+                ping_reply,
+                ping_round_trip,
+                request_tx,
+                request_reply,
+            } => RangingFrame::RangingResponse(
+                source,
+                compute_distance_mm(
+                    ping_reply,
+                    ping_round_trip,
+                    request_reply,
+                    rx_time - request_tx,
+                ),
+            ),
+            Payload::Poll => RangingFrame::Ping(source),
+            Payload::TwrResponse {
+                poll_rx,
+                response_tx,
+            } => RangingFrame::RangingRequest(FakeRequest {
+                source,
+                timestamps: node.poll_tx.map(|poll_tx| RequestTimestamps {
+                    ping_tx: timestamp(poll_tx),
+                    ping_reply: twr::duration(timestamp(poll_rx), timestamp(response_tx)),
+                    request_tx: timestamp(response_tx),
+                    request_rx: timestamp(rx_time),
+                }),
+            }),
+            Payload::Final {
+                poll_tx,
+                response_rx,
+                final_tx,
+            } => RangingFrame::RangingResponse(
+                source,
+                node.response.take().and_then(|(poll_rx, response_tx)| {
+                    Timestamps {
+                        poll_tx: timestamp(poll_tx),
+                        poll_rx: timestamp(poll_rx),
+                        response_tx: timestamp(response_tx),
+                        response_rx: timestamp(response_rx),
+                        final_tx: timestamp(final_tx),
+                        final_rx: timestamp(rx_time),
+                    }
+                    .compute_distance_mm()
+                }),
+            ),
+            Payload::Report(report) => RangingFrame::Report(source, report),
+        };
+
+        if node.dw1000.radio().deliver(ranging_frame) {
+            node.last_rx = Some((frame.payload, rx_time));
+            node.interrupt(self.now);
+
+            // Requests are sent in the reply slot of the tag
+            let slot_delay = match frame.payload {
+                Payload::Ping { .. } | Payload::Poll => {
+                    node.dw1000.radio().get_reply_slot() as f64 * REPLY_SLOT as f64
+                        / TICKS_PER_SECOND
+                }
+                _ => 0.0,
+            };
+            let reply_time = self.now - self.config.frame_duration
+                + node.true_duration(self.config.reply_delay + slot_delay);
+            self.transmit(id, reply_time);
+        }
+    }
+
+    /// Put the frame sent by the node, if any, on the medium
+    fn transmit(&mut self, id: NodeId, tx_time: f64) {
+        let node = &mut self.nodes[id];
+        let sent = match node.dw1000.radio().take_sent() {
+            Some(sent) => sent,
+            None => return,
+        };
+
+        let tx_local = node.local_time(tx_time);
+        let double_sided = node.dw1000.radio().get_ranging_mode() == RangingMode::DoubleSided;
+
+        // Delayed TX of the double-sided exchange with `tx_local` as DX_TIME
+        let delayed_tx = tx_local.floor();
+        let departure = delayed_tx - delayed_tx % DELAYED_TX_RESOLUTION + ANTENNA_DELAY as f64;
+        let twr_tx_time = tx_time + node.true_duration((departure - tx_local) / TICKS_PER_SECOND);
+        let twr_tx = twr::tx_timestamp(timestamp(delayed_tx), ANTENNA_DELAY) as f64;
+
+        let (destination, payload) = match (sent, node.last_rx) {
+            (SentFrame::Ping, _) if double_sided => {
+                self.nodes[id].poll_tx = Some(twr_tx);
+                (None, Payload::Poll)
+            }
+            (SentFrame::Ping, _) => (None, Payload::Ping { ping_tx: tx_local }),
+            (SentFrame::RangingRequest(address), Some((Payload::Poll, rx_time))) => {
+                self.nodes[id].response = Some((rx_time, twr_tx));
+                (
+                    self.find_node(address),
+                    Payload::TwrResponse {
+                        poll_rx: rx_time,
+                        response_tx: twr_tx,
+                    },
+                )
+            }
+            (SentFrame::RangingRequest(address), Some((Payload::Ping { ping_tx }, rx_time))) => (
+                self.find_node(address),
+                Payload::Request {
+                    ping_tx,
+                    ping_reply: tx_local - rx_time,
+                    request_tx: tx_local,
+                },
+            ),
+            (
+                SentFrame::RangingResponse(address),
+                Some((
+                    Payload::Request {
+                        ping_tx,
+                        ping_reply,
+                        request_tx,
+                    },
+                    rx_time,
+                )),
+            ) => (
+                self.find_node(address),
+                Payload::Response {
+                    ping_reply,
+                    ping_round_trip: rx_time - ping_tx,
+                    request_tx,
+                    request_reply: tx_local - rx_time,
+                },
+            ),
+            (SentFrame::RangingResponse(address), Some((Payload::TwrResponse { .. }, rx_time))) => {
+                (
+                    self.find_node(address),
+                    Payload::Final {
+                        poll_tx: self.nodes[id].poll_tx.expect("Response without poll"),
+                        response_rx: rx_time,
+                        final_tx: twr_tx,
+                    },
+                )
+            }
+            (SentFrame::Report(report), _) => (None, Payload::Report(report)),
+            (sent, _) => panic!("{:?} does not answer the last received frame", sent),
+        };
+
+        let tx_time = match payload {
+            Payload::Poll | Payload::TwrResponse { .. } | Payload::Final { .. } => twr_tx_time,
+            _ => tx_time,
+        };
+
+        let frame = Frame {
+            source: id,
+            payload,
+            tx_time,
+        };
+
+        self.schedule(tx_time + self.config.frame_duration, Event::TxDone(id));
+
+        for receiver in 0..self.nodes.len() {
+            if receiver != id && destination.unwrap_or(receiver) == receiver {
+                let arrival =
+                    tx_time + self.time_of_flight(id, receiver) + self.config.frame_duration;
+                self.schedule(arrival, Event::Arrival(receiver, frame));
+            }
+        }
+    }
+
+    fn find_node(&self, address: dw1000::mac::Address) -> Option<NodeId> {
+        self.nodes
+            .iter()
+            .position(|node| node.mac_address() == address)
+    }
+
+    fn time_of_flight(&self, a: NodeId, b: NodeId) -> f64 {
+        (self.nodes[a].position(self.now) - self.nodes[b].position(self.now)).abs() / SPEED_OF_LIGHT
+    }
+}
+
+/// Raw 40 bit DW1000 timestamp of a local time in ticks
+fn timestamp(local_time: f64) -> u64 {
+    local_time as u64 & TIMESTAMP_MASK
+}
+
+/// Asymmetric double-sided two-way ranging like `dw1000::ranging::compute_distance_mm`, which
+/// assumes a tick of 1/64 ns
+fn compute_distance_mm(
+    ping_reply: f64,
+    ping_round_trip: f64,
+    request_reply: f64,
+    request_round_trip: f64,
+) -> Option<u64> {
+    let time_of_flight = (ping_round_trip * request_round_trip - ping_reply * request_reply)
+        / (ping_round_trip + request_round_trip + ping_reply + request_reply);
+
+    if time_of_flight < 0.0 {
+        None
+    } else {
+        Some((time_of_flight / 64e9 * SPEED_OF_LIGHT * 1000.0) as u64)
+    }
+}
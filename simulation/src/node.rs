@@ -0,0 +1,347 @@
+use crate::TICKS_PER_SECOND;
+use bike_distance_indicator::config::DEFAULT_CONFIG;
+use bike_distance_indicator::control::{AnchorControl, ConvoyControl, RadioCommand, TagControl};
+use bike_distance_indicator::dw1000::{Dw1000MessageType, Dw1000State, Dw1000Wrapper};
+use bike_distance_indicator::indicator::DistanceRange;
+use bike_distance_indicator::pairing::{Pairing, DEFAULT_PAN_ID};
+use bike_distance_indicator::radio::FakeRadio;
+use bike_distance_indicator::report::ReportMessage;
+use bike_distance_indicator::role::{ReplySlot, Role, RoleNegotiation};
+use dw1000::mac;
+
+/// Control period of the anchor in s
+const ANCHOR_PERIOD: f64 = DEFAULT_CONFIG.anchor_period as f64 / 1000.0;
+/// Control period of the tag in s while an anchor is detected
+const TAG_PERIOD: f64 = DEFAULT_CONFIG.tag_period as f64 / 1000.0;
+/// Control period of the tag in s while searching for an anchor
+const TAG_PERIOD_SLOW: f64 = DEFAULT_CONFIG.tag_period_slow as f64 / 1000.0;
+/// Control period of a convoy node in s
+const CONVOY_PERIOD: f64 = 0.01;
+
+pub const PAN_ID: u16 = DEFAULT_PAN_ID;
+pub const TARGET_DISTANCE: u64 = DEFAULT_CONFIG.profiles[0].target_distance as u64;
+pub const TOLERANCE: u64 = DEFAULT_CONFIG.profiles[0].tolerance as u64;
+
+#[derive(Debug, Clone, Copy)]
+pub struct NodeConfig {
+    pub address: u16,
+    pub role: Role,
+    /// Position on a line in m at time 0
+    pub position: f64,
+    /// Velocity along the line in m/s
+    pub velocity: f64,
+    /// Deviation of the crystal from its nominal frequency
+    pub clock_drift_ppm: f64,
+    /// Offset of the local clock in s
+    pub clock_offset: f64,
+    /// Negotiate the role at boot instead of using `role`
+    pub negotiate_role: bool,
+    /// Private PAN ID and address instead of `PAN_ID` and `address`
+    pub pairing: Option<Pairing>,
+}
+
+impl NodeConfig {
+    pub fn new(address: u16, role: Role, position: f64) -> Self {
+        NodeConfig {
+            address,
+            role,
+            position,
+            velocity: 0.0,
+            clock_drift_ppm: 0.0,
+            clock_offset: 0.0,
+            negotiate_role: false,
+            pairing: None,
+        }
+    }
+}
+
+/// Contents of a frame on the medium. All timestamps are in local ticks of the respective node.
+#[derive(Debug, Clone, Copy)]
+pub enum Payload {
+    Ping {
+        ping_tx: f64,
+    },
+    Request {
+        ping_tx: f64,
+        ping_reply: f64,
+        request_tx: f64,
+    },
+    Response {
+        ping_reply: f64,
+        ping_round_trip: f64,
+        request_tx: f64,
+        request_reply: f64,
+    },
+    Poll,
+    TwrResponse {
+        poll_rx: f64,
+        response_tx: f64,
+    },
+    Final {
+        poll_tx: f64,
+        response_rx: f64,
+        final_tx: f64,
+    },
+    Report(ReportMessage),
+}
+
+enum Control {
+    Negotiation,
+    Anchor(AnchorControl),
+    Tag(TagControl),
+    Convoy(ConvoyControl),
+}
+
+impl Control {
+    fn new(role: Role) -> Self {
+        match role {
+            Role::Anchor => Control::Anchor(AnchorControl::new()),
+            Role::Tag => Control::Tag(TagControl::new()),
+            Role::Convoy(position) => Control::Convoy(ConvoyControl::new(position)),
+        }
+    }
+}
+
+pub struct Node {
+    pub config: NodeConfig,
+    pub dw1000: Dw1000Wrapper<FakeRadio>,
+    control: Control,
+    negotiation: RoleNegotiation,
+    ping_seen: bool,
+    valid_response_seen: bool,
+    /// Last received payload and its RX timestamp
+    pub last_rx: Option<(Payload, f64)>,
+    /// TX timestamp of the last poll as initiator of a double-sided exchange
+    pub poll_tx: Option<f64>,
+    /// RX timestamp of the last poll and TX timestamp of the response as responder
+    pub response: Option<(f64, f64)>,
+    pub range: DistanceRange,
+    pub ranges: Vec<DistanceRange>,
+    pub distances: Vec<u64>,
+}
+
+impl Node {
+    pub fn new(config: NodeConfig) -> Self {
+        let negotiation = if config.negotiate_role {
+            RoleNegotiation::new(config.address)
+        } else {
+            RoleNegotiation::fixed(config.role)
+        };
+        let control = negotiation
+            .get_role()
+            .map_or(Control::Negotiation, Control::new);
+
+        let mut dw1000 = Dw1000Wrapper::new(FakeRadio::new());
+        dw1000.set_reply_slot(ReplySlot::new(config.role, config.address));
+        dw1000.set_ping_filter(config.role.ping_filter());
+        if let Some(pairing) = config.pairing {
+            dw1000.set_pairing(pairing).unwrap();
+        }
+
+        Node {
+            config,
+            dw1000,
+            control,
+            negotiation,
+            ping_seen: false,
+            valid_response_seen: false,
+            last_rx: None,
+            poll_tx: None,
+            response: None,
+            range: DistanceRange::OutOfRange,
+            ranges: Vec::new(),
+            distances: Vec::new(),
+        }
+    }
+
+    /// `None` while the role is negotiated
+    pub fn role(&self) -> Option<Role> {
+        self.negotiation.get_role()
+    }
+
+    pub fn mac_address(&self) -> mac::Address {
+        match self.config.pairing {
+            Some(pairing) => mac::Address::Short(
+                mac::PanId(pairing.pan_id),
+                mac::ShortAddress(pairing.address),
+            ),
+            None => mac::Address::Short(mac::PanId(PAN_ID), mac::ShortAddress(self.config.address)),
+        }
+    }
+
+    /// Position at true time `t`
+    pub fn position(&self, t: f64) -> f64 {
+        self.config.position + self.config.velocity * t
+    }
+
+    /// Local timestamp in ticks at true time `t`
+    pub fn local_time(&self, t: f64) -> f64 {
+        (t * (1.0 + self.config.clock_drift_ppm * 1e-6) + self.config.clock_offset)
+            * TICKS_PER_SECOND
+    }
+
+    /// True duration of a duration measured with the local clock
+    pub fn true_duration(&self, local_duration: f64) -> f64 {
+        local_duration / (1.0 + self.config.clock_drift_ppm * 1e-6)
+    }
+
+    /// Local time in ms at true time `t`
+    fn local_time_ms(&self, t: f64) -> u32 {
+        (self.local_time(t) / TICKS_PER_SECOND * 1000.0) as u32
+    }
+
+    /// Run one control cycle at true time `t` like `negotiate_role`, `control_anchor`,
+    /// `control_tag` and `control_convoy` and return the true duration until the next one
+    pub fn control(&mut self, t: f64) -> f64 {
+        let now_ms = self.local_time_ms(t);
+
+        match &mut self.control {
+            Control::Negotiation => {
+                match self.negotiation.step(self.ping_seen) {
+                    Some(Role::Anchor) => {
+                        // Announce the anchor right away
+                        let _ = self.dw1000.finish_receiving();
+                        let _ = self.dw1000.send_ping();
+                        self.control = Control::new(Role::Anchor);
+                    }
+                    Some(role) => self.control = Control::new(role),
+                    None => {
+                        if self.dw1000.get_state() == Dw1000State::Ready {
+                            let _ = self.dw1000.start_receiving();
+                        }
+                    }
+                }
+                self.ping_seen = false;
+
+                self.true_duration(TAG_PERIOD)
+            }
+            Control::Anchor(control) => {
+                // A negotiated anchor gives way to another anchor
+                if let Some(role) = self.negotiation.step(self.ping_seen) {
+                    self.dw1000.reset_distance();
+                    self.control = Control::new(role);
+                    return self.true_duration(TAG_PERIOD);
+                }
+                self.ping_seen = false;
+
+                self.dw1000.remove_stale_peers(now_ms);
+                let range = self.dw1000.get_worst_range(TARGET_DISTANCE, TOLERANCE);
+
+                let step = control.step(self.dw1000.get_state() == Dw1000State::Receiving);
+
+                if step.finish_receiving {
+                    let _ = self.dw1000.finish_receiving();
+                }
+                if step.send_ping {
+                    let _ = self.dw1000.send_ping();
+                }
+
+                self.set_range(range);
+
+                self.true_duration(ANCHOR_PERIOD)
+            }
+            Control::Tag(control) => {
+                let step = control.step(self.ping_seen, self.valid_response_seen);
+
+                self.ping_seen = false;
+                self.valid_response_seen = false;
+
+                match step.command {
+                    Some(RadioCommand::FinishReceiving) => {
+                        let _ = self.dw1000.finish_receiving();
+                    }
+                    Some(RadioCommand::StartReceiving) => {
+                        let _ = self.dw1000.start_receiving();
+                    }
+                    None => (),
+                }
+
+                if step.set_out_of_range {
+                    self.set_range(DistanceRange::OutOfRange);
+                    self.dw1000.reset_distance();
+                }
+
+                if step.anchor_detected {
+                    self.true_duration(TAG_PERIOD)
+                } else {
+                    self.true_duration(TAG_PERIOD_SLOW)
+                }
+            }
+            Control::Convoy(control) => {
+                self.dw1000.remove_stale_peers(now_ms);
+                let range = self.dw1000.get_worst_range(TARGET_DISTANCE, TOLERANCE);
+
+                let step = control.step(now_ms, self.dw1000.get_state());
+
+                match step.command {
+                    Some(RadioCommand::FinishReceiving) => {
+                        let _ = self.dw1000.finish_receiving();
+                    }
+                    Some(RadioCommand::StartReceiving) => {
+                        let _ = self.dw1000.start_receiving();
+                    }
+                    None => (),
+                }
+                if step.send_ping {
+                    let _ = self.dw1000.send_ping();
+                }
+
+                self.set_range(range);
+
+                self.true_duration(CONVOY_PERIOD)
+            }
+        }
+    }
+
+    /// Handle the radio interrupt at true time `t` like `exti2` and `receive_message`
+    pub fn interrupt(&mut self, t: f64) {
+        if self.dw1000.handle_interrupt().is_err() {
+            return;
+        }
+
+        match self.dw1000.get_state() {
+            Dw1000State::Ready => {}
+            Dw1000State::Sending => {
+                let _ = self.dw1000.finish_sending();
+                let _ = self.dw1000.start_receiving();
+            }
+            Dw1000State::Receiving => {
+                let timestamp_ms = self.local_time_ms(t);
+
+                match self.dw1000.receive_message(timestamp_ms) {
+                    Ok(Dw1000MessageType::RangingResponse(true)) => {
+                        let filtered_distance = self.dw1000.get_filtered_distance().unwrap();
+                        self.valid_response_seen = true;
+                        self.distances.push(filtered_distance);
+                        let range = DistanceRange::from_distance_and_velocity(
+                            filtered_distance,
+                            self.dw1000.get_velocity(),
+                            TARGET_DISTANCE,
+                            TOLERANCE,
+                        );
+                        self.set_range(range);
+                        let _ = self.dw1000.send_report(filtered_distance, range);
+                    }
+                    Ok(Dw1000MessageType::Ping(true)) => {
+                        self.ping_seen = true;
+                        if let Control::Convoy(control) = &mut self.control {
+                            control.predecessor_ping(timestamp_ms);
+                        }
+                    }
+                    _ => {}
+                }
+
+                if self.dw1000.get_state() != Dw1000State::Sending {
+                    let _ = self.dw1000.start_receiving();
+                }
+            }
+        }
+    }
+
+    fn set_range(&mut self, range: DistanceRange) {
+        if range != self.range {
+            self.range = range;
+            self.ranges.push(range);
+        }
+    }
+}
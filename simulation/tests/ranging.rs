@@ -0,0 +1,392 @@
+use bike_distance_indicator::control::convoy_address;
+use bike_distance_indicator::dw1000::correct_distance;
+use bike_distance_indicator::indicator::DistanceRange;
+use bike_distance_indicator::pairing::Pairing;
+use bike_distance_indicator::twr::RangingMode;
+use simulation::{MediumConfig, NodeConfig, NodeId, Role, Simulation};
+
+fn setup(config: MediumConfig, tag: NodeConfig) -> (Simulation, NodeId) {
+    let mut simulation = Simulation::new(config);
+    simulation.add_node(NodeConfig::new(0x1234, Role::Anchor, 0.0));
+    let tag = simulation.add_node(tag);
+    (simulation, tag)
+}
+
+#[test]
+fn tag_shows_target_distance() {
+    let (mut simulation, tag) = setup(
+        MediumConfig::default(),
+        NodeConfig::new(0x1235, Role::Tag, 1.2),
+    );
+
+    simulation.run_for(20.0);
+
+    assert!(simulation.distances(tag).len() > 10);
+    assert_eq!(simulation.range(tag), DistanceRange::Ok);
+    assert!(!simulation.ranges(tag).contains(&DistanceRange::Long));
+}
+
+#[test]
+fn tag_follows_changing_distance() {
+    let (mut simulation, tag) = setup(
+        MediumConfig::default(),
+        NodeConfig::new(0x1235, Role::Tag, 1.2),
+    );
+
+    simulation.run_for(20.0);
+    assert_eq!(simulation.range(tag), DistanceRange::Ok);
+
+    simulation.set_position(tag, 3.0);
+    simulation.run_for(20.0);
+    assert_eq!(simulation.range(tag), DistanceRange::Long);
+
+    simulation.set_position(tag, 0.3);
+    simulation.run_for(20.0);
+    assert_eq!(simulation.range(tag), DistanceRange::Short);
+
+    let ranges = simulation.ranges(tag);
+    let long = ranges
+        .iter()
+        .position(|r| *r == DistanceRange::Long)
+        .unwrap();
+    let short = ranges
+        .iter()
+        .rposition(|r| *r == DistanceRange::Short)
+        .unwrap();
+    assert!(long < short);
+}
+
+#[test]
+fn anchor_shows_range_of_tag() {
+    let (mut simulation, tag) = setup(
+        MediumConfig::default(),
+        NodeConfig::new(0x1235, Role::Tag, 1.2),
+    );
+    // The anchor is the first node of `setup`
+    let anchor = 0;
+
+    for position in [1.2, 3.0, 0.3, 1.5].iter() {
+        simulation.set_position(tag, *position);
+        simulation.run_for(10.0);
+        assert_eq!(simulation.range(anchor), simulation.range(tag));
+    }
+}
+
+#[test]
+fn units_know_the_battery_of_their_partner() {
+    let (mut simulation, tag) = setup(
+        MediumConfig::default(),
+        NodeConfig::new(0x1235, Role::Tag, 1.2),
+    );
+    // The anchor is the first node of `setup`
+    let anchor = 0;
+    simulation.set_battery(anchor, Some(12));
+    simulation.set_battery(tag, Some(80));
+
+    simulation.run_for(5.0);
+
+    assert_eq!(simulation.partner_battery(tag), Some(12));
+    assert_eq!(simulation.partner_battery(anchor), Some(80));
+}
+
+#[test]
+fn tag_goes_out_of_range() {
+    let (mut simulation, tag) = setup(
+        MediumConfig::default(),
+        NodeConfig::new(0x1235, Role::Tag, 1.2),
+    );
+
+    simulation.run_for(20.0);
+    assert_eq!(simulation.range(tag), DistanceRange::Ok);
+
+    simulation.set_packet_loss(1.0);
+    simulation.run_for(20.0);
+    assert_eq!(simulation.range(tag), DistanceRange::OutOfRange);
+
+    simulation.set_packet_loss(0.0);
+    simulation.run_for(20.0);
+    assert_eq!(simulation.range(tag), DistanceRange::Ok);
+}
+
+#[test]
+fn ranging_with_imperfect_medium() {
+    let config = MediumConfig {
+        packet_loss: 0.1,
+        timestamp_noise: 100e-12,
+        ..MediumConfig::default()
+    };
+    let mut tag = NodeConfig::new(0x1235, Role::Tag, 1.2);
+    tag.clock_drift_ppm = 20.0;
+    tag.clock_offset = 1.5;
+
+    let (mut simulation, tag) = setup(config, tag);
+
+    simulation.run_for(30.0);
+
+    assert!(simulation.distances(tag).len() > 10);
+    assert_eq!(simulation.range(tag), DistanceRange::Ok);
+}
+
+#[test]
+fn closing_fast_is_shown_before_short() {
+    let (mut simulation, tag) = setup(
+        MediumConfig::default(),
+        NodeConfig::new(0x1235, Role::Tag, 5.0),
+    );
+
+    simulation.run_for(20.0);
+    assert_eq!(simulation.range(tag), DistanceRange::Long);
+
+    // Close up with 1.2 m/s and stop at 1.4 m
+    simulation.set_velocity(tag, -1.2);
+    simulation.run_for(3.0);
+    simulation.set_velocity(tag, 0.0);
+    simulation.run_for(20.0);
+
+    let ranges = simulation.ranges(tag);
+    assert!(ranges.contains(&DistanceRange::ClosingFast));
+    assert!(!ranges.contains(&DistanceRange::Short));
+    assert_eq!(simulation.range(tag), DistanceRange::Ok);
+}
+
+/// Mean of the last filtered distances after ranging over 1.2 m with drifting clocks, whose
+/// timers wrap around during the run
+fn distance_with_clock_offsets(mode: RangingMode) -> u64 {
+    let config = MediumConfig {
+        timestamp_noise: 100e-12,
+        ..MediumConfig::default()
+    };
+    let mut anchor = NodeConfig::new(0x1234, Role::Anchor, 0.0);
+    anchor.clock_drift_ppm = -20.0;
+    anchor.clock_offset = 12.0;
+    let mut tag = NodeConfig::new(0x1235, Role::Tag, 1.2);
+    tag.clock_drift_ppm = 20.0;
+    tag.clock_offset = 3.0;
+
+    let mut simulation = Simulation::new(config);
+    let anchor = simulation.add_node(anchor);
+    let tag = simulation.add_node(tag);
+    simulation.set_ranging_mode(anchor, mode);
+    simulation.set_ranging_mode(tag, mode);
+
+    simulation.run_for(30.0);
+
+    let distances = simulation.distances(tag);
+    assert!(distances.len() > 10);
+    assert_eq!(simulation.range(tag), DistanceRange::Ok);
+    distances[distances.len() - 10..].iter().sum::<u64>() / 10
+}
+
+#[test]
+fn double_sided_ranging_matches_dw1000_ranging() {
+    let dw1000 = distance_with_clock_offsets(RangingMode::Dw1000);
+    let double_sided = distance_with_clock_offsets(RangingMode::DoubleSided);
+
+    assert!(
+        dw1000.abs_diff(double_sided) <= 3,
+        "{} {}",
+        dw1000,
+        double_sided
+    );
+    assert!(
+        double_sided.abs_diff(correct_distance(120)) <= 3,
+        "{}",
+        double_sided
+    );
+}
+
+#[test]
+fn anchor_ranges_with_several_tags() {
+    for mode in [RangingMode::Dw1000, RangingMode::DoubleSided].iter() {
+        let mut simulation = Simulation::new(MediumConfig {
+            timestamp_noise: 100e-12,
+            ..MediumConfig::default()
+        });
+
+        let mut anchor = NodeConfig::new(0x1234, Role::Anchor, 0.0);
+        anchor.clock_drift_ppm = 10.0;
+        let mut near = NodeConfig::new(0x2001, Role::Tag, 1.2);
+        near.clock_drift_ppm = -15.0;
+        let mut far = NodeConfig::new(0x2002, Role::Tag, -3.0);
+        far.clock_drift_ppm = 20.0;
+
+        let anchor = simulation.add_node(anchor);
+        let near = simulation.add_node(near);
+        let far = simulation.add_node(far);
+        for node in [anchor, near, far].iter() {
+            simulation.set_ranging_mode(*node, *mode);
+        }
+
+        simulation.run_for(20.0);
+
+        assert_eq!(simulation.range(near), DistanceRange::Ok);
+        assert_eq!(simulation.range(far), DistanceRange::Long);
+        assert_eq!(simulation.range(anchor), DistanceRange::Long);
+
+        let near_distance = simulation.peer_distance(anchor, 0x2001).unwrap();
+        let far_distance = simulation.peer_distance(anchor, 0x2002).unwrap();
+        assert!(
+            near_distance.abs_diff(correct_distance(120)) <= 5,
+            "{}",
+            near_distance
+        );
+        assert!(
+            far_distance.abs_diff(correct_distance(300)) <= 5,
+            "{}",
+            far_distance
+        );
+
+        // The far tag leaves, the anchor only shows the near one
+        simulation.set_position(far, -50.0);
+        simulation.run_for(10.0);
+        assert_eq!(simulation.peer_distance(anchor, 0x2002), None);
+        assert_eq!(simulation.range(anchor), DistanceRange::Ok);
+    }
+}
+
+#[test]
+fn tags_in_the_same_reply_slot_split_up() {
+    for mode in [RangingMode::Dw1000, RangingMode::DoubleSided].iter() {
+        let mut simulation = Simulation::new(MediumConfig::default());
+
+        // Both addresses start in reply slot 1, the anchor only gets the request of the near tag
+        let anchor = simulation.add_node(NodeConfig::new(0x1234, Role::Anchor, 0.0));
+        let near = simulation.add_node(NodeConfig::new(0x2001, Role::Tag, 1.2));
+        let far = simulation.add_node(NodeConfig::new(0x2005, Role::Tag, -3.0));
+        for node in [anchor, near, far].iter() {
+            simulation.set_ranging_mode(*node, *mode);
+        }
+
+        simulation.run_for(20.0);
+
+        assert_eq!(simulation.range(near), DistanceRange::Ok);
+        assert_eq!(simulation.range(far), DistanceRange::Long);
+        assert!(simulation.peer_distance(anchor, 0x2001).is_some());
+        assert!(simulation.peer_distance(anchor, 0x2005).is_some());
+    }
+}
+
+#[test]
+fn convoy_ranges_with_predecessor_and_successor() {
+    for mode in [RangingMode::Dw1000, RangingMode::DoubleSided].iter() {
+        let mut simulation = Simulation::new(MediumConfig {
+            timestamp_noise: 100e-12,
+            ..MediumConfig::default()
+        });
+
+        let mut nodes = Vec::new();
+        for (position, (x, drift)) in [(0.0, 10.0), (-1.2, -15.0), (-4.2, 20.0)]
+            .iter()
+            .enumerate()
+        {
+            let mut config = NodeConfig::new(
+                convoy_address(position as u8),
+                Role::Convoy(position as u8),
+                *x,
+            );
+            config.clock_drift_ppm = *drift;
+            let node = simulation.add_node(config);
+            simulation.set_ranging_mode(node, *mode);
+            nodes.push(node);
+        }
+
+        simulation.run_for(20.0);
+
+        let expected = [
+            (nodes[0], convoy_address(1), 120),
+            (nodes[1], convoy_address(0), 120),
+            (nodes[1], convoy_address(2), 300),
+            (nodes[2], convoy_address(1), 300),
+        ];
+        for (node, address, distance) in expected.iter() {
+            let measured = simulation.peer_distance(*node, *address).unwrap();
+            assert!(
+                measured.abs_diff(correct_distance(*distance)) <= 5,
+                "{:04x}: {}",
+                address,
+                measured
+            );
+        }
+        assert_eq!(simulation.peer_distance(nodes[0], convoy_address(2)), None);
+        assert_eq!(simulation.peer_distance(nodes[2], convoy_address(0)), None);
+
+        assert_eq!(simulation.range(nodes[0]), DistanceRange::Ok);
+        assert_eq!(simulation.range(nodes[1]), DistanceRange::Long);
+        assert_eq!(simulation.range(nodes[2]), DistanceRange::Long);
+
+        // The last bike drops back, the middle one only shows the head
+        simulation.set_position(nodes[2], -50.0);
+        simulation.run_for(10.0);
+        assert_eq!(simulation.peer_distance(nodes[1], convoy_address(2)), None);
+        assert_eq!(simulation.range(nodes[1]), DistanceRange::Ok);
+    }
+}
+
+fn negotiating_unit(address: u16, position: f64, clock_drift_ppm: f64) -> NodeConfig {
+    let mut config = NodeConfig::new(address, Role::Tag, position);
+    config.negotiate_role = true;
+    config.clock_drift_ppm = clock_drift_ppm;
+    config
+}
+
+#[test]
+fn identical_units_negotiate_their_roles() {
+    let mut simulation = Simulation::new(MediumConfig::default());
+    let a = simulation.add_node(negotiating_unit(0x2001, 0.0, 0.0));
+    let b = simulation.add_node(negotiating_unit(0x2002, 1.2, 0.0));
+
+    simulation.run_for(0.5);
+    assert_eq!(simulation.role(a), None);
+    assert_eq!(simulation.role(b), None);
+
+    // The unit with the shorter back-off becomes the anchor
+    simulation.run_for(20.0);
+    assert_eq!(simulation.role(a), Some(Role::Tag));
+    assert_eq!(simulation.role(b), Some(Role::Anchor));
+    assert_eq!(simulation.range(a), DistanceRange::Ok);
+}
+
+#[test]
+fn units_with_same_backoff_end_up_with_one_anchor() {
+    let mut simulation = Simulation::new(MediumConfig::default());
+    // Both addresses result in the same back-off, both units become anchors at first
+    let a = simulation.add_node(negotiating_unit(0x2001, 0.0, 10.0));
+    let b = simulation.add_node(negotiating_unit(0x2038, 1.2, -10.0));
+
+    simulation.run_for(20.0);
+
+    let (tag, anchor) = match simulation.role(a) {
+        Some(Role::Tag) => (a, b),
+        _ => (b, a),
+    };
+    assert_eq!(simulation.role(tag), Some(Role::Tag));
+    assert_eq!(simulation.role(anchor), Some(Role::Anchor));
+    assert_eq!(simulation.range(tag), DistanceRange::Ok);
+}
+
+#[test]
+fn paired_units_ignore_other_pairs() {
+    let mut simulation = Simulation::new(MediumConfig::default());
+
+    // Two pairs next to each other, identified by the unique IDs of their units
+    let mut nodes = Vec::new();
+    for (anchor_id, tag_id, anchor_position, tag_position) in
+        [(0x0a31, 0x0a32, 0.0, 1.2), (0x0b41, 0x0b42, 0.3, 3.3)].iter()
+    {
+        let mut anchor = NodeConfig::new(*anchor_id, Role::Anchor, *anchor_position);
+        anchor.pairing = Some(Pairing::derive(*anchor_id, *tag_id));
+        let mut tag = NodeConfig::new(*tag_id, Role::Tag, *tag_position);
+        tag.pairing = Some(Pairing::derive(*tag_id, *anchor_id));
+
+        nodes.push((simulation.add_node(anchor), simulation.add_node(tag)));
+    }
+
+    simulation.run_for(20.0);
+
+    let (near_anchor, near_tag) = nodes[0];
+    let (far_anchor, far_tag) = nodes[1];
+    assert_eq!(simulation.range(near_tag), DistanceRange::Ok);
+    assert_eq!(simulation.range(far_tag), DistanceRange::Long);
+    assert_eq!(simulation.range(near_anchor), DistanceRange::Ok);
+    assert_eq!(simulation.range(far_anchor), DistanceRange::Long);
+}
@@ -1,40 +1,773 @@
-use crate::types::{BatteryAdcType, BatteryChType};
-use embedded_hal::adc::OneShot;
+//! Battery voltage and state of charge
+//!
+//! The state of charge is estimated from the open-circuit voltage of the cell with a lookup table
+//! of the discharge curve. Between two points of the table it is interpolated linearly.
+//!
+//! The supply voltage of the ADC drops with the regulator as the cell sags, so it is measured
+//! with the internal reference VREFINT on every reading instead of assuming 3.3 V.
+//!
+//! The voltage sags under load, e.g. while the LEDs are bright, so the LEDs are dimmed for a
+//! measurement. A worse state is only taken after [`LOW_READINGS`] readings in a row and a better
+//! state only once the reading clears the threshold by [`HYSTERESIS_PERCENT`] or
+//! [`HYSTERESIS_VOLTAGE`], see [`BatteryDebouncer`].
+//!
+//! An external charger is detected from the step of the voltage when it is connected, see
+//! [`ChargeDetector`].
+//!
+//! [`BatteryMonitor`] works with any `OneShot` ADC, so it can be tested on the host.
 
+use crate::error::Error;
+use core::marker::PhantomData;
+use defmt::Format;
+use embedded_hal::adc::{Channel, OneShot};
+
+/// Nominal voltage of the internal reference in mV
+const VREFINT: u32 = 1200;
+/// Nominal supply voltage of the ADC in mV, used if the reference reading is implausible
 const SUPPLY_VOLTAGE: u32 = 3300;
-const LOW_BAT_THRESHOLD: u32 = 3450;
+/// Supply voltages in mV the regulator can deliver
+const SUPPLY_VOLTAGE_RANGE: (u32, u32) = (2000, 3600);
+/// Ratio of the voltage divider at the battery pin
 const VOLTAGE_FACTOR: u32 = 2;
+/// Number of ADC samples that are averaged for one reading
+const SAMPLES: u32 = 8;
+/// Number of times a sample is retried while the ADC is busy
+const ADC_RETRIES: u32 = 100;
+
+/// Number of failed readings in a row until the battery state is unknown
+pub const ADC_FAILURES: u8 = 3;
+/// Number of readings in a row below the current state until the state gets worse
+pub const LOW_READINGS: u8 = 3;
+/// A better state needs a state of charge that much in % above its threshold
+pub const HYSTERESIS_PERCENT: u8 = 5;
+/// An empty battery needs a voltage that much in mV above [`LOW_BAT_THRESHOLD`] to recover
+pub const HYSTERESIS_VOLTAGE: u16 = 50;
+/// Time in ms the battery voltage needs to recover after the LEDs were dimmed
+pub const SETTLE_TIME: u16 = 20;
+/// Step of the voltage in mV when a charger is connected or disconnected
+pub const CHARGER_STEP: u16 = 40;
+/// Number of readings after a step of the voltage until it counts
+pub const CHARGE_READINGS: usize = 3;
+
+/// Below this voltage in mV the battery is empty and the unit shuts down
+pub const LOW_BAT_THRESHOLD: u16 = 3450;
+/// Time in ms between an empty battery and the shutdown, in which the indicator warns the rider
+pub const SHUTDOWN_GRACE_PERIOD: u32 = 30_000;
+/// Minimum state of charge in % of a full battery
+pub const FULL_PERCENT: u8 = 90;
+/// Below this state of charge in % the battery is low
+pub const LOW_PERCENT: u8 = 20;
+/// Below this state of charge in % the battery is almost empty
+pub const CRITICAL_PERCENT: u8 = 10;
+
+/// Point of the discharge curve
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OcvPoint {
+    /// Open-circuit voltage in mV
+    pub voltage: u16,
+    /// State of charge in %
+    pub percent: u8,
+}
+
+const fn point(voltage: u16, percent: u8) -> OcvPoint {
+    OcvPoint { voltage, percent }
+}
+
+/// Typical discharge curve of a Li-ion cell, sorted by falling voltage
+///
+/// The curve is fixed at build time, a different cell needs a new firmware.
+pub const OCV_TABLE: [OcvPoint; 11] = [
+    point(4200, 100),
+    point(4110, 90),
+    point(4020, 80),
+    point(3950, 70),
+    point(3870, 60),
+    point(3840, 50),
+    point(3800, 40),
+    point(3770, 30),
+    point(3730, 20),
+    point(3690, 10),
+    point(3270, 0),
+];
+
+/// State of charge in % at `voltage` in mV according to `table`, which is sorted by falling
+/// voltage
+///
+/// Points with the same voltage are skipped and a rising state of charge is treated as flat, so
+/// an invalid table gives a wrong state of charge but doesn't panic.
+pub fn state_of_charge(voltage: u16, table: &[OcvPoint]) -> u8 {
+    let (first, last) = match (table.first(), table.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return 0,
+    };
+
+    if voltage >= first.voltage {
+        return first.percent;
+    }
+
+    for pair in table.windows(2) {
+        let (upper, lower) = (pair[0], pair[1]);
+        if voltage >= lower.voltage && upper.voltage > lower.voltage {
+            let span = (upper.voltage - lower.voltage) as u32;
+            let offset = ((voltage - lower.voltage) as u32).min(span);
+            let percent = upper.percent.saturating_sub(lower.percent) as u32 * offset / span;
+            return lower.percent.saturating_add(percent as u8);
+        }
+    }
+
+    last.percent
+}
+
+/// Supply voltage of the ADC in mV computed from the reading of VREFINT. Falls back to the
+/// nominal supply voltage if the reading is implausible.
+pub fn supply_voltage(vref_reading: u16, max_sample: u16) -> u16 {
+    let voltage = match vref_reading {
+        0 => SUPPLY_VOLTAGE,
+        r => VREFINT * max_sample as u32 / r as u32,
+    };
+
+    if voltage < SUPPLY_VOLTAGE_RANGE.0 || voltage > SUPPLY_VOLTAGE_RANGE.1 {
+        SUPPLY_VOLTAGE as u16
+    } else {
+        voltage as u16
+    }
+}
+
+/// Battery voltage in mV from the reading of the battery pin and the reading of VREFINT
+pub fn reading_to_battery_voltage(reading: u16, vref_reading: u16, max_sample: u16) -> u16 {
+    let supply_voltage = supply_voltage(vref_reading, max_sample) as u32;
+    let reading_voltage = reading as u32 * supply_voltage / max_sample as u32;
+    (reading_voltage * VOLTAGE_FACTOR) as u16
+}
 
+/// Battery voltage and the estimated state of charge
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct BatteryReading {
+    /// Voltage in mV
+    pub voltage: u16,
+    /// State of charge in %
+    pub percent: u8,
+}
+
+impl BatteryReading {
+    pub fn new(voltage: u16, table: &[OcvPoint]) -> Self {
+        BatteryReading {
+            voltage,
+            percent: state_of_charge(voltage, table),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
 pub enum BatteryState {
-    Ok(u16),
-    Empty(u16),
+    Full(BatteryReading),
+    Good(BatteryReading),
+    /// Time to warn the rider
+    Low(BatteryReading),
+    /// The unit shuts down soon
+    Critical(BatteryReading),
+    /// Below [`LOW_BAT_THRESHOLD`], the unit has to shut down
+    Empty(BatteryReading),
+    /// The ADC failed repeatedly
     Unknown,
 }
 
-pub struct BatteryMonitor {
-    adc: BatteryAdcType,
-    ch: BatteryChType,
+impl BatteryState {
+    pub fn from_reading(reading: BatteryReading) -> Self {
+        match reading {
+            r if r.voltage < LOW_BAT_THRESHOLD => BatteryState::Empty(r),
+            r if r.percent < CRITICAL_PERCENT => BatteryState::Critical(r),
+            r if r.percent < LOW_PERCENT => BatteryState::Low(r),
+            r if r.percent < FULL_PERCENT => BatteryState::Good(r),
+            r => BatteryState::Full(r),
+        }
+    }
+
+    /// Level of the state, higher is better, `None` if it is unknown
+    fn level(&self) -> Option<u8> {
+        match self {
+            BatteryState::Empty(_) => Some(0),
+            BatteryState::Critical(_) => Some(1),
+            BatteryState::Low(_) => Some(2),
+            BatteryState::Good(_) => Some(3),
+            BatteryState::Full(_) => Some(4),
+            BatteryState::Unknown => None,
+        }
+    }
+
+    fn from_level(level: u8, reading: BatteryReading) -> Self {
+        match level {
+            0 => BatteryState::Empty(reading),
+            1 => BatteryState::Critical(reading),
+            2 => BatteryState::Low(reading),
+            3 => BatteryState::Good(reading),
+            _ => BatteryState::Full(reading),
+        }
+    }
+
+    pub fn get_reading(&self) -> Option<BatteryReading> {
+        match self {
+            BatteryState::Full(r)
+            | BatteryState::Good(r)
+            | BatteryState::Low(r)
+            | BatteryState::Critical(r)
+            | BatteryState::Empty(r) => Some(*r),
+            BatteryState::Unknown => None,
+        }
+    }
+}
+
+/// Filters transient sags and noise of the readings
+pub struct BatteryDebouncer {
+    state: BatteryState,
+    /// Readings in a row below the current state
+    low_readings: u8,
 }
 
-impl BatteryMonitor {
-    pub fn new(adc: BatteryAdcType, ch: BatteryChType) -> Self {
-        BatteryMonitor { adc, ch }
+impl BatteryDebouncer {
+    pub const fn new() -> Self {
+        BatteryDebouncer {
+            state: BatteryState::Unknown,
+            low_readings: 0,
+        }
+    }
+
+    /// Add a reading and return the debounced state, which carries the latest reading
+    pub fn update(&mut self, reading: BatteryReading) -> BatteryState {
+        let level = BatteryState::from_reading(reading).level().unwrap_or(0);
+        // Without a state only an empty battery has to be confirmed
+        let current = self.state.level().unwrap_or_else(|| level.max(1));
+
+        let level = if level < current {
+            self.low_readings += 1;
+            if self.low_readings >= LOW_READINGS {
+                self.low_readings = 0;
+                level
+            } else {
+                current
+            }
+        } else {
+            self.low_readings = 0;
+            let lowered = BatteryReading {
+                voltage: reading.voltage.saturating_sub(HYSTERESIS_VOLTAGE),
+                percent: reading.percent.saturating_sub(HYSTERESIS_PERCENT),
+            };
+            let lowered_level = BatteryState::from_reading(lowered).level().unwrap_or(0);
+            lowered_level.max(current)
+        };
+
+        self.state = BatteryState::from_level(level, reading);
+        self.state
+    }
+
+    /// Forget the state, e.g. after the ADC failed
+    pub fn reset(&mut self) {
+        self.state = BatteryState::Unknown;
+        self.low_readings = 0;
+    }
+
+    pub fn get_state(&self) -> BatteryState {
+        self.state
+    }
+}
+
+impl Default for BatteryDebouncer {
+    fn default() -> Self {
+        BatteryDebouncer::new()
+    }
+}
+
+/// Detects an external charger from the trend of the battery voltage
+///
+/// The charge current through the internal resistance of the cell raises the voltage as soon as
+/// a charger is connected. Charging starts once the last [`CHARGE_READINGS`] readings are all
+/// [`CHARGER_STEP`] above the ones before, so a single spike is ignored. It ends once they are
+/// all that far below the highest voltage while charging, which happens when the charger is
+/// disconnected, even from a full battery that is held at the charge voltage.
+pub struct ChargeDetector {
+    /// Last readings in mV, oldest first
+    readings: [u16; 2 * CHARGE_READINGS],
+    len: usize,
+    /// Highest voltage while charging
+    peak: Option<u16>,
+}
+
+impl ChargeDetector {
+    pub const fn new() -> Self {
+        ChargeDetector {
+            readings: [0; 2 * CHARGE_READINGS],
+            len: 0,
+            peak: None,
+        }
+    }
+
+    /// Add the voltage of a reading in mV. Returns `true` while charging.
+    pub fn update(&mut self, voltage: u16) -> bool {
+        if self.len == self.readings.len() {
+            self.readings.rotate_left(1);
+            self.len -= 1;
+        }
+        self.readings[self.len] = voltage;
+        self.len += 1;
+
+        let (before, after) =
+            self.readings[..self.len].split_at(self.len.saturating_sub(CHARGE_READINGS));
+        let after_max = after.iter().copied().max().unwrap_or(0);
+
+        self.peak = match self.peak {
+            Some(peak) if after_max.saturating_add(CHARGER_STEP) <= peak => None,
+            Some(peak) => Some(peak.max(voltage)),
+            None => {
+                let before_max = before.iter().copied().max();
+                let after_min = after.iter().copied().min().unwrap_or(0);
+
+                match before_max {
+                    Some(max)
+                        if before.len() == CHARGE_READINGS
+                            && after_min >= max.saturating_add(CHARGER_STEP) =>
+                    {
+                        Some(after_max)
+                    }
+                    _ => None,
+                }
+            }
+        };
+
+        self.is_charging()
+    }
+
+    pub fn is_charging(&self) -> bool {
+        self.peak.is_some()
+    }
+}
+
+impl Default for ChargeDetector {
+    fn default() -> Self {
+        ChargeDetector::new()
+    }
+}
+
+/// ADC that measures the internal reference VREFINT
+pub trait VrefAdc {
+    fn read_vref(&mut self) -> u16;
+    /// Reading at the supply voltage
+    fn max_sample(&self) -> u16;
+}
+
+/// Measures the battery with the ADC `ADC` of the peripheral `A` at the channel `CH`
+///
+/// A failed reading keeps the last state until [`ADC_FAILURES`] readings in a row failed, then
+/// the state is [`BatteryState::Unknown`].
+pub struct BatteryMonitor<ADC, CH, A> {
+    adc: ADC,
+    ch: CH,
+    debouncer: BatteryDebouncer,
+    charge_detector: ChargeDetector,
+    /// Failed readings in a row
+    failures: u8,
+    _adc: PhantomData<A>,
+}
+
+impl<ADC, CH, A> BatteryMonitor<ADC, CH, A>
+where
+    ADC: OneShot<A, u16, CH> + VrefAdc,
+    CH: Channel<A>,
+{
+    pub fn new(adc: ADC, ch: CH) -> Self {
+        BatteryMonitor {
+            adc,
+            ch,
+            debouncer: BatteryDebouncer::new(),
+            charge_detector: ChargeDetector::new(),
+            failures: 0,
+            _adc: PhantomData,
+        }
+    }
+
+    /// Sample of the battery pin, retried while the ADC is busy
+    fn read_sample(&mut self) -> Result<u16, Error> {
+        for _ in 0..ADC_RETRIES {
+            match self.adc.read(&mut self.ch) {
+                Ok(sample) => return Ok(sample),
+                Err(nb::Error::WouldBlock) => continue,
+                Err(nb::Error::Other(_)) => return Err(Error::Adc),
+            }
+        }
+
+        Err(Error::WouldBlock)
+    }
+
+    pub fn read_battery_voltage(&mut self) -> Result<u16, Error> {
+        let mut sum = 0;
+        let mut vref_sum = 0;
+
+        for _ in 0..SAMPLES {
+            sum += self.read_sample()? as u32;
+            vref_sum += self.adc.read_vref() as u32;
+        }
+
+        Ok(reading_to_battery_voltage(
+            (sum / SAMPLES) as u16,
+            (vref_sum / SAMPLES) as u16,
+            self.adc.max_sample(),
+        ))
     }
 
-    pub fn read_battery_voltage(&mut self) -> u16 {
-        let reading: u16 = self.adc.read(&mut self.ch).unwrap();
-        self.reading_to_battery_voltage(reading)
+    pub fn read_battery(&mut self) -> Result<BatteryReading, Error> {
+        Ok(BatteryReading::new(
+            self.read_battery_voltage()?,
+            &OCV_TABLE,
+        ))
     }
 
+    /// Debounced state of the battery, see [`BatteryDebouncer`]
     pub fn check_battery(&mut self) -> BatteryState {
-        match self.read_battery_voltage() {
-            v if v > LOW_BAT_THRESHOLD as u16 => BatteryState::Ok(v),
-            v => BatteryState::Empty(v),
+        match self.read_battery() {
+            Ok(reading) => {
+                self.failures = 0;
+                self.charge_detector.update(reading.voltage);
+                self.debouncer.update(reading)
+            }
+            Err(e) => {
+                defmt::warn!("Battery reading failed: {:?}", e);
+
+                self.failures = self.failures.saturating_add(1);
+                if self.failures >= ADC_FAILURES {
+                    self.debouncer.reset();
+                }
+                self.debouncer.get_state()
+            }
+        }
+    }
+
+    /// Whether an external charger is connected according to the last readings
+    pub fn is_charging(&self) -> bool {
+        self.charge_detector.is_charging()
+    }
+}
+
+#[cfg(target_os = "none")]
+mod hardware {
+    use super::VrefAdc;
+    use stm32f1xx_hal::adc::Adc;
+    use stm32f1xx_hal::pac::ADC1;
+
+    impl VrefAdc for Adc<ADC1> {
+        fn read_vref(&mut self) -> u16 {
+            Adc::<ADC1>::read_vref(self)
+        }
+
+        fn max_sample(&self) -> u16 {
+            Adc::<ADC1>::max_sample(self)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    #[test]
+    fn state_of_charge_is_interpolated() {
+        let table = &OCV_TABLE;
+
+        assert_eq!(state_of_charge(4250, table), 100);
+        assert_eq!(state_of_charge(4200, table), 100);
+        assert_eq!(state_of_charge(4155, table), 95);
+        assert_eq!(state_of_charge(3840, table), 50);
+        assert_eq!(state_of_charge(3710, table), 15);
+        assert_eq!(state_of_charge(3270, table), 0);
+        assert_eq!(state_of_charge(3000, table), 0);
+        assert_eq!(state_of_charge(3900, &[]), 0);
+    }
+
+    #[test]
+    fn custom_table() {
+        let table = [point(4100, 100), point(3500, 0)];
+
+        assert_eq!(state_of_charge(3800, &table), 50);
+        assert_eq!(state_of_charge(4200, &table), 100);
+    }
+
+    #[test]
+    fn invalid_table() {
+        let table = [
+            point(4100, 100),
+            point(3800, 50),
+            point(3800, 40),
+            point(3500, 0),
+        ];
+        assert_eq!(state_of_charge(3800, &table), 50);
+        assert_eq!(state_of_charge(3650, &table), 20);
+
+        // Rising state of charge and voltage
+        let table = [point(4100, 0), point(3500, 100), point(3800, 50)];
+        assert_eq!(state_of_charge(3700, &table), 100);
+        assert_eq!(state_of_charge(3000, &table), 50);
+    }
+
+    #[test]
+    fn supply_voltage_from_vrefint() {
+        // 1.2 V of 3.3 V
+        assert_eq!(supply_voltage(1489, 4095), 3300);
+        // Sagging regulator
+        assert_eq!(supply_voltage(1638, 4095), 3000);
+        // Implausible readings
+        assert_eq!(supply_voltage(0, 4095), 3300);
+        assert_eq!(supply_voltage(4095, 4095), 3300);
+        assert_eq!(supply_voltage(1000, 4095), 3300);
+    }
+
+    #[test]
+    fn battery_voltage_is_corrected() {
+        // Half of 3.3 V, so 3.3 V at the battery with the voltage divider
+        assert_eq!(reading_to_battery_voltage(2048, 1489, 4095), 3300);
+        // The same reading at 3.0 V supply
+        assert_eq!(reading_to_battery_voltage(2048, 1638, 4095), 3000);
+    }
+
+    #[test]
+    fn states() {
+        let state = |voltage| BatteryState::from_reading(BatteryReading::new(voltage, &OCV_TABLE));
+
+        assert!(matches!(state(4150), BatteryState::Full(_)));
+        assert!(matches!(state(3850), BatteryState::Good(_)));
+        assert!(matches!(state(3700), BatteryState::Low(_)));
+        assert!(matches!(state(3600), BatteryState::Critical(_)));
+        assert!(matches!(state(3400), BatteryState::Empty(_)));
+        assert_eq!(
+            state(3850).get_reading(),
+            Some(BatteryReading {
+                voltage: 3850,
+                percent: 53
+            })
+        );
+        assert_eq!(BatteryState::Unknown.get_reading(), None);
+    }
+
+    fn debounce(debouncer: &mut BatteryDebouncer, voltage: u16) -> BatteryState {
+        debouncer.update(BatteryReading::new(voltage, &OCV_TABLE))
+    }
+
+    #[test]
+    fn transient_sag_is_ignored() {
+        let mut debouncer = BatteryDebouncer::new();
+        assert!(matches!(
+            debounce(&mut debouncer, 3850),
+            BatteryState::Good(_)
+        ));
+
+        for _ in 0..LOW_READINGS - 1 {
+            let state = debounce(&mut debouncer, 3400);
+            assert!(matches!(state, BatteryState::Good(_)));
+            // The state carries the latest reading
+            assert_eq!(state.get_reading().unwrap().voltage, 3400);
+        }
+        assert!(matches!(
+            debounce(&mut debouncer, 3850),
+            BatteryState::Good(_)
+        ));
+
+        for _ in 0..LOW_READINGS - 1 {
+            debounce(&mut debouncer, 3400);
         }
+        assert!(matches!(
+            debounce(&mut debouncer, 3400),
+            BatteryState::Empty(_)
+        ));
     }
 
-    fn reading_to_battery_voltage(&self, reading: u16) -> u16 {
-        let reading_voltage = reading as u32 * SUPPLY_VOLTAGE / self.adc.max_sample() as u32;
-        (reading_voltage * VOLTAGE_FACTOR) as u16
+    #[test]
+    fn empty_battery_is_confirmed_at_boot() {
+        let mut debouncer = BatteryDebouncer::new();
+
+        for _ in 0..LOW_READINGS - 1 {
+            assert!(matches!(
+                debounce(&mut debouncer, 3400),
+                BatteryState::Critical(_)
+            ));
+        }
+        assert!(matches!(
+            debounce(&mut debouncer, 3400),
+            BatteryState::Empty(_)
+        ));
+
+        debouncer.reset();
+        assert_eq!(debouncer.get_state(), BatteryState::Unknown);
+        assert!(matches!(
+            debounce(&mut debouncer, 3700),
+            BatteryState::Low(_)
+        ));
+    }
+
+    #[test]
+    fn recovery_needs_hysteresis() {
+        let mut debouncer = BatteryDebouncer::new();
+        // 15 %
+        assert!(matches!(
+            debounce(&mut debouncer, 3710),
+            BatteryState::Low(_)
+        ));
+
+        // 21 % and 24 % are within the hysteresis
+        assert!(matches!(
+            debounce(&mut debouncer, 3734),
+            BatteryState::Low(_)
+        ));
+        assert!(matches!(
+            debounce(&mut debouncer, 3746),
+            BatteryState::Low(_)
+        ));
+        // 25 %
+        assert!(matches!(
+            debounce(&mut debouncer, 3750),
+            BatteryState::Good(_)
+        ));
+
+        // An empty battery has to recover by the voltage as well
+        for _ in 0..LOW_READINGS {
+            debounce(&mut debouncer, 3400);
+        }
+        assert!(matches!(
+            debounce(&mut debouncer, 3480),
+            BatteryState::Empty(_)
+        ));
+        assert!(matches!(
+            debounce(&mut debouncer, 3500),
+            BatteryState::Critical(_)
+        ));
+    }
+
+    fn detect(detector: &mut ChargeDetector, voltages: &[u16]) -> Vec<bool> {
+        voltages.iter().map(|v| detector.update(*v)).collect()
+    }
+
+    #[test]
+    fn charger_is_detected() {
+        let mut detector = ChargeDetector::new();
+
+        // A single spike and the recovery from a sag are no charger
+        assert_eq!(
+            detect(&mut detector, &[3800, 3850, 3800, 3700, 3800, 3800, 3800]),
+            [false; 7]
+        );
+
+        // Connected
+        assert_eq!(
+            detect(&mut detector, &[3860, 3860, 3870, 3880]),
+            [false, false, true, true]
+        );
+        // The voltage keeps rising until the cell is full and stays at the charge voltage
+        assert_eq!(
+            detect(&mut detector, &[4100, 4200, 4200, 4200, 4190]),
+            [true; 5]
+        );
+        // Disconnected
+        assert_eq!(
+            detect(&mut detector, &[4150, 4150, 4140, 4140]),
+            [true, true, false, false]
+        );
+        assert!(!detector.is_charging());
+    }
+
+    #[test]
+    fn charger_is_not_detected_at_boot() {
+        let mut detector = ChargeDetector::new();
+        assert_eq!(detect(&mut detector, &[3800, 3850, 3850, 3850]), [false; 4]);
+    }
+
+    struct MockAdc {
+        /// Results of the next battery samples, the ADC fails afterwards
+        samples: VecDeque<nb::Result<u16, ()>>,
+    }
+
+    struct MockChannel;
+
+    impl Channel<MockAdc> for MockChannel {
+        type ID = u8;
+
+        fn channel() -> u8 {
+            3
+        }
+    }
+
+    impl OneShot<MockAdc, u16, MockChannel> for MockAdc {
+        type Error = ();
+
+        fn read(&mut self, _pin: &mut MockChannel) -> nb::Result<u16, ()> {
+            self.samples
+                .pop_front()
+                .unwrap_or(Err(nb::Error::Other(())))
+        }
+    }
+
+    impl VrefAdc for MockAdc {
+        fn read_vref(&mut self) -> u16 {
+            // 3.3 V supply
+            1489
+        }
+
+        fn max_sample(&self) -> u16 {
+            4095
+        }
+    }
+
+    fn monitor() -> BatteryMonitor<MockAdc, MockChannel, MockAdc> {
+        BatteryMonitor::new(
+            MockAdc {
+                samples: VecDeque::new(),
+            },
+            MockChannel,
+        )
+    }
+
+    /// Queue the samples of one reading of `voltage` in mV
+    fn add_reading(monitor: &mut BatteryMonitor<MockAdc, MockChannel, MockAdc>, voltage: u16) {
+        let sample = (voltage as u32 * 4095 / 2 / 3300) as u16;
+        for _ in 0..SAMPLES {
+            monitor.adc.samples.push_back(Ok(sample));
+        }
+    }
+
+    #[test]
+    fn busy_adc_is_retried() {
+        let mut monitor = monitor();
+        for _ in 0..ADC_RETRIES - 1 {
+            monitor.adc.samples.push_back(Err(nb::Error::WouldBlock));
+        }
+        add_reading(&mut monitor, 3850);
+
+        assert!(matches!(monitor.check_battery(), BatteryState::Good(_)));
+
+        for _ in 0..ADC_RETRIES {
+            monitor.adc.samples.push_back(Err(nb::Error::WouldBlock));
+        }
+        assert_eq!(monitor.read_battery(), Err(Error::WouldBlock));
+    }
+
+    #[test]
+    fn persistent_failures_are_unknown() {
+        let mut monitor = monitor();
+        assert_eq!(monitor.read_battery(), Err(Error::Adc));
+        assert_eq!(monitor.check_battery(), BatteryState::Unknown);
+
+        add_reading(&mut monitor, 3700);
+        let low = monitor.check_battery();
+        assert!(matches!(low, BatteryState::Low(_)));
+
+        // Single failures keep the last state
+        for _ in 0..ADC_FAILURES - 1 {
+            assert_eq!(monitor.check_battery(), low);
+        }
+        assert_eq!(monitor.check_battery(), BatteryState::Unknown);
+
+        // A failure in the middle of a reading fails the reading
+        add_reading(&mut monitor, 3850);
+        monitor.adc.samples.truncate(SAMPLES as usize / 2);
+        assert_eq!(monitor.check_battery(), BatteryState::Unknown);
+
+        add_reading(&mut monitor, 3850);
+        assert!(matches!(monitor.check_battery(), BatteryState::Good(_)));
     }
 }
@@ -1,91 +1,178 @@
 #![no_main]
 #![no_std]
 
-#[cfg(all(feature = "anchor", feature = "tag"))]
-compile_error!("feature \"anchor\" and feature \"tag\" cannot be enabled at the same time");
-
 use bike_distance_indicator as _;
 use bike_distance_indicator::init::init_hardware;
 
 use rtic::app;
 
-use bike_distance_indicator::battery::{BatteryMonitor, BatteryState};
-use bike_distance_indicator::dw1000::{Dw1000MessageType, Dw1000State, Dw1000Wrapper};
+use bike_distance_indicator::battery::{
+    BatteryState, LOW_PERCENT, SETTLE_TIME, SHUTDOWN_GRACE_PERIOD,
+};
+use bike_distance_indicator::button::{Button, ButtonEvent};
+use bike_distance_indicator::cli::{self, Action, CliError, Command, Line, LineBuffer, Status};
+use bike_distance_indicator::config::{Config, ConfigStore, Stm32Flash};
+use bike_distance_indicator::control::{AnchorControl, ConvoyControl, RadioCommand, TagControl};
+use bike_distance_indicator::dw1000::{Dw1000MessageType, Dw1000Radio, Dw1000State, Dw1000Wrapper};
 use bike_distance_indicator::error::Error;
-use bike_distance_indicator::helper::get_delay;
-use bike_distance_indicator::indicator::{DistanceIndicator, LedIndicator};
-use bike_distance_indicator::types::Led1Type;
+use bike_distance_indicator::helper::{get_delay, get_unique_short_id, ms_to_cycles, Clock};
+use bike_distance_indicator::indicator::{DistanceIndicator, DistanceRange, LedIndicator, Overlay};
+use bike_distance_indicator::pairing::PairingSession;
+use bike_distance_indicator::role::{ReplySlot, Role, RoleNegotiation};
+use bike_distance_indicator::sync::ConfigSync;
+use bike_distance_indicator::types::{
+    BatteryMonitorType, ButtonType, DwWrapperType, Led1Type, SerialRxType, SerialTxType,
+};
+use core::fmt::Write;
 use dw1000::mac;
 use embedded_hal::blocking::delay::DelayMs;
-use embedded_hal::digital::v2::ToggleableOutputPin;
+use embedded_hal::digital::v2::{InputPin, ToggleableOutputPin};
+use embedded_hal::serial::Read;
 use rtic::cyccnt::U32Ext;
 use stm32f1xx_hal::pac::PWR;
 
-#[cfg(feature = "anchor")]
-const ADDRESS: u16 = 0x1234;
-#[cfg(feature = "tag")]
-const ADDRESS: u16 = 0x1235;
-
-#[cfg(feature = "anchor")]
-const CTRL_PERIOD: u32 = 6_400_000;
-#[cfg(feature = "tag")]
-const CTRL_PERIOD: u32 = 3_200_000;
-const CTRL_PERIOD_SLOW: u32 = 10_000_000;
-const BATTERY_PERIOD: u32 = 256_000_000;
+const CONVOY_CTRL_PERIOD: u32 = 640_000;
+/// The button is sampled every 10 ms
+const BUTTON_PERIOD: u32 = 640_000;
+/// The LED of a warning blinks with 1 Hz
+const BLINK_PERIOD: u32 = 32_000_000;
+/// Sync updates are repeated every 150 ms until they are acknowledged
+const SYNC_PERIOD: u32 = 9_600_000;
+/// Pairing frames are sent every 100 ms plus up to 75 ms depending on the unique ID so that two
+/// units don't keep sending at the same time
+const PAIRING_PERIOD: u32 = 6_400_000;
+const PAIRING_PERIOD_STEP: u32 = 320_000;
+/// Give up pairing after this many pairing cycles
+const PAIRING_TIMEOUT: u16 = 300;
+/// Keep answering the partner for this many pairing cycles after we are paired in case it missed
+/// our confirm
+const PAIRING_LINGER: u16 = 10;
 
 #[app(device = stm32f1xx_hal::stm32, monotonic = rtic::cyccnt::CYCCNT, peripherals = true)]
 const APP: () = {
     struct Resources {
-        dw1000: Dw1000Wrapper,
+        dw1000: DwWrapperType,
         led1: Led1Type,
         indicator: LedIndicator,
-        battery_monitor: BatteryMonitor,
+        battery_monitor: BatteryMonitorType,
+        serial_tx: SerialTxType,
+        serial_rx: SerialRxType,
+        line_buffer: LineBuffer,
+        button_pin: ButtonType,
+        button: Button,
+        clock: Clock,
+        config: Config,
+        config_store: ConfigStore<Stm32Flash>,
         ping_seen: bool,
         valid_response_seen: bool,
+        convoy: ConvoyControl,
+        negotiation: RoleNegotiation,
+        /// Only present in pairing mode
+        pairing_session: Option<PairingSession>,
+        /// Short address until paired
+        address: u16,
+        sync: ConfigSync,
+        /// An external charger is connected, ranging is stopped
+        charging: bool,
     }
 
-    #[init(spawn = [control_tag, control_anchor, start_receiving, check_battery_voltage])]
+    #[init(spawn = [control_tag, control_anchor, control_convoy, negotiate_role, pair, start_receiving, check_battery_voltage, poll_button, blink_indicator])]
     fn init(mut cx: init::Context) -> init::LateResources {
         cx.core.DWT.enable_cycle_counter();
 
+        let start = cx.start;
+
         defmt::info!("Hello, RTIC!");
 
         let dp = cx.device;
         let cp = cx.core;
 
-        let (mut dw1000, irq, led1, indicator, battery_monitor) = init_hardware(dp, cp);
+        let hardware = init_hardware(dp, cp);
+        let mut dw1000 = hardware.dw1000;
+        let config = hardware.config;
+
+        let unique_id = get_unique_short_id();
 
-        defmt::info!("Set address");
+        let role = Role::select(hardware.anchor_strap, config.role);
+        let negotiation = match role {
+            Some(role) => RoleNegotiation::fixed(role),
+            None => RoleNegotiation::new(unique_id),
+        };
+
+        // A negotiated anchor keeps the address of a tag, tags answer pings from any address
+        let role = role.unwrap_or(Role::Tag);
+        let address = config.address.unwrap_or_else(|| role.address(unique_id));
+
+        defmt::info!(
+            "Role {:?}, set address {:04x}",
+            negotiation.get_role(),
+            address
+        );
 
         // Set network address
         dw1000
             .set_address(
-                mac::PanId(0x0d57),         // hardcoded network id
-                mac::ShortAddress(ADDRESS), // random device address
+                mac::PanId(config.pan_id),  // network id until paired
+                mac::ShortAddress(address), // device address
             )
             .expect("Failed to set address");
 
-        cx.spawn.check_battery_voltage().unwrap();
+        let mut dw1000 = Dw1000Wrapper::new(Dw1000Radio::new(dw1000, hardware.irq));
+        dw1000.set_reply_slot(ReplySlot::new(role, address));
+        dw1000.set_ping_filter(role.ping_filter());
+        dw1000.set_ranging_mode(config.ranging_mode);
 
-        #[cfg(feature = "anchor")]
-        cx.spawn.control_anchor().unwrap();
+        // Pairing mode replaces the pairing
+        if let (Some(pairing), false) = (config.pairing, hardware.pairing_button) {
+            defmt::info!("Paired: {:?}", pairing);
+            dw1000.set_pairing(pairing).expect("Failed to set pairing");
+        }
+
+        cx.spawn.check_battery_voltage().unwrap();
+        cx.spawn.poll_button().unwrap();
+        cx.spawn.blink_indicator().unwrap();
 
-        #[cfg(feature = "tag")]
-        cx.spawn.control_tag().unwrap();
+        let pairing_session = if hardware.pairing_button {
+            defmt::info!("Pairing mode");
+            cx.spawn.pair().unwrap();
+            Some(PairingSession::new(unique_id))
+        } else {
+            match negotiation.get_role() {
+                Some(Role::Anchor) => cx.spawn.control_anchor().unwrap(),
+                Some(Role::Tag) => cx.spawn.control_tag().unwrap(),
+                Some(Role::Convoy(_)) => cx.spawn.control_convoy().unwrap(),
+                None => cx.spawn.negotiate_role().unwrap(),
+            }
+            None
+        };
 
         init::LateResources {
-            dw1000: Dw1000Wrapper::new(dw1000, irq),
-            led1,
-            indicator,
-            battery_monitor,
+            dw1000,
+            led1: hardware.led1,
+            indicator: hardware.led_indicator,
+            battery_monitor: hardware.battery_monitor,
+            serial_tx: hardware.serial_tx,
+            serial_rx: hardware.serial_rx,
+            line_buffer: LineBuffer::new(),
+            button_pin: hardware.button,
+            button: Button::new(hardware.pairing_button),
+            clock: Clock::new(start),
+            config,
+            config_store: hardware.config_store,
             ping_seen: false,
             valid_response_seen: false,
+            convoy: ConvoyControl::new(role.convoy_position().unwrap_or(0)),
+            negotiation,
+            pairing_session,
+            address,
+            sync: ConfigSync::new(unique_id, &config),
+            charging: false,
         }
     }
 
     #[task(resources = [dw1000])]
     fn send_ping(cx: send_ping::Context) {
-        let dw1000: &mut Dw1000Wrapper = cx.resources.dw1000;
+        let dw1000: &mut DwWrapperType = cx.resources.dw1000;
 
         match dw1000.send_ping() {
             Ok(()) => {}
@@ -96,9 +183,14 @@ const APP: () = {
         }
     }
 
-    #[task(resources = [dw1000])]
+    #[task(resources = [dw1000, charging])]
     fn start_receiving(cx: start_receiving::Context) {
-        let dw1000: &mut Dw1000Wrapper = cx.resources.dw1000;
+        let dw1000: &mut DwWrapperType = cx.resources.dw1000;
+
+        // The radio stays off while charging
+        if *cx.resources.charging {
+            return;
+        }
 
         match dw1000.start_receiving() {
             Ok(()) => {}
@@ -111,7 +203,7 @@ const APP: () = {
 
     #[task(resources = [dw1000])]
     fn finish_receiving(cx: finish_receiving::Context) {
-        let dw1000: &mut Dw1000Wrapper = cx.resources.dw1000;
+        let dw1000: &mut DwWrapperType = cx.resources.dw1000;
 
         match dw1000.finish_receiving() {
             Ok(()) => {}
@@ -124,7 +216,7 @@ const APP: () = {
 
     #[task(resources = [dw1000])]
     fn finish_sending(cx: finish_sending::Context) {
-        let dw1000: &mut Dw1000Wrapper = cx.resources.dw1000;
+        let dw1000: &mut DwWrapperType = cx.resources.dw1000;
 
         match dw1000.finish_sending() {
             Ok(()) => {}
@@ -135,30 +227,95 @@ const APP: () = {
         }
     }
 
-    #[task(resources = [dw1000, led1, ping_seen, valid_response_seen], spawn = [start_receiving, set_indicator])]
+    #[task(resources = [dw1000, led1, clock, config, ping_seen, valid_response_seen, convoy, pairing_session, sync], spawn = [start_receiving, show_range, save_config])]
     fn receive_message(cx: receive_message::Context) {
-        let dw1000: &mut Dw1000Wrapper = cx.resources.dw1000;
+        let dw1000: &mut DwWrapperType = cx.resources.dw1000;
         let led1: &mut Led1Type = cx.resources.led1;
+        let clock: &mut Clock = cx.resources.clock;
+        let config: &mut Config = cx.resources.config;
         let ping_seen: &mut bool = cx.resources.ping_seen;
         let valid_response_seen: &mut bool = cx.resources.valid_response_seen;
+        let convoy: &mut ConvoyControl = cx.resources.convoy;
+        let pairing_session: &mut Option<PairingSession> = cx.resources.pairing_session;
+        let sync: &mut ConfigSync = cx.resources.sync;
+
+        let now_ms = clock.now_ms();
 
-        match dw1000.receive_message() {
+        match dw1000.receive_message(now_ms) {
             Ok(Dw1000MessageType::RangingResponse(valid)) => {
-                if valid {
+                if let (true, Some(filtered_distance)) = (valid, dw1000.get_filtered_distance()) {
                     led1.toggle().unwrap();
-                    let average_distance = dw1000.get_average_distance();
                     defmt::info!(
-                        "Received ranging response: {:?}cm ==> New filtered distance: {:?}cm",
+                        "Received ranging response: {:?}cm ==> New filtered distance: {:?}cm, velocity: {:?}cm/s",
                         dw1000.get_last_distance(),
-                        average_distance
+                        filtered_distance,
+                        dw1000.get_velocity()
                     );
                     *valid_response_seen = true;
-                    cx.spawn.set_indicator(average_distance, 100, 20).unwrap();
+                    let profile = config.get_profile();
+                    let range = DistanceRange::from_distance_and_velocity(
+                        filtered_distance,
+                        dw1000.get_velocity(),
+                        profile.target_distance as u64,
+                        profile.tolerance as u64,
+                    );
+                    cx.spawn.show_range(range).unwrap();
+
+                    // The other unit shows the same
+                    if let Err(e) = dw1000.send_report(filtered_distance, range) {
+                        defmt::error!("send_report: {:?}", e);
+                    }
+                } else {
+                    defmt::info!(
+                        "Received invalid ranging response, {:?} outliers rejected so far",
+                        dw1000.get_rejected_count()
+                    );
                 }
             }
-            Ok(Dw1000MessageType::Ping) => {
+            Ok(Dw1000MessageType::RangingRequest(true)) => {
+                led1.toggle().unwrap();
+                defmt::info!(
+                    "Received ranging request: {:?}cm, {:?} tags",
+                    dw1000.get_last_distance(),
+                    dw1000.get_peers().len()
+                );
+            }
+            Ok(Dw1000MessageType::Report(true)) => {
+                defmt::info!(
+                    "Received distance report, {:?} reports lost so far",
+                    dw1000.get_lost_reports()
+                );
+            }
+            Ok(Dw1000MessageType::Ping(true)) => {
                 defmt::info!("Received ping");
                 *ping_seen = true;
+                convoy.predecessor_ping(now_ms);
+            }
+            Ok(Dw1000MessageType::Pairing(message)) => {
+                defmt::info!("Received pairing message: {:?}", message);
+
+                let reply = pairing_session
+                    .as_mut()
+                    .and_then(|session| session.handle(message));
+                if let Some(reply) = reply {
+                    if let Err(e) = dw1000.send_pairing(&reply) {
+                        defmt::error!("send_pairing: {:?}", e);
+                    }
+                }
+            }
+            Ok(Dw1000MessageType::Sync(message)) => {
+                defmt::info!("Received sync message: {:?}", message);
+
+                let (reply, changed) = sync.handle(message, config);
+                if changed {
+                    defmt::info!("Profile of partner: {:?}", config.get_profile());
+                    cx.spawn.save_config().unwrap();
+                }
+                if let Some(reply) = reply {
+                    if let Err(e) = dw1000.send_sync(&reply) {
+                        defmt::error!("send_sync: {:?}", e);
+                    }
+                }
             }
             Ok(message_type) => defmt::info!("Received message: {:?}", message_type),
             Err(Error::InvalidState) => {
@@ -172,23 +329,9 @@ const APP: () = {
         }
     }
 
-    #[task(resources = [indicator])]
-    fn set_indicator(
-        cx: set_indicator::Context,
-        current_distance: u64,
-        target_distance: u64,
-        tolerance: u64,
-    ) {
-        let indicator: &mut LedIndicator = cx.resources.indicator;
-
-        indicator
-            .update_range(current_distance, target_distance, tolerance)
-            .unwrap();
-    }
-
     #[task(binds = EXTI0, resources = [dw1000], spawn = [receive_message, start_receiving, finish_sending])]
     fn exti2(cx: exti2::Context) {
-        let dw1000: &mut Dw1000Wrapper = cx.resources.dw1000;
+        let dw1000: &mut DwWrapperType = cx.resources.dw1000;
 
         dw1000.handle_interrupt().unwrap();
 
@@ -204,30 +347,274 @@ const APP: () = {
         }
     }
 
-    #[task(resources = [battery_monitor], spawn = [shutdown], schedule = [check_battery_voltage])]
-    fn check_battery_voltage(cx: check_battery_voltage::Context) {
-        let battery_monitor: &mut BatteryMonitor = cx.resources.battery_monitor;
+    #[task(binds = USART1, resources = [serial_rx, line_buffer], spawn = [run_command])]
+    fn usart1(cx: usart1::Context) {
+        let serial_rx: &mut SerialRxType = cx.resources.serial_rx;
+        let line_buffer: &mut LineBuffer = cx.resources.line_buffer;
+
+        match serial_rx.read() {
+            Ok(byte) => {
+                if let Some(line) = line_buffer.push(byte) {
+                    if cx.spawn.run_command(line).is_err() {
+                        defmt::warn!("Console busy, command dropped");
+                    }
+                }
+            }
+            Err(_) => defmt::warn!("Serial receive error"),
+        }
+    }
+
+    #[task(resources = [serial_tx, config, config_store, dw1000, battery_monitor, negotiation, sync], spawn = [sync_config])]
+    fn run_command(cx: run_command::Context, line: Result<Line, CliError>) {
+        let serial_tx: &mut SerialTxType = cx.resources.serial_tx;
+        let config: &mut Config = cx.resources.config;
+        let config_store: &mut ConfigStore<Stm32Flash> = cx.resources.config_store;
+        let dw1000: &mut DwWrapperType = cx.resources.dw1000;
+        let battery_monitor: &mut BatteryMonitorType = cx.resources.battery_monitor;
+        let negotiation: &mut RoleNegotiation = cx.resources.negotiation;
+        let sync: &mut ConfigSync = cx.resources.sync;
+
+        let status = Status {
+            role: negotiation.get_role(),
+            paired: dw1000.get_pairing().is_some(),
+            distance: dw1000.get_filtered_distance(),
+            velocity: dw1000.get_velocity(),
+            peers: dw1000.get_peers().len(),
+            rejected: dw1000.get_rejected_count(),
+            lost_reports: dw1000.get_lost_reports(),
+            battery: battery_monitor.read_battery().ok(),
+        };
+
+        let result = line
+            .and_then(|line| Command::parse(line.as_str()?))
+            .and_then(|command| cli::execute(command, config, &status, serial_tx));
+
+        if sync.check(config) {
+            // Fails if the sync is already running, which sends the new settings as well
+            let _ = cx.spawn.sync_config();
+        }
+
+        // Nothing left to report if the console fails
+        let _ = match result {
+            Ok(Action::None) => Ok(()),
+            Ok(Action::Save) => match config_store.save(config) {
+                Ok(()) => writeln!(serial_tx, "saved"),
+                Err(e) => {
+                    defmt::error!("Failed to save configuration: {:?}", e);
+                    writeln!(serial_tx, "error: save failed")
+                }
+            },
+            Ok(Action::Reboot) => cortex_m::peripheral::SCB::sys_reset(),
+            Err(e) => writeln!(serial_tx, "error: {}", e.description()),
+        };
+    }
 
-        match battery_monitor.check_battery() {
-            BatteryState::Ok(v) => {
-                defmt::info!("Battery Ok, voltage: {:?}mV", v);
+    #[task(schedule = [poll_button], spawn = [button_event], resources = [button_pin, button, clock])]
+    fn poll_button(cx: poll_button::Context) {
+        let button_pin: &mut ButtonType = cx.resources.button_pin;
+        let button: &mut Button = cx.resources.button;
+        let clock: &mut Clock = cx.resources.clock;
+
+        // Pressed pulls the pin to ground
+        if let Some(event) = button.update(button_pin.is_low().unwrap(), clock.now_ms()) {
+            cx.spawn.button_event(event).unwrap();
+        }
+
+        cx.schedule
+            .poll_button(cx.scheduled + BUTTON_PERIOD.cycles())
+            .unwrap();
+    }
+
+    /// Short press: next distance profile, double press: pairing, long press: shutdown
+    #[task(spawn = [pair, shutdown, sync_config], resources = [dw1000, config, config_store, pairing_session, address, sync])]
+    fn button_event(cx: button_event::Context, event: ButtonEvent) {
+        let dw1000: &mut DwWrapperType = cx.resources.dw1000;
+        let config: &mut Config = cx.resources.config;
+        let config_store: &mut ConfigStore<Stm32Flash> = cx.resources.config_store;
+        let pairing_session: &mut Option<PairingSession> = cx.resources.pairing_session;
+        let address: &mut u16 = cx.resources.address;
+        let sync: &mut ConfigSync = cx.resources.sync;
+
+        defmt::info!("Button: {:?}", event);
+
+        match event {
+            ButtonEvent::ShortPress => {
+                config.next_profile();
+                defmt::info!("Profile {:?}: {:?}", config.profile, config.get_profile());
+                // Saves the new sync version as well
+                let changed = sync.check(config);
+                if let Err(e) = config_store.save(config) {
+                    defmt::error!("Failed to save profile: {:?}", e);
+                }
+                if changed {
+                    let _ = cx.spawn.sync_config();
+                }
+            }
+            ButtonEvent::DoublePress if pairing_session.is_none() => {
+                defmt::info!("Pairing mode");
+
+                // Pairing frames are exchanged on the default PAN
+                if dw1000.get_state() == Dw1000State::Receiving {
+                    let _ = dw1000.finish_receiving();
+                }
+                if let Err(e) = dw1000.clear_pairing(config.pan_id, *address) {
+                    defmt::error!("clear_pairing: {:?}", e);
+                }
+
+                *pairing_session = Some(PairingSession::new(get_unique_short_id()));
+                cx.spawn.pair().unwrap();
             }
-            BatteryState::Empty(v) => {
-                defmt::info!("Battery Empty, voltage: {:?}mV", v);
-                cx.spawn.shutdown().unwrap();
+            ButtonEvent::DoublePress => {}
+            ButtonEvent::LongPress => cx.spawn.shutdown().unwrap(),
+        }
+    }
+
+    #[task(resources = [config, config_store])]
+    fn save_config(cx: save_config::Context) {
+        let config: &mut Config = cx.resources.config;
+        let config_store: &mut ConfigStore<Stm32Flash> = cx.resources.config_store;
+
+        if let Err(e) = config_store.save(config) {
+            defmt::error!("Failed to save configuration: {:?}", e);
+        }
+    }
+
+    /// Send the shared settings to the partner until it acknowledges them
+    #[task(schedule = [sync_config], resources = [dw1000, sync])]
+    fn sync_config(cx: sync_config::Context) {
+        let dw1000: &mut DwWrapperType = cx.resources.dw1000;
+        let sync: &mut ConfigSync = cx.resources.sync;
+
+        if let Some(message) = sync.step() {
+            if dw1000.get_state() == Dw1000State::Receiving {
+                let _ = dw1000.finish_receiving();
+            }
+            if let Err(e) = dw1000.send_sync(&message) {
+                defmt::error!("send_sync: {:?}", e);
             }
-            BatteryState::Unknown => {}
-        };
 
+            cx.schedule
+                .sync_config(cx.scheduled + SYNC_PERIOD.cycles())
+                .unwrap();
+        }
+    }
+
+    /// Dim the LEDs and measure the battery once the voltage recovered from their load
+    #[task(resources = [indicator, config], schedule = [check_battery_voltage, measure_battery])]
+    fn check_battery_voltage(cx: check_battery_voltage::Context) {
+        let indicator: &mut LedIndicator = cx.resources.indicator;
+        let config: &mut Config = cx.resources.config;
+
+        indicator.set_dimmed(true);
+
+        cx.schedule
+            .measure_battery(cx.scheduled + ms_to_cycles(SETTLE_TIME).cycles())
+            .unwrap();
         cx.schedule
-            .check_battery_voltage(cx.scheduled + BATTERY_PERIOD.cycles())
+            .check_battery_voltage(cx.scheduled + ms_to_cycles(config.battery_period).cycles())
             .unwrap();
     }
 
+    #[task(resources = [battery_monitor, indicator, dw1000, clock, negotiation, pairing_session, charging], spawn = [shutdown, start_receiving, control_anchor, control_tag, control_convoy, negotiate_role])]
+    fn measure_battery(cx: measure_battery::Context) {
+        // Time of the first empty reading
+        static mut EMPTY_SINCE: Option<u32> = None;
+
+        let battery_monitor: &mut BatteryMonitorType = cx.resources.battery_monitor;
+        let indicator: &mut LedIndicator = cx.resources.indicator;
+        let dw1000: &mut DwWrapperType = cx.resources.dw1000;
+        let clock: &mut Clock = cx.resources.clock;
+        let negotiation: &mut RoleNegotiation = cx.resources.negotiation;
+        let pairing_session: &mut Option<PairingSession> = cx.resources.pairing_session;
+        let charging: &mut bool = cx.resources.charging;
+
+        let now_ms = clock.now_ms();
+
+        let state = battery_monitor.check_battery();
+        indicator.set_dimmed(false);
+        // Sent to the partner with the next distance report
+        dw1000.set_battery(state.get_reading().map(|r| r.percent));
+
+        let partner_battery_low = matches!(
+            dw1000.get_partner_battery(),
+            Some(percent) if percent < LOW_PERCENT
+        );
+
+        let overlay = match state {
+            BatteryState::Full(r) | BatteryState::Good(r) => {
+                defmt::info!("Battery Ok, voltage: {:?}mV, {:?}%", r.voltage, r.percent);
+                *EMPTY_SINCE = None;
+                Overlay::None
+            }
+            BatteryState::Low(r) | BatteryState::Critical(r) => {
+                defmt::warn!("Battery low, voltage: {:?}mV, {:?}%", r.voltage, r.percent);
+                *EMPTY_SINCE = None;
+                Overlay::LowBattery
+            }
+            BatteryState::Empty(r) => {
+                defmt::info!("Battery Empty, voltage: {:?}mV", r.voltage);
+
+                let since = *EMPTY_SINCE.get_or_insert(now_ms);
+                if now_ms.wrapping_sub(since) >= SHUTDOWN_GRACE_PERIOD {
+                    cx.spawn.shutdown().unwrap();
+                }
+                Overlay::EmptyBattery
+            }
+            BatteryState::Unknown => {
+                // Don't shut down without a valid reading
+                defmt::warn!("Battery unknown");
+                *EMPTY_SINCE = None;
+                Overlay::None
+            }
+        };
+
+        // Our own battery is more urgent
+        if overlay == Overlay::None && partner_battery_low {
+            defmt::warn!("Partner battery low");
+            indicator.set_overlay(Overlay::PartnerBatteryLow);
+        } else {
+            indicator.set_overlay(overlay);
+        }
+
+        if battery_monitor.is_charging() != *charging {
+            *charging = battery_monitor.is_charging();
+
+            if *charging {
+                defmt::info!("Charging, ranging stopped");
+
+                // The control tasks stop at their next step
+                if dw1000.get_state() == Dw1000State::Receiving {
+                    let _ = dw1000.finish_receiving();
+                }
+            } else if pairing_session.is_none() {
+                // Otherwise `pair` restarts the control afterwards
+                defmt::info!("Charger disconnected, ranging restarted");
+
+                if dw1000.get_state() == Dw1000State::Ready {
+                    cx.spawn.start_receiving().unwrap();
+                }
+
+                match negotiation.get_role() {
+                    Some(Role::Anchor) => cx.spawn.control_anchor().unwrap(),
+                    Some(Role::Tag) => cx.spawn.control_tag().unwrap(),
+                    Some(Role::Convoy(_)) => cx.spawn.control_convoy().unwrap(),
+                    None => cx.spawn.negotiate_role().unwrap(),
+                }
+            }
+        }
+
+        // Show the progress instead of the range while charging
+        indicator.set_charging(if *charging {
+            Some(state.get_reading().map_or(0, |r| r.percent))
+        } else {
+            None
+        });
+    }
+
     #[task(resources = [indicator, dw1000])]
     fn shutdown(cx: shutdown::Context) {
         let indicator: &mut LedIndicator = cx.resources.indicator;
-        let dw1000: &mut Dw1000Wrapper = cx.resources.dw1000;
+        let dw1000: &mut DwWrapperType = cx.resources.dw1000;
 
         let mut delay = get_delay();
 
@@ -250,90 +637,286 @@ const APP: () = {
         }
     }
 
-    #[task(schedule = [control_anchor], spawn = [start_receiving, finish_receiving, send_ping], resources = [dw1000])]
+    /// Blink the warning of the indicator, if any
+    #[task(schedule = [blink_indicator], resources = [indicator])]
+    fn blink_indicator(cx: blink_indicator::Context) {
+        let indicator: &mut LedIndicator = cx.resources.indicator;
+
+        indicator.blink();
+
+        cx.schedule
+            .blink_indicator(cx.scheduled + BLINK_PERIOD.cycles())
+            .unwrap();
+    }
+
+    /// Show `range` on the indicator. Both `receive_message` and the control of an anchor or
+    /// convoy node spawn it, so two can be pending.
+    #[task(capacity = 2, resources = [indicator])]
+    fn show_range(cx: show_range::Context, range: DistanceRange) {
+        let indicator: &mut LedIndicator = cx.resources.indicator;
+
+        indicator.set_range(range);
+    }
+
+    #[task(schedule = [negotiate_role], spawn = [start_receiving, finish_receiving, send_ping, control_anchor, control_tag], resources = [dw1000, config, ping_seen, negotiation, pairing_session, charging])]
+    fn negotiate_role(cx: negotiate_role::Context) {
+        let dw1000: &mut DwWrapperType = cx.resources.dw1000;
+        let config: &mut Config = cx.resources.config;
+        let ping_seen: &mut bool = cx.resources.ping_seen;
+        let negotiation: &mut RoleNegotiation = cx.resources.negotiation;
+        let pairing_session: &mut Option<PairingSession> = cx.resources.pairing_session;
+
+        // Paused while pairing or charging, `pair` or `measure_battery` restarts the control
+        // afterwards
+        if pairing_session.is_some() || *cx.resources.charging {
+            return;
+        }
+
+        match negotiation.step(*ping_seen) {
+            Some(Role::Anchor) => {
+                defmt::info!("No anchor found, becoming anchor");
+
+                // Announce the anchor right away
+                if dw1000.get_state() == Dw1000State::Receiving {
+                    cx.spawn.finish_receiving().unwrap();
+                }
+                cx.spawn.send_ping().unwrap();
+                cx.spawn.control_anchor().unwrap();
+            }
+            Some(_) => {
+                defmt::info!("Anchor found, becoming tag");
+                cx.spawn.control_tag().unwrap();
+            }
+            None => {
+                // Listen for pings all the time
+                if dw1000.get_state() == Dw1000State::Ready {
+                    cx.spawn.start_receiving().unwrap();
+                }
+                cx.schedule
+                    .negotiate_role(cx.scheduled + ms_to_cycles(config.tag_period).cycles())
+                    .unwrap();
+            }
+        }
+    }
+
+    #[task(schedule = [pair], spawn = [start_receiving, control_anchor, control_tag, control_convoy, negotiate_role], resources = [dw1000, config, config_store, negotiation, pairing_session])]
+    fn pair(cx: pair::Context) {
+        static mut CYCLES: u16 = 0;
+        static mut PAIRED_CYCLES: u16 = 0;
+
+        let dw1000: &mut DwWrapperType = cx.resources.dw1000;
+        let config: &mut Config = cx.resources.config;
+        let config_store: &mut ConfigStore<Stm32Flash> = cx.resources.config_store;
+        let negotiation: &mut RoleNegotiation = cx.resources.negotiation;
+        let pairing_session: &mut Option<PairingSession> = cx.resources.pairing_session;
+
+        let session = match pairing_session {
+            Some(session) => session,
+            None => return,
+        };
+
+        *CYCLES += 1;
+
+        let done = match session.get_pairing() {
+            Some(pairing) if *PAIRED_CYCLES == PAIRING_LINGER => {
+                if dw1000.get_state() == Dw1000State::Receiving {
+                    let _ = dw1000.finish_receiving();
+                }
+
+                match dw1000.set_pairing(pairing) {
+                    Ok(()) => defmt::info!("Paired: {:?}", pairing),
+                    Err(e) => defmt::error!("set_pairing: {:?}", e),
+                }
+
+                config.pairing = Some(pairing);
+                if let Err(e) = config_store.save(config) {
+                    defmt::error!("Failed to save pairing: {:?}", e);
+                }
+                true
+            }
+            Some(_) => {
+                *PAIRED_CYCLES += 1;
+                false
+            }
+            None if *CYCLES > PAIRING_TIMEOUT => {
+                defmt::warn!("No partner found, leaving pairing mode");
+
+                // Keep the previous partner
+                if let Some(pairing) = config.pairing {
+                    if dw1000.get_state() == Dw1000State::Receiving {
+                        let _ = dw1000.finish_receiving();
+                    }
+                    if let Err(e) = dw1000.set_pairing(pairing) {
+                        defmt::error!("set_pairing: {:?}", e);
+                    }
+                }
+                true
+            }
+            None => false,
+        };
+
+        if done {
+            *pairing_session = None;
+            *CYCLES = 0;
+            *PAIRED_CYCLES = 0;
+
+            if dw1000.get_state() == Dw1000State::Ready {
+                cx.spawn.start_receiving().unwrap();
+            }
+
+            match negotiation.get_role() {
+                Some(Role::Anchor) => cx.spawn.control_anchor().unwrap(),
+                Some(Role::Tag) => cx.spawn.control_tag().unwrap(),
+                Some(Role::Convoy(_)) => cx.spawn.control_convoy().unwrap(),
+                None => cx.spawn.negotiate_role().unwrap(),
+            }
+            return;
+        }
+
+        if let Some(message) = session.step() {
+            if dw1000.get_state() == Dw1000State::Receiving {
+                let _ = dw1000.finish_receiving();
+            }
+            if let Err(e) = dw1000.send_pairing(&message) {
+                defmt::error!("send_pairing: {:?}", e);
+            }
+        } else if dw1000.get_state() == Dw1000State::Ready {
+            cx.spawn.start_receiving().unwrap();
+        }
+
+        let period = PAIRING_PERIOD + (get_unique_short_id() & 0xf) as u32 * PAIRING_PERIOD_STEP;
+        cx.schedule.pair(cx.scheduled + period.cycles()).unwrap();
+    }
+
+    #[task(schedule = [control_anchor], spawn = [start_receiving, finish_receiving, send_ping, show_range, control_tag], resources = [dw1000, clock, config, ping_seen, negotiation, pairing_session, charging])]
     fn control_anchor(cx: control_anchor::Context) {
-        static mut COUNT: u8 = 0;
+        static mut CONTROL: AnchorControl = AnchorControl::new();
 
-        let dw1000: &mut Dw1000Wrapper = cx.resources.dw1000;
+        let dw1000: &mut DwWrapperType = cx.resources.dw1000;
+        let clock: &mut Clock = cx.resources.clock;
+        let config: &mut Config = cx.resources.config;
+        let ping_seen: &mut bool = cx.resources.ping_seen;
+        let negotiation: &mut RoleNegotiation = cx.resources.negotiation;
+        let pairing_session: &mut Option<PairingSession> = cx.resources.pairing_session;
 
-        if let Dw1000State::Receiving = dw1000.get_state() {
+        // Paused while pairing or charging, `pair` or `measure_battery` restarts the control
+        // afterwards
+        if pairing_session.is_some() || *cx.resources.charging {
+            return;
+        }
+
+        // A negotiated anchor gives way to another anchor
+        if negotiation.step(*ping_seen) == Some(Role::Tag) {
+            defmt::info!("Other anchor found, becoming tag");
+            dw1000.reset_distance();
+            cx.spawn.control_tag().unwrap();
+            return;
+        }
+        *ping_seen = false;
+
+        // Show the worst case of all tags
+        dw1000.remove_stale_peers(clock.now_ms());
+        let profile = config.get_profile();
+        cx.spawn
+            .show_range(
+                dw1000.get_worst_range(profile.target_distance as u64, profile.tolerance as u64),
+            )
+            .unwrap();
+
+        let step = CONTROL.step(dw1000.get_state() == Dw1000State::Receiving);
+
+        if step.finish_receiving {
             cx.spawn.finish_receiving().unwrap();
         }
 
-        if *COUNT == 5 {
-            *COUNT = 0;
+        if step.send_ping {
             cx.spawn.send_ping().unwrap();
-        } else {
-            *COUNT += 1;
         }
 
         cx.schedule
-            .control_anchor(cx.scheduled + CTRL_PERIOD.cycles())
+            .control_anchor(cx.scheduled + ms_to_cycles(config.anchor_period).cycles())
+            .unwrap();
+    }
+
+    #[task(schedule = [control_convoy], spawn = [start_receiving, finish_receiving, send_ping, show_range], resources = [dw1000, clock, config, convoy, pairing_session, charging])]
+    fn control_convoy(cx: control_convoy::Context) {
+        let dw1000: &mut DwWrapperType = cx.resources.dw1000;
+        let clock: &mut Clock = cx.resources.clock;
+        let config: &mut Config = cx.resources.config;
+        let convoy: &mut ConvoyControl = cx.resources.convoy;
+        let pairing_session: &mut Option<PairingSession> = cx.resources.pairing_session;
+
+        // Paused while pairing or charging, `pair` or `measure_battery` restarts the control
+        // afterwards
+        if pairing_session.is_some() || *cx.resources.charging {
+            return;
+        }
+
+        let now_ms = clock.now_ms();
+
+        // Show the worst case of predecessor and successor. If the queue is full with ranges of
+        // received responses, the next cycle shows it.
+        dw1000.remove_stale_peers(now_ms);
+        let profile = config.get_profile();
+        let _ = cx.spawn.show_range(
+            dw1000.get_worst_range(profile.target_distance as u64, profile.tolerance as u64),
+        );
+
+        let step = convoy.step(now_ms, dw1000.get_state());
+
+        match step.command {
+            Some(RadioCommand::FinishReceiving) => cx.spawn.finish_receiving().unwrap(),
+            Some(RadioCommand::StartReceiving) => cx.spawn.start_receiving().unwrap(),
+            None => (),
+        }
+
+        if step.send_ping {
+            cx.spawn.send_ping().unwrap();
+        }
+
+        cx.schedule
+            .control_convoy(cx.scheduled + CONVOY_CTRL_PERIOD.cycles())
             .unwrap();
     }
 
-    #[task(schedule = [control_tag], spawn = [start_receiving, finish_receiving], resources = [ping_seen, indicator, valid_response_seen])]
+    #[task(schedule = [control_tag], spawn = [start_receiving, finish_receiving], resources = [dw1000, config, ping_seen, indicator, valid_response_seen, pairing_session, charging])]
     fn control_tag(cx: control_tag::Context) {
-        static mut COUNT: u8 = 0;
-        static mut CYCLES_SINCE_PING: u8 = 255;
-        static mut CYCLES_SINCE_VALID_RESPONSE: u8 = 255;
-        static mut ANCHOR_DETECTED: bool = false;
+        static mut CONTROL: TagControl = TagControl::new();
 
+        let dw1000: &mut DwWrapperType = cx.resources.dw1000;
+        let config: &mut Config = cx.resources.config;
         let indicator: &mut LedIndicator = cx.resources.indicator;
+        let pairing_session: &mut Option<PairingSession> = cx.resources.pairing_session;
 
         let ping_seen: &mut bool = cx.resources.ping_seen;
         let valid_response_seen: &mut bool = cx.resources.valid_response_seen;
 
-        // Anchor detection
-        if *ping_seen {
-            *ANCHOR_DETECTED = true;
-            *CYCLES_SINCE_PING = 0;
-        } else {
-            *CYCLES_SINCE_PING += 1;
+        // Paused while pairing or charging, `pair` or `measure_battery` restarts the control
+        // afterwards
+        if pairing_session.is_some() || *cx.resources.charging {
+            return;
         }
 
-        if *CYCLES_SINCE_PING > 50 && *ANCHOR_DETECTED {
-            indicator.set_out_of_range();
-            *ANCHOR_DETECTED = false;
-        }
-
-        if *COUNT == 10 {
-            *COUNT = 0;
-        } else if *ping_seen {
-            *COUNT = 1;
-        } else {
-            *COUNT += 1;
-        }
+        let step = CONTROL.step(*ping_seen, *valid_response_seen);
 
         *ping_seen = false;
+        *valid_response_seen = false;
 
-        // Valid response detection
-        if *valid_response_seen {
-            *CYCLES_SINCE_VALID_RESPONSE = 0;
-        } else {
-            *CYCLES_SINCE_VALID_RESPONSE = (*CYCLES_SINCE_VALID_RESPONSE).saturating_add(1);
-        }
-
-        if *CYCLES_SINCE_VALID_RESPONSE > 100 {
+        if step.set_out_of_range {
             indicator.set_out_of_range();
+            dw1000.reset_distance();
         }
 
-        *valid_response_seen = false;
+        match step.command {
+            Some(RadioCommand::FinishReceiving) => cx.spawn.finish_receiving().unwrap(),
+            Some(RadioCommand::StartReceiving) => cx.spawn.start_receiving().unwrap(),
+            None => (),
+        }
 
-        let delay_cycles = if *ANCHOR_DETECTED {
-            match *COUNT {
-                3 => cx.spawn.finish_receiving().unwrap(),
-                0 => cx.spawn.start_receiving().unwrap(),
-                _ => (),
-            }
-            CTRL_PERIOD.cycles()
+        let delay_cycles = if step.anchor_detected {
+            ms_to_cycles(config.tag_period).cycles()
         } else {
-            match *COUNT {
-                4 => cx.spawn.finish_receiving().unwrap(),
-                0 => cx.spawn.start_receiving().unwrap(),
-                _ => (),
-            }
-            CTRL_PERIOD_SLOW.cycles()
+            ms_to_cycles(config.tag_period_slow).cycles()
         };
 
         cx.schedule
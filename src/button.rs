@@ -0,0 +1,201 @@
+//! Debouncing and press detection of the push button
+//!
+//! The button is sampled periodically. A level only counts after it was stable for
+//! [`DEBOUNCE_SAMPLES`] samples. A press that is held for [`LONG_PRESS_MS`] is a long press, which
+//! is reported without waiting for the release. A short press is only reported after
+//! [`DOUBLE_PRESS_GAP_MS`] without a second press, otherwise both are a double press.
+
+use defmt::Format;
+
+/// Number of equal samples until a level change is accepted
+pub const DEBOUNCE_SAMPLES: u8 = 3;
+/// Minimum duration of a long press in ms
+pub const LONG_PRESS_MS: u32 = 1_000;
+/// Maximum time between the release and the second press of a double press in ms
+pub const DOUBLE_PRESS_GAP_MS: u32 = 300;
+
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum ButtonEvent {
+    ShortPress,
+    DoublePress,
+    LongPress,
+}
+
+/// Filters bouncing of the raw pin level
+pub struct Debouncer {
+    pressed: bool,
+    count: u8,
+}
+
+impl Debouncer {
+    pub const fn new(pressed: bool) -> Self {
+        Debouncer { pressed, count: 0 }
+    }
+
+    /// Add a raw sample and return the debounced level
+    pub fn update(&mut self, pressed: bool) -> bool {
+        if pressed == self.pressed {
+            self.count = 0;
+        } else {
+            self.count += 1;
+            if self.count >= DEBOUNCE_SAMPLES {
+                self.pressed = pressed;
+                self.count = 0;
+            }
+        }
+
+        self.pressed
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    Pressed {
+        since_ms: u32,
+    },
+    /// Released after a short press, waiting for a second press
+    Released {
+        at_ms: u32,
+    },
+    SecondPress {
+        since_ms: u32,
+    },
+    /// Wait for the release without reporting anything, e.g. after a long press
+    Held,
+}
+
+/// Detection of short, double and long presses
+pub struct Button {
+    debouncer: Debouncer,
+    state: State,
+}
+
+impl Button {
+    /// A button that is already pressed, e.g. to select the pairing mode at boot, is ignored
+    /// until it is released.
+    pub const fn new(pressed: bool) -> Self {
+        Button {
+            debouncer: Debouncer::new(pressed),
+            state: if pressed { State::Held } else { State::Idle },
+        }
+    }
+
+    /// Add a raw sample of the button at `now_ms`. Returns the detected event, if any.
+    pub fn update(&mut self, pressed: bool, now_ms: u32) -> Option<ButtonEvent> {
+        let pressed = self.debouncer.update(pressed);
+
+        let (state, event) = match (self.state, pressed) {
+            (State::Idle, true) => (State::Pressed { since_ms: now_ms }, None),
+            (State::Idle, false) => (State::Idle, None),
+            (State::Pressed { since_ms }, true) | (State::SecondPress { since_ms }, true)
+                if now_ms.wrapping_sub(since_ms) >= LONG_PRESS_MS =>
+            {
+                (State::Held, Some(ButtonEvent::LongPress))
+            }
+            (State::Pressed { .. }, false) => (State::Released { at_ms: now_ms }, None),
+            (State::Released { at_ms }, false)
+                if now_ms.wrapping_sub(at_ms) > DOUBLE_PRESS_GAP_MS =>
+            {
+                (State::Idle, Some(ButtonEvent::ShortPress))
+            }
+            (State::Released { .. }, true) => (State::SecondPress { since_ms: now_ms }, None),
+            (State::SecondPress { .. }, false) => (State::Idle, Some(ButtonEvent::DoublePress)),
+            (State::Held, false) => (State::Idle, None),
+            (state, _) => (state, None),
+        };
+
+        self.state = state;
+        event
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sample period of the firmware in ms
+    const PERIOD: u32 = 10;
+
+    /// Feed `levels` as (pressed, duration in ms) and collect the events
+    fn run(button: &mut Button, levels: &[(bool, u32)]) -> Vec<(u32, ButtonEvent)> {
+        let mut now_ms = 0;
+        let mut events = Vec::new();
+
+        for (pressed, duration) in levels.iter() {
+            for _ in 0..duration / PERIOD {
+                if let Some(event) = button.update(*pressed, now_ms) {
+                    events.push((now_ms, event));
+                }
+                now_ms += PERIOD;
+            }
+        }
+
+        events
+    }
+
+    fn events(levels: &[(bool, u32)]) -> Vec<ButtonEvent> {
+        run(&mut Button::new(false), levels)
+            .into_iter()
+            .map(|(_, event)| event)
+            .collect()
+    }
+
+    #[test]
+    fn bouncing_is_filtered() {
+        let mut debouncer = Debouncer::new(false);
+
+        assert!(!debouncer.update(true));
+        assert!(!debouncer.update(false));
+        assert!(!debouncer.update(true));
+        assert!(!debouncer.update(true));
+        assert!(debouncer.update(true));
+        assert!(debouncer.update(false));
+    }
+
+    #[test]
+    fn short_press() {
+        let events = run(&mut Button::new(false), &[(true, 100), (false, 500)]);
+
+        assert_eq!(events.len(), 1);
+        let (at_ms, event) = events[0];
+        assert_eq!(event, ButtonEvent::ShortPress);
+        assert!(at_ms > DOUBLE_PRESS_GAP_MS);
+    }
+
+    #[test]
+    fn double_press() {
+        assert_eq!(
+            events(&[(true, 100), (false, 150), (true, 100), (false, 500)]),
+            [ButtonEvent::DoublePress]
+        );
+    }
+
+    #[test]
+    fn presses_with_long_gap_are_separate() {
+        assert_eq!(
+            events(&[(true, 100), (false, 500), (true, 100), (false, 500)]),
+            [ButtonEvent::ShortPress, ButtonEvent::ShortPress]
+        );
+    }
+
+    #[test]
+    fn long_press_is_reported_while_held() {
+        let events = run(&mut Button::new(false), &[(true, 3_000), (false, 500)]);
+
+        assert_eq!(events.len(), 1);
+        let (at_ms, event) = events[0];
+        assert_eq!(event, ButtonEvent::LongPress);
+        assert!(at_ms < LONG_PRESS_MS + 100);
+    }
+
+    #[test]
+    fn button_held_at_boot_is_ignored() {
+        assert!(run(&mut Button::new(true), &[(true, 3_000), (false, 500)]).is_empty());
+    }
+
+    #[test]
+    fn glitches_are_not_presses() {
+        assert!(events(&[(true, 10), (false, 100), (true, 20), (false, 500)]).is_empty());
+    }
+}
@@ -0,0 +1,809 @@
+//! Text console on the serial port to tune the configuration in the field
+//!
+//! Every line is one command:
+//!
+//! - `get [setting]`: show one or all settings
+//! - `set <setting> <value>`: change a setting in RAM, `target` and `tolerance` belong to the
+//!   active profile and the tolerance has to be less than half the target. Selecting a profile
+//!   with `set profile <index>` is saved right away.
+//! - `set ranging <mode>`: `dw1000` for the ranging of the `dw1000` crate or `double-sided` for
+//!   asymmetric double-sided two-way ranging, all nodes have to use the same mode
+//! - `set pattern-<range> <colors>`: LEDs of a distance range, one letter per zone of the strip
+//!   from its short end, `-` for off, `r`ed, `g`reen, `b`lue or `a`mber
+//! - `save`: store the configuration in the flash
+//! - `defaults`: restore the defaults, except for the pairing
+//! - `reboot`: restart, e.g. to apply the radio settings
+//! - `stats`: distance, velocity and peers
+//! - `battery`: battery voltage and state of charge
+//! - `role`: current role
+//! - `help`: list the commands
+
+use crate::battery::BatteryReading;
+use crate::config::{Config, Profile, DEFAULT_CONFIG};
+use crate::indicator::{DistanceRange, Pattern, MAX_LEDS};
+use crate::role::Role;
+use crate::twr::RangingMode;
+use core::fmt::{self, Write};
+use defmt::Format;
+
+/// Maximum length of a command line
+pub const MAX_LINE_LEN: usize = 48;
+
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum CliError {
+    UnknownCommand,
+    UnknownSetting,
+    InvalidValue,
+    MissingArgument,
+    LineTooLong,
+    /// Writing the answer failed
+    Output,
+}
+
+impl From<fmt::Error> for CliError {
+    fn from(_: fmt::Error) -> Self {
+        CliError::Output
+    }
+}
+
+impl CliError {
+    pub fn description(self) -> &'static str {
+        match self {
+            CliError::UnknownCommand => "unknown command, try help",
+            CliError::UnknownSetting => "unknown setting, try get",
+            CliError::InvalidValue => "invalid value",
+            CliError::MissingArgument => "missing argument",
+            CliError::LineTooLong => "line too long",
+            CliError::Output => "output failed",
+        }
+    }
+}
+
+/// Complete command line received on the serial port
+#[derive(Clone, Copy)]
+pub struct Line {
+    buf: [u8; MAX_LINE_LEN],
+    len: usize,
+}
+
+impl Line {
+    pub fn as_str(&self) -> Result<&str, CliError> {
+        core::str::from_utf8(&self.buf[..self.len]).map_err(|_| CliError::InvalidValue)
+    }
+}
+
+/// Collects received bytes until the end of a line
+pub struct LineBuffer {
+    line: Line,
+    overflow: bool,
+}
+
+impl LineBuffer {
+    pub const fn new() -> Self {
+        LineBuffer {
+            line: Line {
+                buf: [0; MAX_LINE_LEN],
+                len: 0,
+            },
+            overflow: false,
+        }
+    }
+
+    /// Add a received byte. Returns the line at the end of a non-empty line.
+    pub fn push(&mut self, byte: u8) -> Option<Result<Line, CliError>> {
+        match byte {
+            b'\r' | b'\n' => {
+                let line = self.line;
+                let overflow = self.overflow;
+                self.line.len = 0;
+                self.overflow = false;
+
+                match (line.len, overflow) {
+                    (_, true) => Some(Err(CliError::LineTooLong)),
+                    (0, false) => None,
+                    (_, false) => Some(Ok(line)),
+                }
+            }
+            // Backspace and delete
+            0x08 | 0x7f => {
+                self.line.len = self.line.len.saturating_sub(1);
+                None
+            }
+            _ if self.line.len == MAX_LINE_LEN => {
+                self.overflow = true;
+                None
+            }
+            _ => {
+                self.line.buf[self.line.len] = byte;
+                self.line.len += 1;
+                None
+            }
+        }
+    }
+}
+
+impl Default for LineBuffer {
+    fn default() -> Self {
+        LineBuffer::new()
+    }
+}
+
+/// Setting of the [`Config`] that can be changed on the console
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum Setting {
+    Profile,
+    Target,
+    Tolerance,
+    Role,
+    PanId,
+    Address,
+    RxAntennaDelay,
+    TxAntennaDelay,
+    Ranging,
+    AnchorPeriod,
+    TagPeriod,
+    TagPeriodSlow,
+    BatteryPeriod,
+    Leds,
+    LedsReversed,
+    /// LEDs that are lit for a distance range
+    Pattern(DistanceRange),
+}
+
+impl Setting {
+    pub const ALL: [Setting; 22] = [
+        Setting::Profile,
+        Setting::Target,
+        Setting::Tolerance,
+        Setting::Role,
+        Setting::PanId,
+        Setting::Address,
+        Setting::RxAntennaDelay,
+        Setting::TxAntennaDelay,
+        Setting::Ranging,
+        Setting::AnchorPeriod,
+        Setting::TagPeriod,
+        Setting::TagPeriodSlow,
+        Setting::BatteryPeriod,
+        Setting::Leds,
+        Setting::LedsReversed,
+        Setting::Pattern(DistanceRange::OutOfRange),
+        Setting::Pattern(DistanceRange::Long),
+        Setting::Pattern(DistanceRange::OkLong),
+        Setting::Pattern(DistanceRange::Ok),
+        Setting::Pattern(DistanceRange::OkShort),
+        Setting::Pattern(DistanceRange::Short),
+        Setting::Pattern(DistanceRange::ClosingFast),
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Setting::Profile => "profile",
+            Setting::Target => "target",
+            Setting::Tolerance => "tolerance",
+            Setting::Role => "role",
+            Setting::PanId => "pan",
+            Setting::Address => "address",
+            Setting::RxAntennaDelay => "rx-delay",
+            Setting::TxAntennaDelay => "tx-delay",
+            Setting::Ranging => "ranging",
+            Setting::AnchorPeriod => "anchor-period",
+            Setting::TagPeriod => "tag-period",
+            Setting::TagPeriodSlow => "tag-period-slow",
+            Setting::BatteryPeriod => "battery-period",
+            Setting::Leds => "leds",
+            Setting::LedsReversed => "leds-reversed",
+            Setting::Pattern(range) => match range {
+                DistanceRange::OutOfRange => "pattern-out",
+                DistanceRange::Long => "pattern-long",
+                DistanceRange::OkLong => "pattern-ok-long",
+                DistanceRange::Ok => "pattern-ok",
+                DistanceRange::OkShort => "pattern-ok-short",
+                DistanceRange::Short => "pattern-short",
+                DistanceRange::ClosingFast => "pattern-closing",
+            },
+        }
+    }
+
+    /// Settings of the radio and the LED strip are only applied at boot
+    pub fn needs_reboot(self) -> bool {
+        matches!(
+            self,
+            Setting::Role
+                | Setting::PanId
+                | Setting::Address
+                | Setting::RxAntennaDelay
+                | Setting::TxAntennaDelay
+                | Setting::Ranging
+                | Setting::Leds
+                | Setting::LedsReversed
+                | Setting::Pattern(_)
+        )
+    }
+
+    fn parse(name: &str) -> Result<Self, CliError> {
+        Setting::ALL
+            .iter()
+            .copied()
+            .find(|setting| setting.name() == name)
+            .ok_or(CliError::UnknownSetting)
+    }
+
+    /// Numeric value of the setting, `None` for the role, the ranging mode, the patterns and an
+    /// automatic address
+    fn get(self, config: &Config) -> Option<u16> {
+        match self {
+            Setting::Profile => Some(config.profile as u16),
+            Setting::Target => Some(config.get_profile().target_distance),
+            Setting::Tolerance => Some(config.get_profile().tolerance),
+            Setting::Role => None,
+            Setting::PanId => Some(config.pan_id),
+            Setting::Address => config.address,
+            Setting::RxAntennaDelay => Some(config.rx_antenna_delay),
+            Setting::TxAntennaDelay => Some(config.tx_antenna_delay),
+            Setting::Ranging => None,
+            Setting::AnchorPeriod => Some(config.anchor_period),
+            Setting::TagPeriod => Some(config.tag_period),
+            Setting::TagPeriodSlow => Some(config.tag_period_slow),
+            Setting::BatteryPeriod => Some(config.battery_period),
+            Setting::Leds => Some(config.led_count as u16),
+            Setting::LedsReversed => Some(config.leds_reversed as u16),
+            Setting::Pattern(_) => None,
+        }
+    }
+
+    fn write<W: Write>(self, config: &Config, out: &mut W) -> fmt::Result {
+        write!(out, "{} ", self.name())?;
+        match (self, self.get(config)) {
+            (Setting::Role, _) => write_role(out, config.role)?,
+            (Setting::Ranging, _) => write_ranging_mode(out, config.ranging_mode)?,
+            (Setting::Pattern(range), _) => write!(out, "{}", config.led_patterns.get(range))?,
+            (Setting::PanId, Some(value)) | (Setting::Address, Some(value)) => {
+                write!(out, "0x{:04x}", value)?
+            }
+            (_, Some(value)) => write!(out, "{}", value)?,
+            (_, None) => write!(out, "auto")?,
+        }
+        writeln!(out)
+    }
+}
+
+/// New value of a setting
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum Value {
+    Number(u16),
+    Role(Role),
+    Pattern(Pattern),
+    RangingMode(RangingMode),
+    /// Negotiated role or address derived from the role
+    Auto,
+}
+
+impl Value {
+    fn parse<'a>(
+        setting: Setting,
+        mut args: impl Iterator<Item = &'a str>,
+    ) -> Result<Self, CliError> {
+        let value = match (setting, args.next().ok_or(CliError::MissingArgument)?) {
+            (Setting::Role, "auto") | (Setting::Address, "auto") => Value::Auto,
+            (Setting::Role, "anchor") => Value::Role(Role::Anchor),
+            (Setting::Role, "tag") => Value::Role(Role::Tag),
+            (Setting::Role, "convoy") => {
+                // The configuration stores 7 bits of the position
+                match parse_number(args.next().ok_or(CliError::MissingArgument)?)? {
+                    position if position < 0x80 => Value::Role(Role::Convoy(position as u8)),
+                    _ => return Err(CliError::InvalidValue),
+                }
+            }
+            (Setting::Role, _) => return Err(CliError::InvalidValue),
+            (Setting::Ranging, "dw1000") => Value::RangingMode(RangingMode::Dw1000),
+            (Setting::Ranging, "double-sided") => Value::RangingMode(RangingMode::DoubleSided),
+            (Setting::Ranging, _) => return Err(CliError::InvalidValue),
+            (Setting::Pattern(_), pattern) => {
+                Value::Pattern(Pattern::parse(pattern).ok_or(CliError::InvalidValue)?)
+            }
+            (_, number) => Value::Number(parse_number(number)?),
+        };
+
+        match args.next() {
+            Some(_) => Err(CliError::InvalidValue),
+            None => Ok(value),
+        }
+    }
+}
+
+/// Decimal or hexadecimal with `0x` prefix
+fn parse_number(s: &str) -> Result<u16, CliError> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| CliError::InvalidValue)
+}
+
+fn write_role<W: Write>(out: &mut W, role: Option<Role>) -> fmt::Result {
+    match role {
+        None => write!(out, "auto"),
+        Some(Role::Anchor) => write!(out, "anchor"),
+        Some(Role::Tag) => write!(out, "tag"),
+        Some(Role::Convoy(position)) => write!(out, "convoy {}", position),
+    }
+}
+
+fn write_ranging_mode<W: Write>(out: &mut W, mode: RangingMode) -> fmt::Result {
+    match mode {
+        RangingMode::Dw1000 => write!(out, "dw1000"),
+        RangingMode::DoubleSided => write!(out, "double-sided"),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum Command {
+    Help,
+    /// Show one setting or all if `None`
+    Get(Option<Setting>),
+    Set(Setting, Value),
+    Save,
+    Defaults,
+    Reboot,
+    Stats,
+    Battery,
+    Role,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Self, CliError> {
+        let mut words = line.split_whitespace();
+        let command = words.next().ok_or(CliError::UnknownCommand)?;
+
+        let command = match command {
+            "help" => Command::Help,
+            "get" => Command::Get(words.next().map(Setting::parse).transpose()?),
+            "set" => {
+                let setting = Setting::parse(words.next().ok_or(CliError::MissingArgument)?)?;
+                return Ok(Command::Set(setting, Value::parse(setting, words)?));
+            }
+            "save" => Command::Save,
+            "defaults" => Command::Defaults,
+            "reboot" => Command::Reboot,
+            "stats" => Command::Stats,
+            "battery" => Command::Battery,
+            "role" => Command::Role,
+            _ => return Err(CliError::UnknownCommand),
+        };
+
+        match words.next() {
+            Some(_) => Err(CliError::InvalidValue),
+            None => Ok(command),
+        }
+    }
+}
+
+/// State of the node shown by the diagnostic commands
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Status {
+    /// `None` while the role is negotiated
+    pub role: Option<Role>,
+    pub paired: bool,
+    pub distance: Option<u64>,
+    pub velocity: Option<i32>,
+    pub peers: usize,
+    pub rejected: u32,
+    /// Distance reports of the peers that were lost
+    pub lost_reports: u32,
+    /// `None` if the ADC failed
+    pub battery: Option<BatteryReading>,
+}
+
+/// What the caller has to do after a command
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum Action {
+    None,
+    /// Store the configuration in the flash
+    Save,
+    Reboot,
+}
+
+/// Execute `command` on `config` and write the answer to `out`
+pub fn execute<W: Write>(
+    command: Command,
+    config: &mut Config,
+    status: &Status,
+    out: &mut W,
+) -> Result<Action, CliError> {
+    match command {
+        Command::Help => {
+            writeln!(
+                out,
+                "get [setting], set <setting> <value>, save, defaults, reboot"
+            )?;
+            writeln!(out, "stats, battery, role, help")?;
+        }
+        Command::Get(Some(setting)) => setting.write(config, out)?,
+        Command::Get(None) => {
+            for setting in Setting::ALL.iter() {
+                setting.write(config, out)?;
+            }
+        }
+        Command::Set(setting, value) => {
+            set(config, setting, value)?;
+            setting.write(config, out)?;
+            if setting.needs_reboot() {
+                writeln!(out, "applied after save and reboot")?;
+            }
+            if setting == Setting::Profile {
+                return Ok(Action::Save);
+            }
+        }
+        Command::Save => return Ok(Action::Save),
+        Command::Defaults => {
+            // The partner would ignore a profile with a lower sync version
+            *config = Config {
+                pairing: config.pairing,
+                sync_version: config.sync_version,
+                ..DEFAULT_CONFIG
+            };
+            writeln!(out, "ok")?;
+        }
+        Command::Reboot => return Ok(Action::Reboot),
+        Command::Stats => {
+            match status.distance {
+                Some(distance) => writeln!(out, "distance {} cm", distance)?,
+                None => writeln!(out, "distance none")?,
+            }
+            match status.velocity {
+                Some(velocity) => writeln!(out, "velocity {} cm/s", velocity)?,
+                None => writeln!(out, "velocity none")?,
+            }
+            writeln!(out, "peers {}", status.peers)?;
+            writeln!(out, "rejected {}", status.rejected)?;
+            writeln!(out, "lost reports {}", status.lost_reports)?;
+        }
+        Command::Battery => match status.battery {
+            Some(battery) => {
+                writeln!(out, "battery {} mV, {} %", battery.voltage, battery.percent)?
+            }
+            None => writeln!(out, "battery unknown")?,
+        },
+        Command::Role => {
+            write!(out, "role ")?;
+            match status.role {
+                Some(role) => write_role(out, Some(role))?,
+                None => write!(out, "negotiating")?,
+            }
+            writeln!(out, "{}", if status.paired { ", paired" } else { "" })?;
+        }
+    }
+
+    Ok(Action::None)
+}
+
+fn set(config: &mut Config, setting: Setting, value: Value) -> Result<(), CliError> {
+    match (setting, value) {
+        (Setting::Role, Value::Role(role)) => config.role = Some(role),
+        (Setting::Role, Value::Auto) => config.role = None,
+        (Setting::Address, Value::Auto) => config.address = None,
+        (Setting::Address, Value::Number(address)) => config.address = Some(address),
+        (Setting::Pattern(range), Value::Pattern(pattern)) => {
+            *config.led_patterns.get_mut(range) = pattern
+        }
+        // Broadcast PAN ID
+        (Setting::PanId, Value::Number(0xffff)) => return Err(CliError::InvalidValue),
+        (Setting::PanId, Value::Number(pan_id)) => config.pan_id = pan_id,
+        (Setting::Profile, Value::Number(index)) => {
+            if index > u8::MAX as u16 || !config.select_profile(index as u8) {
+                return Err(CliError::InvalidValue);
+            }
+        }
+        (Setting::Target, Value::Number(target_distance)) => set_profile(
+            config,
+            Profile {
+                target_distance,
+                ..config.get_profile()
+            },
+        )?,
+        (Setting::Tolerance, Value::Number(tolerance)) => set_profile(
+            config,
+            Profile {
+                tolerance,
+                ..config.get_profile()
+            },
+        )?,
+        (Setting::RxAntennaDelay, Value::Number(delay)) => config.rx_antenna_delay = delay,
+        (Setting::TxAntennaDelay, Value::Number(delay)) => config.tx_antenna_delay = delay,
+        (Setting::Ranging, Value::RangingMode(mode)) => config.ranging_mode = mode,
+        (Setting::LedsReversed, Value::Number(reversed)) if reversed <= 1 => {
+            config.leds_reversed = reversed == 1
+        }
+        // A period of 0 would keep the control task running all the time
+        (_, Value::Number(0)) => return Err(CliError::InvalidValue),
+        (Setting::AnchorPeriod, Value::Number(period)) => config.anchor_period = period,
+        (Setting::TagPeriod, Value::Number(period)) => config.tag_period = period,
+        (Setting::TagPeriodSlow, Value::Number(period)) => config.tag_period_slow = period,
+        (Setting::BatteryPeriod, Value::Number(period)) => config.battery_period = period,
+        (Setting::Leds, Value::Number(count)) if count as usize <= MAX_LEDS => {
+            config.led_count = count as u8
+        }
+        _ => return Err(CliError::InvalidValue),
+    }
+
+    Ok(())
+}
+
+/// Change the active profile, which has to stay valid
+fn set_profile(config: &mut Config, profile: Profile) -> Result<(), CliError> {
+    if !profile.is_valid() {
+        return Err(CliError::InvalidValue);
+    }
+
+    *config.get_profile_mut() = profile;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status() -> Status {
+        Status {
+            role: Some(Role::Tag),
+            paired: true,
+            distance: Some(123),
+            velocity: Some(-15),
+            peers: 1,
+            rejected: 2,
+            lost_reports: 3,
+            battery: Some(BatteryReading {
+                voltage: 3_900,
+                percent: 68,
+            }),
+        }
+    }
+
+    fn run(config: &mut Config, line: &str) -> Result<(Action, String), CliError> {
+        let mut out = String::new();
+        let action = execute(Command::parse(line)?, config, &status(), &mut out)?;
+        Ok((action, out))
+    }
+
+    #[test]
+    fn lines_are_collected() {
+        let mut buffer = LineBuffer::new();
+
+        let lines: Vec<_> = b"\r\nget\x08t target\r\n"
+            .iter()
+            .filter_map(|byte| buffer.push(*byte))
+            .collect();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].unwrap().as_str(), Ok("get target"));
+
+        for _ in 0..MAX_LINE_LEN + 1 {
+            assert!(buffer.push(b'a').is_none());
+        }
+        assert!(matches!(
+            buffer.push(b'\n'),
+            Some(Err(CliError::LineTooLong))
+        ));
+        assert!(buffer.push(b'a').is_none());
+        assert_eq!(buffer.push(b'\n').unwrap().unwrap().as_str(), Ok("a"));
+    }
+
+    #[test]
+    fn commands_are_parsed() {
+        assert_eq!(Command::parse("get"), Ok(Command::Get(None)));
+        assert_eq!(
+            Command::parse("  get   tolerance "),
+            Ok(Command::Get(Some(Setting::Tolerance)))
+        );
+        assert_eq!(
+            Command::parse("set tolerance 30"),
+            Ok(Command::Set(Setting::Tolerance, Value::Number(30)))
+        );
+        assert_eq!(
+            Command::parse("set pan 0x0d57"),
+            Ok(Command::Set(Setting::PanId, Value::Number(0x0d57)))
+        );
+        assert_eq!(
+            Command::parse("set role convoy 2"),
+            Ok(Command::Set(Setting::Role, Value::Role(Role::Convoy(2))))
+        );
+        assert_eq!(
+            Command::parse("set address auto"),
+            Ok(Command::Set(Setting::Address, Value::Auto))
+        );
+        assert_eq!(Command::parse("stats"), Ok(Command::Stats));
+    }
+
+    #[test]
+    fn invalid_commands_are_rejected() {
+        assert_eq!(Command::parse(""), Err(CliError::UnknownCommand));
+        assert_eq!(Command::parse("gte"), Err(CliError::UnknownCommand));
+        assert_eq!(Command::parse("get speed"), Err(CliError::UnknownSetting));
+        assert_eq!(Command::parse("set target"), Err(CliError::MissingArgument));
+        assert_eq!(Command::parse("set target -1"), Err(CliError::InvalidValue));
+        assert_eq!(
+            Command::parse("set target 70000"),
+            Err(CliError::InvalidValue)
+        );
+        assert_eq!(
+            Command::parse("set target 1 2"),
+            Err(CliError::InvalidValue)
+        );
+        assert_eq!(Command::parse("set role bike"), Err(CliError::InvalidValue));
+        assert_eq!(
+            Command::parse("set role convoy 200"),
+            Err(CliError::InvalidValue)
+        );
+        assert_eq!(Command::parse("stats now"), Err(CliError::InvalidValue));
+    }
+
+    #[test]
+    fn settings_are_changed() {
+        let mut config = DEFAULT_CONFIG;
+
+        assert_eq!(
+            run(&mut config, "set tolerance 30"),
+            Ok((Action::None, "tolerance 30\n".into()))
+        );
+        assert_eq!(config.get_profile().tolerance, 30);
+        assert_eq!(
+            run(&mut config, "get target"),
+            Ok((Action::None, "target 100\n".into()))
+        );
+
+        let (_, out) = run(&mut config, "set role anchor").unwrap();
+        assert_eq!(out, "role anchor\napplied after save and reboot\n");
+        assert_eq!(config.role, Some(Role::Anchor));
+
+        assert_eq!(
+            run(&mut config, "set tag-period 0"),
+            Err(CliError::InvalidValue)
+        );
+        assert_eq!(
+            run(&mut config, "set target auto"),
+            Err(CliError::InvalidValue)
+        );
+        assert_eq!(config.tag_period, DEFAULT_CONFIG.tag_period);
+
+        let (_, out) = run(&mut config, "set leds 8").unwrap();
+        assert_eq!(out, "leds 8\napplied after save and reboot\n");
+        run(&mut config, "set leds-reversed 1").unwrap();
+        assert_eq!((config.led_count, config.leds_reversed), (8, true));
+        assert_eq!(run(&mut config, "set leds 0"), Err(CliError::InvalidValue));
+        assert_eq!(run(&mut config, "set leds 17"), Err(CliError::InvalidValue));
+        assert_eq!(
+            run(&mut config, "set leds-reversed 2"),
+            Err(CliError::InvalidValue)
+        );
+
+        let (_, out) = run(&mut config, "set pattern-ok-short rrg--").unwrap();
+        assert_eq!(
+            out,
+            "pattern-ok-short rrg--\napplied after save and reboot\n"
+        );
+        assert_eq!(
+            config.led_patterns.ok_short,
+            Pattern::parse("rrg--").unwrap()
+        );
+        assert_eq!(
+            run(&mut config, "get pattern-long"),
+            Ok((Action::None, "pattern-long ---bb\n".into()))
+        );
+        assert_eq!(
+            run(&mut config, "set pattern-ok 1"),
+            Err(CliError::InvalidValue)
+        );
+
+        assert_eq!(
+            run(&mut config, "get ranging"),
+            Ok((Action::None, "ranging dw1000\n".into()))
+        );
+        let (_, out) = run(&mut config, "set ranging double-sided").unwrap();
+        assert_eq!(out, "ranging double-sided\napplied after save and reboot\n");
+        assert_eq!(config.ranging_mode, RangingMode::DoubleSided);
+        assert_eq!(
+            run(&mut config, "set ranging 1"),
+            Err(CliError::InvalidValue)
+        );
+
+        let (_, out) = run(&mut config, "get").unwrap();
+        assert_eq!(out.lines().count(), Setting::ALL.len());
+        assert!(out.contains("pan 0x0d57\n"));
+        assert!(out.contains("address auto\n"));
+    }
+
+    #[test]
+    fn profiles_are_selected() {
+        let mut config = DEFAULT_CONFIG;
+
+        assert_eq!(
+            run(&mut config, "set profile 1"),
+            Ok((Action::Save, "profile 1\n".into()))
+        );
+        assert_eq!(
+            run(&mut config, "get target"),
+            Ok((Action::None, "target 300\n".into()))
+        );
+
+        run(&mut config, "set target 350").unwrap();
+        assert_eq!(config.profiles[1].target_distance, 350);
+        assert_eq!(config.profiles[0], DEFAULT_CONFIG.profiles[0]);
+
+        assert_eq!(
+            run(&mut config, "set profile 3"),
+            Err(CliError::InvalidValue)
+        );
+        assert_eq!(config.profile, 1);
+    }
+
+    #[test]
+    fn profiles_stay_valid() {
+        let mut config = DEFAULT_CONFIG;
+
+        // The tolerance has to be less than half the target distance
+        assert_eq!(
+            run(&mut config, "set target 0"),
+            Err(CliError::InvalidValue)
+        );
+        assert_eq!(
+            run(&mut config, "set tolerance 60"),
+            Err(CliError::InvalidValue)
+        );
+        assert_eq!(
+            run(&mut config, "set tolerance 50"),
+            Err(CliError::InvalidValue)
+        );
+        assert_eq!(config.get_profile(), DEFAULT_CONFIG.profiles[0]);
+
+        run(&mut config, "set tolerance 49").unwrap();
+        assert_eq!(
+            run(&mut config, "set target 98"),
+            Err(CliError::InvalidValue)
+        );
+        run(&mut config, "set target 99").unwrap();
+        assert_eq!(
+            config.get_profile(),
+            Profile {
+                target_distance: 99,
+                tolerance: 49,
+            }
+        );
+    }
+
+    #[test]
+    fn defaults_keep_the_pairing() {
+        let pairing = crate::pairing::Pairing::derive(0x1234, 0xabcd);
+        let sync_version = crate::sync::Version {
+            counter: 3,
+            origin: 0x1234,
+        };
+        let mut config = Config {
+            profile: 1,
+            pairing: Some(pairing),
+            sync_version,
+            ..DEFAULT_CONFIG
+        };
+
+        run(&mut config, "defaults").unwrap();
+        assert_eq!(config.profile, DEFAULT_CONFIG.profile);
+        assert_eq!(config.pairing, Some(pairing));
+        assert_eq!(config.sync_version, sync_version);
+    }
+
+    #[test]
+    fn actions_and_diagnostics() {
+        let mut config = DEFAULT_CONFIG;
+
+        assert_eq!(run(&mut config, "save").unwrap().0, Action::Save);
+        assert_eq!(run(&mut config, "reboot").unwrap().0, Action::Reboot);
+        assert_eq!(
+            run(&mut config, "stats").unwrap().1,
+            "distance 123 cm\nvelocity -15 cm/s\npeers 1\nrejected 2\nlost reports 3\n"
+        );
+        assert_eq!(
+            run(&mut config, "battery").unwrap().1,
+            "battery 3900 mV, 68 %\n"
+        );
+        assert_eq!(run(&mut config, "role").unwrap().1, "role tag, paired\n");
+    }
+}
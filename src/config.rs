@@ -0,0 +1,762 @@
+//! Persistent configuration in the last pages of the flash
+//!
+//! The configuration is stored as a record with magic, version, sequence number and CRC. New
+//! records are appended to the configuration area instead of overwriting the last one, so a page
+//! is only erased when the writes reach it. The valid record with the highest sequence number is
+//! the current configuration. If there is none, e.g. after flashing or after a format change, the
+//! defaults are used.
+
+use crate::error::Error;
+use crate::indicator::{DistanceRange, Pattern, RangePatterns, DEFAULT_PATTERNS, MAX_LEDS};
+use crate::pairing::{Pairing, DEFAULT_PAN_ID};
+use crate::role::Role;
+use crate::sync::Version;
+use crate::twr::{RangingMode, DEFAULT_RANGING_MODE};
+use defmt::Format;
+
+#[cfg(target_os = "none")]
+pub use self::hardware::Stm32Flash;
+
+/// Size of a flash page, the smallest unit that can be erased
+pub const PAGE_SIZE: usize = 1024;
+/// Number of pages of the configuration area at the end of the flash
+pub const PAGES: usize = 2;
+/// Space of a record in the configuration area
+const RECORD_LEN: usize = 128;
+const SLOTS_PER_PAGE: usize = PAGE_SIZE / RECORD_LEN;
+const SLOTS: usize = PAGES * SLOTS_PER_PAGE;
+
+const MAGIC: u16 = 0xbd1c;
+/// Records of other versions are ignored
+const VERSION: u8 = 6;
+const HEADER_LEN: usize = 8;
+const PAYLOAD_LEN: usize = 58;
+const CRC_LEN: usize = 2;
+
+/// Number of distance profiles
+pub const PROFILES: usize = 3;
+
+/// Target distance and tolerance in cm, e.g. for riding on roads or trails
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct Profile {
+    /// Distance in cm that is shown as ok
+    pub target_distance: u16,
+    pub tolerance: u16,
+}
+
+impl Profile {
+    /// A profile needs a short range, i.e. the tolerance has to be less than half the target
+    /// distance
+    pub fn is_valid(&self) -> bool {
+        2 * (self.tolerance as u32) < self.target_distance as u32
+    }
+}
+
+/// Settings that can be changed without building a new firmware
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct Config {
+    pub profiles: [Profile; PROFILES],
+    /// Index of the active profile
+    pub profile: u8,
+    /// Role of the node, negotiated if `None` and not forced by the strap pin
+    pub role: Option<Role>,
+    /// PAN ID until paired
+    pub pan_id: u16,
+    /// Short address instead of the one derived from the role
+    pub address: Option<u16>,
+    pub pairing: Option<Pairing>,
+    pub rx_antenna_delay: u16,
+    pub tx_antenna_delay: u16,
+    /// Ranging scheme of the radio
+    pub ranging_mode: RangingMode,
+    /// Control periods in ms
+    pub anchor_period: u16,
+    pub tag_period: u16,
+    /// Control period of the tag in ms while searching for an anchor
+    pub tag_period_slow: u16,
+    pub battery_period: u16,
+    /// Number of LEDs of the indicator strip
+    pub led_count: u8,
+    /// The first LED of the strip is at the long end
+    pub leds_reversed: bool,
+    /// LEDs that are lit for each distance range
+    pub led_patterns: RangePatterns,
+    /// Version of the profile shared with the partner, see [`crate::sync`]
+    pub sync_version: Version,
+}
+
+pub const DEFAULT_CONFIG: Config = Config {
+    profiles: [
+        // Close
+        Profile {
+            target_distance: 100,
+            tolerance: 20,
+        },
+        // Road
+        Profile {
+            target_distance: 300,
+            tolerance: 50,
+        },
+        // Trail
+        Profile {
+            target_distance: 800,
+            tolerance: 100,
+        },
+    ],
+    profile: 0,
+    role: None,
+    pan_id: DEFAULT_PAN_ID,
+    address: None,
+    pairing: None,
+    // These are the hardcoded calibration values from the dwm1001-examples repository[1].
+    // Ideally, the calibration values would be determined using the proper calibration procedure,
+    // but hopefully those are good enough for now.
+    //
+    // [1] https://github.com/Decawave/dwm1001-examples
+    rx_antenna_delay: 16456,
+    tx_antenna_delay: 16300,
+    ranging_mode: DEFAULT_RANGING_MODE,
+    anchor_period: 100,
+    tag_period: 50,
+    tag_period_slow: 156,
+    battery_period: 4_000,
+    led_count: 5,
+    leds_reversed: false,
+    led_patterns: DEFAULT_PATTERNS,
+    sync_version: Version {
+        counter: 0,
+        origin: 0,
+    },
+};
+
+impl Config {
+    /// Target distance and tolerance that are shown
+    pub fn get_profile(&self) -> Profile {
+        self.profiles[self.profile as usize]
+    }
+
+    pub fn get_profile_mut(&mut self) -> &mut Profile {
+        &mut self.profiles[self.profile as usize]
+    }
+
+    /// Activate the profile with `index`. Returns `false` if there is no such profile.
+    pub fn select_profile(&mut self, index: u8) -> bool {
+        if (index as usize) < PROFILES {
+            self.profile = index;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Activate the next profile, e.g. on a button press
+    pub fn next_profile(&mut self) {
+        self.profile = (self.profile + 1) % PROFILES as u8;
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        DEFAULT_CONFIG
+    }
+}
+
+/// Little endian writer for the payload of a record
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl Writer<'_> {
+    fn u8(&mut self, value: u8) {
+        self.buf[self.pos] = value;
+        self.pos += 1;
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.buf[self.pos..self.pos + 2].copy_from_slice(&value.to_le_bytes());
+        self.pos += 2;
+    }
+}
+
+/// Little endian reader for the payload of a record
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn u8(&mut self) -> u8 {
+        self.pos += 1;
+        self.buf[self.pos - 1]
+    }
+
+    fn u16(&mut self) -> u16 {
+        self.pos += 2;
+        u16::from_le_bytes([self.buf[self.pos - 2], self.buf[self.pos - 1]])
+    }
+}
+
+const NO_ROLE: u8 = 0xff;
+const CONVOY_ROLE: u8 = 0x80;
+const NO_ADDRESS: u16 = 0xffff;
+
+impl Config {
+    fn encode_payload(&self, buf: &mut [u8]) {
+        let mut w = Writer { buf, pos: 0 };
+
+        for profile in self.profiles.iter() {
+            w.u16(profile.target_distance);
+            w.u16(profile.tolerance);
+        }
+        w.u8(self.profile);
+        w.u8(match self.role {
+            None => NO_ROLE,
+            Some(Role::Anchor) => 0,
+            Some(Role::Tag) => 1,
+            Some(Role::Convoy(position)) => CONVOY_ROLE | (position & 0x7f),
+        });
+        w.u16(self.pan_id);
+        w.u16(self.address.unwrap_or(NO_ADDRESS));
+        match self.pairing {
+            Some(pairing) => {
+                w.u8(1);
+                w.u16(pairing.pan_id);
+                w.u16(pairing.address);
+                w.u16(pairing.partner_address);
+            }
+            None => {
+                w.u8(0);
+                w.u16(0);
+                w.u16(0);
+                w.u16(0);
+            }
+        }
+        w.u16(self.rx_antenna_delay);
+        w.u16(self.tx_antenna_delay);
+        w.u8(match self.ranging_mode {
+            RangingMode::Dw1000 => 0,
+            RangingMode::DoubleSided => 1,
+        });
+        w.u16(self.anchor_period);
+        w.u16(self.tag_period);
+        w.u16(self.tag_period_slow);
+        w.u16(self.battery_period);
+        w.u8(self.led_count);
+        w.u8(self.leds_reversed as u8);
+        for range in DistanceRange::ALL.iter() {
+            w.u16(self.led_patterns.get(*range).encode());
+        }
+        w.u16(self.sync_version.counter);
+        w.u16(self.sync_version.origin);
+    }
+
+    fn decode_payload(buf: &[u8]) -> Option<Self> {
+        let mut r = Reader { buf, pos: 0 };
+
+        let mut profiles = DEFAULT_CONFIG.profiles;
+        for profile in profiles.iter_mut() {
+            profile.target_distance = r.u16();
+            profile.tolerance = r.u16();
+            if !profile.is_valid() {
+                return None;
+            }
+        }
+        let profile = r.u8();
+        if profile as usize >= PROFILES {
+            return None;
+        }
+        let role = match r.u8() {
+            NO_ROLE => None,
+            0 => Some(Role::Anchor),
+            1 => Some(Role::Tag),
+            role if role & CONVOY_ROLE != 0 => Some(Role::Convoy(role & 0x7f)),
+            _ => return None,
+        };
+        let pan_id = r.u16();
+        let address = match r.u16() {
+            NO_ADDRESS => None,
+            address => Some(address),
+        };
+        let paired = r.u8() != 0;
+        let pairing = Pairing {
+            pan_id: r.u16(),
+            address: r.u16(),
+            partner_address: r.u16(),
+        };
+        let rx_antenna_delay = r.u16();
+        let tx_antenna_delay = r.u16();
+        let ranging_mode = match r.u8() {
+            0 => RangingMode::Dw1000,
+            1 => RangingMode::DoubleSided,
+            _ => return None,
+        };
+        let anchor_period = r.u16();
+        let tag_period = r.u16();
+        let tag_period_slow = r.u16();
+        let battery_period = r.u16();
+        let led_count = r.u8();
+        if led_count == 0 || led_count as usize > MAX_LEDS {
+            return None;
+        }
+        let leds_reversed = r.u8() != 0;
+        let mut led_patterns = DEFAULT_PATTERNS;
+        for range in DistanceRange::ALL.iter() {
+            *led_patterns.get_mut(*range) = Pattern::decode(r.u16())?;
+        }
+        let sync_version = Version {
+            counter: r.u16(),
+            origin: r.u16(),
+        };
+
+        Some(Config {
+            profiles,
+            profile,
+            role,
+            pan_id,
+            address,
+            pairing: if paired { Some(pairing) } else { None },
+            rx_antenna_delay,
+            tx_antenna_delay,
+            ranging_mode,
+            anchor_period,
+            tag_period,
+            tag_period_slow,
+            battery_period,
+            led_count,
+            leds_reversed,
+            led_patterns,
+            sync_version,
+        })
+    }
+
+    /// Encode the configuration as record with sequence number `sequence`
+    pub fn serialize(&self, sequence: u32, buf: &mut [u8; RECORD_LEN]) {
+        *buf = [0xff; RECORD_LEN];
+
+        buf[0..2].copy_from_slice(&MAGIC.to_le_bytes());
+        buf[2] = VERSION;
+        buf[3] = 0;
+        buf[4..HEADER_LEN].copy_from_slice(&sequence.to_le_bytes());
+        self.encode_payload(&mut buf[HEADER_LEN..HEADER_LEN + PAYLOAD_LEN]);
+
+        let crc = crc16(&buf[..HEADER_LEN + PAYLOAD_LEN]);
+        buf[HEADER_LEN + PAYLOAD_LEN..HEADER_LEN + PAYLOAD_LEN + CRC_LEN]
+            .copy_from_slice(&crc.to_le_bytes());
+    }
+
+    /// Decode a record, `None` if it is erased, corrupt or of another version
+    pub fn deserialize(buf: &[u8; RECORD_LEN]) -> Option<(u32, Self)> {
+        let len = HEADER_LEN + PAYLOAD_LEN;
+        let crc = u16::from_le_bytes([buf[len], buf[len + 1]]);
+
+        if buf[0..2] != MAGIC.to_le_bytes() || buf[2] != VERSION || crc16(&buf[..len]) != crc {
+            return None;
+        }
+
+        let sequence = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        Config::decode_payload(&buf[HEADER_LEN..len]).map(|config| (sequence, config))
+    }
+}
+
+/// CRC-16/CCITT-FALSE
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xffff, |crc, byte| {
+        (0..8).fold(crc ^ ((*byte as u16) << 8), |crc, _| {
+            if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// Flash memory of the configuration area
+///
+/// Offsets are relative to the start of the area. Like the STM32 flash, bytes can only be
+/// written once after the page was erased.
+pub trait Flash {
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), Error>;
+    /// Write `data` with an even length to an even `offset`
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), Error>;
+    /// Set all bytes of `page` to `0xff`
+    fn erase_page(&mut self, page: usize) -> Result<(), Error>;
+}
+
+/// Wear levelling configuration store on top of a [`Flash`]
+pub struct ConfigStore<F: Flash> {
+    flash: F,
+    /// Slot and sequence number of the current record
+    current: Option<(usize, u32)>,
+}
+
+impl<F: Flash> ConfigStore<F> {
+    pub fn new(flash: F) -> Self {
+        ConfigStore {
+            flash,
+            current: None,
+        }
+    }
+
+    /// Read the current configuration. `None` if there is no valid record.
+    pub fn load(&mut self) -> Result<Option<Config>, Error> {
+        let mut latest = None;
+
+        for slot in 0..SLOTS {
+            if let Some((sequence, config)) = self.read_slot(slot)? {
+                match latest {
+                    Some((_, latest_sequence, _)) if sequence <= latest_sequence => {}
+                    _ => latest = Some((slot, sequence, config)),
+                }
+            }
+        }
+
+        self.current = latest.map(|(slot, sequence, _)| (slot, sequence));
+        Ok(latest.map(|(_, _, config)| config))
+    }
+
+    /// Read the current configuration or the defaults
+    pub fn load_or_default(&mut self) -> Config {
+        match self.load() {
+            Ok(Some(config)) => config,
+            Ok(None) => {
+                defmt::info!("No configuration stored, using defaults");
+                DEFAULT_CONFIG
+            }
+            Err(e) => {
+                defmt::warn!("Could not read configuration: {:?}", e);
+                DEFAULT_CONFIG
+            }
+        }
+    }
+
+    /// Store `config` as the new current configuration
+    pub fn save(&mut self, config: &Config) -> Result<(), Error> {
+        let slot = self.next_slot()?;
+        let sequence = self
+            .current
+            .map_or(0, |(_, sequence)| sequence.wrapping_add(1));
+
+        let mut buf = [0; RECORD_LEN];
+        config.serialize(sequence, &mut buf);
+        self.flash.write(slot * RECORD_LEN, &buf)?;
+
+        self.current = Some((slot, sequence));
+        Ok(())
+    }
+
+    pub fn flash(&mut self) -> &mut F {
+        &mut self.flash
+    }
+
+    fn read_slot(&mut self, slot: usize) -> Result<Option<(u32, Config)>, Error> {
+        let mut buf = [0; RECORD_LEN];
+        self.flash.read(slot * RECORD_LEN, &mut buf)?;
+        Ok(Config::deserialize(&buf))
+    }
+
+    fn is_erased(&mut self, slot: usize) -> Result<bool, Error> {
+        let mut buf = [0; RECORD_LEN];
+        self.flash.read(slot * RECORD_LEN, &mut buf)?;
+        Ok(buf.iter().all(|byte| *byte == 0xff))
+    }
+
+    /// Next erased slot after the current record. Erases the following page if the current one
+    /// is full, which never contains the current record.
+    fn next_slot(&mut self) -> Result<usize, Error> {
+        let start = self.current.map_or(0, |(slot, _)| (slot + 1) % SLOTS);
+        let (page, index) = (start / SLOTS_PER_PAGE, start % SLOTS_PER_PAGE);
+
+        let page = if index > 0 {
+            for slot in start..(page + 1) * SLOTS_PER_PAGE {
+                if self.is_erased(slot)? {
+                    return Ok(slot);
+                }
+            }
+
+            // Start a new page
+            (page + 1) % PAGES
+        } else {
+            page
+        };
+        let first = page * SLOTS_PER_PAGE;
+
+        for slot in first..first + SLOTS_PER_PAGE {
+            if !self.is_erased(slot)? {
+                self.flash.erase_page(page)?;
+                break;
+            }
+        }
+
+        Ok(first)
+    }
+}
+
+/// In-memory [`Flash`] that checks the restrictions of the STM32 flash
+pub struct MockFlash {
+    data: [u8; PAGES * PAGE_SIZE],
+    erase_counts: [u32; PAGES],
+}
+
+impl MockFlash {
+    pub fn new() -> Self {
+        MockFlash {
+            data: [0xff; PAGES * PAGE_SIZE],
+            erase_counts: [0; PAGES],
+        }
+    }
+
+    /// Number of erase cycles of every page
+    pub fn erase_counts(&self) -> &[u32; PAGES] {
+        &self.erase_counts
+    }
+
+    /// Raw contents, e.g. to corrupt a record
+    pub fn data(&mut self) -> &mut [u8; PAGES * PAGE_SIZE] {
+        &mut self.data
+    }
+}
+
+impl Default for MockFlash {
+    fn default() -> Self {
+        MockFlash::new()
+    }
+}
+
+impl Flash for MockFlash {
+    fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), Error> {
+        let data = self
+            .data
+            .get(offset..offset + buf.len())
+            .ok_or(Error::Flash)?;
+        buf.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), Error> {
+        // Half-word writes only
+        if (offset | data.len()) & 1 != 0 {
+            return Err(Error::Flash);
+        }
+
+        let target = self
+            .data
+            .get_mut(offset..offset + data.len())
+            .ok_or(Error::Flash)?;
+        if target.iter().any(|byte| *byte != 0xff) {
+            return Err(Error::Flash);
+        }
+
+        target.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn erase_page(&mut self, page: usize) -> Result<(), Error> {
+        if page >= PAGES {
+            return Err(Error::Flash);
+        }
+
+        self.data[page * PAGE_SIZE..(page + 1) * PAGE_SIZE].fill(0xff);
+        self.erase_counts[page] += 1;
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "none")]
+mod hardware {
+    use super::{Flash, PAGES, PAGE_SIZE};
+    use crate::error::Error;
+    use stm32f1xx_hal::flash::{FlashSize, Parts, SectorSize};
+
+    /// Size of the flash of the STM32F103C8
+    const FLASH_SIZE: usize = 64 * 1024;
+    /// Offset of the configuration area from the start of the flash
+    const AREA_OFFSET: usize = FLASH_SIZE - PAGES * PAGE_SIZE;
+
+    /// Configuration area in the last pages of the internal flash, see `memory.x`
+    pub struct Stm32Flash {
+        flash: Parts,
+    }
+
+    impl Stm32Flash {
+        pub fn new(flash: Parts) -> Self {
+            Stm32Flash { flash }
+        }
+    }
+
+    impl Flash for Stm32Flash {
+        fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), Error> {
+            let writer = self.flash.writer(SectorSize::Sz1K, FlashSize::Sz64K);
+            let data = writer
+                .read((AREA_OFFSET + offset) as u32, buf.len())
+                .map_err(|_| Error::Flash)?;
+            buf.copy_from_slice(data);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), Error> {
+            let mut writer = self.flash.writer(SectorSize::Sz1K, FlashSize::Sz64K);
+            writer
+                .write((AREA_OFFSET + offset) as u32, data)
+                .map_err(|_| Error::Flash)
+        }
+
+        fn erase_page(&mut self, page: usize) -> Result<(), Error> {
+            let mut writer = self.flash.writer(SectorSize::Sz1K, FlashSize::Sz64K);
+            writer
+                .erase((AREA_OFFSET + page * PAGE_SIZE) as u32, PAGE_SIZE)
+                .map_err(|_| Error::Flash)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        let mut profiles = DEFAULT_CONFIG.profiles;
+        profiles[2].target_distance = 1_000;
+
+        Config {
+            profiles,
+            profile: 2,
+            role: Some(Role::Convoy(3)),
+            address: Some(0x4242),
+            pairing: Some(Pairing::derive(0x1234, 0xabcd)),
+            led_count: 8,
+            leds_reversed: true,
+            ranging_mode: RangingMode::DoubleSided,
+            sync_version: Version {
+                counter: 7,
+                origin: 0xabcd,
+            },
+            led_patterns: RangePatterns {
+                ok: Pattern::parse("-ggg-").unwrap(),
+                ..DEFAULT_PATTERNS
+            },
+            ..DEFAULT_CONFIG
+        }
+    }
+
+    #[test]
+    fn record_roundtrip() {
+        for config in [DEFAULT_CONFIG, config()].iter() {
+            let mut buf = [0; RECORD_LEN];
+            config.serialize(7, &mut buf);
+            assert_eq!(Config::deserialize(&buf), Some((7, *config)));
+        }
+    }
+
+    #[test]
+    fn profiles_are_switched() {
+        let mut config = DEFAULT_CONFIG;
+        assert_eq!(config.get_profile(), DEFAULT_CONFIG.profiles[0]);
+
+        config.next_profile();
+        assert_eq!(config.get_profile(), DEFAULT_CONFIG.profiles[1]);
+        config.next_profile();
+        config.next_profile();
+        assert_eq!(config.profile, 0);
+
+        assert!(config.select_profile(2));
+        assert!(!config.select_profile(PROFILES as u8));
+        assert_eq!(config.profile, 2);
+    }
+
+    #[test]
+    fn corrupt_records_are_rejected() {
+        let mut buf = [0; RECORD_LEN];
+        config().serialize(0, &mut buf);
+
+        let mut corrupt = buf;
+        corrupt[HEADER_LEN] ^= 1;
+        assert_eq!(Config::deserialize(&corrupt), None);
+
+        let mut other_version = buf;
+        other_version[2] = VERSION + 1;
+        assert_eq!(Config::deserialize(&other_version), None);
+
+        assert_eq!(Config::deserialize(&[0xff; RECORD_LEN]), None);
+    }
+
+    #[test]
+    fn invalid_profiles_are_rejected() {
+        assert!(DEFAULT_CONFIG.profiles.iter().all(Profile::is_valid));
+
+        let profile = |target_distance, tolerance| Profile {
+            target_distance,
+            tolerance,
+        };
+        assert!(profile(100, 49).is_valid());
+        assert!(!profile(100, 50).is_valid());
+        assert!(!profile(0, 0).is_valid());
+        assert!(!profile(u16::MAX, u16::MAX).is_valid());
+
+        // A stored record with an invalid profile falls back to the defaults
+        let mut config = config();
+        config.profiles[1].tolerance = 150;
+        let mut buf = [0; RECORD_LEN];
+        config.serialize(0, &mut buf);
+        assert_eq!(Config::deserialize(&buf), None);
+    }
+
+    #[test]
+    fn empty_store_uses_defaults() {
+        let mut store = ConfigStore::new(MockFlash::new());
+
+        assert_eq!(store.load(), Ok(None));
+        assert_eq!(store.load_or_default(), DEFAULT_CONFIG);
+    }
+
+    #[test]
+    fn latest_record_is_loaded() {
+        let mut store = ConfigStore::new(MockFlash::new());
+        store.load().unwrap();
+        store.save(&DEFAULT_CONFIG).unwrap();
+        store.save(&config()).unwrap();
+
+        // Like after a reset
+        let mut store = ConfigStore::new(store.flash);
+        assert_eq!(store.load(), Ok(Some(config())));
+    }
+
+    #[test]
+    fn writes_are_spread_over_all_pages() {
+        let mut store = ConfigStore::new(MockFlash::new());
+        store.load().unwrap();
+
+        for i in 0..10 * SLOTS as u16 {
+            let mut config = DEFAULT_CONFIG;
+            config.get_profile_mut().target_distance = 100 + i;
+            store.save(&config).unwrap();
+
+            let mut reloaded = ConfigStore::new(MockFlash::new());
+            reloaded.flash.data = store.flash.data;
+            assert_eq!(reloaded.load(), Ok(Some(config)));
+        }
+
+        // Both pages are erased once per round, except in the first round on a blank flash
+        assert_eq!(store.flash().erase_counts(), &[9, 9]);
+    }
+
+    #[test]
+    fn interrupted_write_keeps_previous_record() {
+        let mut store = ConfigStore::new(MockFlash::new());
+        store.load().unwrap();
+        store.save(&DEFAULT_CONFIG).unwrap();
+        store.save(&config()).unwrap();
+
+        // Power loss while writing the second record
+        store.flash().data()[RECORD_LEN + HEADER_LEN] ^= 0xff;
+
+        let mut store = ConfigStore::new(store.flash);
+        assert_eq!(store.load(), Ok(Some(DEFAULT_CONFIG)));
+
+        // The corrupt slot is skipped
+        store.save(&config()).unwrap();
+        let mut store = ConfigStore::new(store.flash);
+        assert_eq!(store.load(), Ok(Some(config())));
+    }
+}
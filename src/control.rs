@@ -0,0 +1,246 @@
+use crate::dw1000::Dw1000State;
+use defmt::Format;
+
+/// Radio command issued by a control loop
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum RadioCommand {
+    StartReceiving,
+    FinishReceiving,
+}
+
+/// Result of one anchor control cycle
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct AnchorStep {
+    pub finish_receiving: bool,
+    pub send_ping: bool,
+}
+
+/// Scheduling of the anchor: stop receiving every cycle and send a ping every sixth cycle
+pub struct AnchorControl {
+    count: u8,
+}
+
+impl AnchorControl {
+    pub const fn new() -> Self {
+        AnchorControl { count: 0 }
+    }
+
+    pub fn step(&mut self, receiving: bool) -> AnchorStep {
+        let send_ping = if self.count == 5 {
+            self.count = 0;
+            true
+        } else {
+            self.count += 1;
+            false
+        };
+
+        AnchorStep {
+            finish_receiving: receiving,
+            send_ping,
+        }
+    }
+}
+
+impl Default for AnchorControl {
+    fn default() -> Self {
+        AnchorControl::new()
+    }
+}
+
+/// Result of one tag control cycle
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct TagStep {
+    pub command: Option<RadioCommand>,
+    pub set_out_of_range: bool,
+    /// The next cycle should use the fast period
+    pub anchor_detected: bool,
+}
+
+/// Scheduling of the tag: open a receive window synchronized to the pings of the anchor and
+/// detect a lost anchor
+pub struct TagControl {
+    count: u8,
+    cycles_since_ping: u8,
+    cycles_since_valid_response: u8,
+    anchor_detected: bool,
+}
+
+impl TagControl {
+    pub const fn new() -> Self {
+        TagControl {
+            count: 0,
+            cycles_since_ping: 255,
+            cycles_since_valid_response: 255,
+            anchor_detected: false,
+        }
+    }
+
+    pub fn step(&mut self, ping_seen: bool, valid_response_seen: bool) -> TagStep {
+        let mut set_out_of_range = false;
+
+        // Anchor detection
+        if ping_seen {
+            self.anchor_detected = true;
+            self.cycles_since_ping = 0;
+        } else {
+            self.cycles_since_ping = self.cycles_since_ping.saturating_add(1);
+        }
+
+        if self.cycles_since_ping > 50 && self.anchor_detected {
+            set_out_of_range = true;
+            self.anchor_detected = false;
+        }
+
+        if self.count == 10 {
+            self.count = 0;
+        } else if ping_seen {
+            self.count = 1;
+        } else {
+            self.count += 1;
+        }
+
+        // Valid response detection
+        if valid_response_seen {
+            self.cycles_since_valid_response = 0;
+        } else {
+            self.cycles_since_valid_response = self.cycles_since_valid_response.saturating_add(1);
+        }
+
+        if self.cycles_since_valid_response > 100 {
+            set_out_of_range = true;
+        }
+
+        let finish_count = if self.anchor_detected { 3 } else { 4 };
+
+        let command = match self.count {
+            c if c == finish_count => Some(RadioCommand::FinishReceiving),
+            0 => Some(RadioCommand::StartReceiving),
+            _ => None,
+        };
+
+        TagStep {
+            command,
+            set_out_of_range,
+            anchor_detected: self.anchor_detected,
+        }
+    }
+}
+
+impl Default for TagControl {
+    fn default() -> Self {
+        TagControl::new()
+    }
+}
+
+/// Duration of one convoy slot in ms, long enough for a complete ranging exchange
+pub const CONVOY_SLOT: u32 = 100;
+/// Number of slots per frame. Nodes that are this many positions apart use the same slot.
+pub const CONVOY_SLOTS: u32 = 6;
+/// Short address of the node at position 0 in a convoy
+pub const CONVOY_ADDRESS_BASE: u16 = 0x3000;
+
+/// Short address of the node at `position` in a convoy
+pub const fn convoy_address(position: u8) -> u16 {
+    CONVOY_ADDRESS_BASE + position as u16
+}
+
+/// Result of one convoy control cycle
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct ConvoyStep {
+    pub command: Option<RadioCommand>,
+    pub send_ping: bool,
+}
+
+/// TDMA scheduling of a node in a convoy
+///
+/// Every node acts as tag towards its predecessor and as anchor towards its successor. The node at
+/// `position` pings in slot `position % CONVOY_SLOTS`, i.e. one slot after the ping of its
+/// predecessor. The head of the convoy and nodes that lost their predecessor keep the frame
+/// period on their own. Between pings the node is always receiving.
+pub struct ConvoyControl {
+    next_ping_ms: u32,
+}
+
+impl ConvoyControl {
+    pub const fn new(position: u8) -> Self {
+        ConvoyControl {
+            next_ping_ms: (position as u32 % CONVOY_SLOTS) * CONVOY_SLOT,
+        }
+    }
+
+    /// Synchronize to a ping of the predecessor received at `now_ms`
+    pub fn predecessor_ping(&mut self, now_ms: u32) {
+        self.next_ping_ms = now_ms.wrapping_add(CONVOY_SLOT);
+    }
+
+    pub fn step(&mut self, now_ms: u32, state: Dw1000State) -> ConvoyStep {
+        let send_ping = now_ms.wrapping_sub(self.next_ping_ms) as i32 >= 0;
+
+        if send_ping {
+            self.next_ping_ms = self.next_ping_ms.wrapping_add(CONVOY_SLOTS * CONVOY_SLOT);
+
+            // Skip frames that were missed completely
+            if now_ms.wrapping_sub(self.next_ping_ms) as i32 >= 0 {
+                self.next_ping_ms = now_ms.wrapping_add(CONVOY_SLOTS * CONVOY_SLOT);
+            }
+        }
+
+        let command = match state {
+            Dw1000State::Receiving if send_ping => Some(RadioCommand::FinishReceiving),
+            Dw1000State::Ready if !send_ping => Some(RadioCommand::StartReceiving),
+            _ => None,
+        };
+
+        ConvoyStep { command, send_ping }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pings(control: &mut ConvoyControl, from_ms: u32, to_ms: u32) -> Vec<u32> {
+        (from_ms..to_ms)
+            .step_by(10)
+            .filter(|now_ms| control.step(*now_ms, Dw1000State::Receiving).send_ping)
+            .collect()
+    }
+
+    #[test]
+    fn convoy_head_pings_every_frame() {
+        let mut control = ConvoyControl::new(0);
+
+        assert_eq!(pings(&mut control, 0, 1_300), vec![0, 600, 1_200]);
+    }
+
+    #[test]
+    fn convoy_node_pings_after_predecessor() {
+        let mut control = ConvoyControl::new(2);
+        assert_eq!(pings(&mut control, 0, 300), vec![200]);
+
+        control.predecessor_ping(355);
+        assert_eq!(pings(&mut control, 300, 1_100), vec![460, 1_060]);
+
+        // Predecessor lost, keep the frame period
+        assert_eq!(pings(&mut control, 1_100, 2_300), vec![1_660, 2_260]);
+    }
+
+    #[test]
+    fn convoy_radio_commands() {
+        let mut control = ConvoyControl::new(0);
+
+        assert_eq!(
+            control.step(0, Dw1000State::Receiving),
+            ConvoyStep {
+                command: Some(RadioCommand::FinishReceiving),
+                send_ping: true
+            }
+        );
+        assert_eq!(control.step(10, Dw1000State::Sending).command, None);
+        assert_eq!(
+            control.step(20, Dw1000State::Ready).command,
+            Some(RadioCommand::StartReceiving)
+        );
+        assert_eq!(control.step(30, Dw1000State::Receiving).command, None);
+    }
+}
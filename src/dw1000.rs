@@ -1,11 +1,18 @@
 use crate::error::Error;
-use crate::helper::get_delay;
-use crate::types::{DwCsType, DwIrqType, DwSpiType, DwTypeReady, DwTypeReceiving, DwTypeSending};
+use crate::filter::{FilterKind, DEFAULT_FILTER};
+use crate::indicator::DistanceRange;
+use crate::pairing::{Pairing, PairingMessage};
+use crate::peers::{Peer, PeerTable};
+use crate::radio::{RangingFrame, RangingRadio};
+use crate::report::{DistanceReport, ReportMessage};
+use crate::role::ReplySlot;
+use crate::sync::SyncMessage;
+use crate::twr::{RangingMode, RequestTimestamps};
 use defmt::Format;
-use dw1000::ranging::Message;
-use dw1000::{mac, ranging, RxConfig};
-use embedded_hal::blocking::delay::DelayUs;
-use stm32f1xx_hal::gpio::ExtiPin;
+use dw1000::mac;
+
+#[cfg(target_os = "none")]
+pub use self::hardware::{Dw1000Ping, Dw1000Radio, Dw1000Request};
 
 #[derive(Debug, Clone, Copy, PartialEq, Format)]
 pub enum Dw1000State {
@@ -16,179 +23,806 @@ pub enum Dw1000State {
 
 #[derive(Debug, Clone, Copy, PartialEq, Format)]
 pub enum Dw1000MessageType {
-    Ping,
-    RangingRequest,
+    /// Whether the ping was answered, see [`PingFilter`]
+    Ping(bool),
+    /// Whether the distance to the tag could be measured
+    RangingRequest(bool),
     RangingResponse(bool),
+    Pairing(PairingMessage),
+    /// Frame of the configuration sync
+    Sync(SyncMessage),
+    /// Whether the distance report of a tag was used, `false` for an answer to our report
+    Report(bool),
+    /// Frame of a node we are not paired with
+    Unpaired,
     Unknown,
 }
 
-pub struct Dw1000Wrapper {
-    dw1000_ready: Option<DwTypeReady>,
-    dw1000_sending: Option<DwTypeSending>,
-    dw1000_receiving: Option<DwTypeReceiving>,
-    irq: DwIrqType,
-    distance_history: [u64; 10],
+/// Pings that are answered with a ranging request
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum PingFilter {
+    Any,
+    /// Only pings of the node with this short address, e.g. the predecessor in a convoy
+    Only(u16),
+    Ignore,
+}
+
+pub struct Dw1000Wrapper<R: RangingRadio> {
+    radio: R,
+    peers: PeerTable,
+    ping_filter: PingFilter,
+    reply_slot: ReplySlot,
+    pairing: Option<Pairing>,
+    /// Address of the peer with the most recent distance
+    last_peer: Option<u16>,
+    rejected_count: u32,
+    lost_reports: u32,
+    /// Sequence number of the next distance report
+    report_sequence: u8,
+    /// State of charge of our battery in %, sent with the distance reports
+    battery: Option<u8>,
 }
 
-impl Dw1000Wrapper {
-    pub fn new(dw1000: DwTypeReady, irq: DwIrqType) -> Self {
+impl<R: RangingRadio> Dw1000Wrapper<R> {
+    pub fn new(radio: R) -> Self {
         Dw1000Wrapper {
-            dw1000_ready: Some(dw1000),
-            dw1000_sending: None,
-            dw1000_receiving: None,
-            irq,
-            distance_history: [0; 10],
+            radio,
+            peers: PeerTable::new(DEFAULT_FILTER),
+            ping_filter: PingFilter::Any,
+            reply_slot: ReplySlot::fixed(0),
+            pairing: None,
+            last_peer: None,
+            rejected_count: 0,
+            lost_reports: 0,
+            report_sequence: 0,
+            battery: None,
         }
     }
 
-    pub fn start_receiving(&mut self) -> Result<(), Error> {
-        if let Some(dw1000) = self.dw1000_ready.take() {
-            defmt::debug!("Start receiving");
+    pub fn set_filter(&mut self, kind: FilterKind) {
+        self.peers.set_filter(kind);
+    }
 
-            let receiving = dw1000
-                .receive(RxConfig::default())
-                .expect("Could not start receiving"); // TODO: Find a way to do proper error handling
-            self.dw1000_receiving = Some(receiving);
-            Ok(())
-        } else if self.dw1000_receiving.is_some() {
-            self.finish_receiving()?;
-            self.start_receiving()
-        } else {
-            Err(Error::InvalidState)
-        }
+    pub fn set_ranging_mode(&mut self, mode: RangingMode) {
+        self.radio.set_ranging_mode(mode);
     }
 
-    pub fn get_state(&self) -> Dw1000State {
-        let state = (
-            self.dw1000_ready.is_some(),
-            self.dw1000_receiving.is_some(),
-            self.dw1000_sending.is_some(),
-        );
+    /// Answer pings in reply slot `reply_slot` so that several tags of one anchor don't collide
+    pub fn set_reply_slot(&mut self, reply_slot: ReplySlot) {
+        self.radio.set_reply_slot(reply_slot.get());
+        self.reply_slot = reply_slot;
+    }
 
-        match state {
-            (true, false, false) => Dw1000State::Ready,
-            (false, true, false) => Dw1000State::Receiving,
-            (false, false, true) => Dw1000State::Sending,
-            state => defmt::panic!("Invalid state: {:?}", state),
-        }
+    pub fn get_reply_slot(&self) -> u8 {
+        self.reply_slot.get()
     }
 
-    pub fn finish_receiving(&mut self) -> Result<(), Error> {
-        if let Some(dw1000) = self.dw1000_receiving.take() {
-            defmt::debug!("Finish receiving");
+    pub fn set_ping_filter(&mut self, filter: PingFilter) {
+        self.ping_filter = filter;
+    }
 
-            let ready = dw1000.finish_receiving().map_err(|(receiving, e)| {
-                self.dw1000_receiving = Some(receiving);
-                e
-            })?;
+    /// Use the private PAN ID and address of `pairing` and ignore all frames except the ones of
+    /// the partner. Only possible from [`Dw1000State::Ready`].
+    pub fn set_pairing(&mut self, pairing: Pairing) -> Result<(), Error> {
+        self.radio.set_address(pairing.pan_id, pairing.address)?;
+        self.pairing = Some(pairing);
+        self.reset_distance();
+        Ok(())
+    }
 
-            self.dw1000_ready = Some(ready);
+    /// Return to `pan_id` and `address` of an unpaired node, e.g. to pair again. Only possible
+    /// from [`Dw1000State::Ready`].
+    pub fn clear_pairing(&mut self, pan_id: u16, address: u16) -> Result<(), Error> {
+        self.radio.set_address(pan_id, address)?;
+        self.pairing = None;
+        self.reset_distance();
+        Ok(())
+    }
 
-            Ok(())
-        } else if self.dw1000_ready.is_some() {
-            Ok(())
-        } else {
-            Err(Error::InvalidState)
-        }
+    pub fn get_pairing(&self) -> Option<Pairing> {
+        self.pairing
+    }
+
+    pub fn radio(&mut self) -> &mut R {
+        &mut self.radio
+    }
+
+    pub fn start_receiving(&mut self) -> Result<(), Error> {
+        self.radio.start_receiving()
+    }
+
+    pub fn get_state(&self) -> Dw1000State {
+        self.radio.get_state()
+    }
+
+    pub fn finish_receiving(&mut self) -> Result<(), Error> {
+        self.radio.finish_receiving()
     }
 
     pub fn finish_sending(&mut self) -> Result<(), Error> {
-        if let Some(dw1000) = self.dw1000_sending.take() {
-            defmt::debug!("Finish sending");
+        self.radio.finish_sending()
+    }
 
-            let ready = dw1000.finish_sending().map_err(|(sending, e)| {
-                self.dw1000_sending = Some(sending);
-                e
-            })?;
+    /// Receive and handle a message. `timestamp_ms` is the time of reception in ms.
+    pub fn receive_message(&mut self, timestamp_ms: u32) -> Result<Dw1000MessageType, Error> {
+        let frame = self.radio.wait_for_frame()?;
+        self.handle_message(frame, timestamp_ms)
+    }
 
-            self.dw1000_ready = Some(ready);
+    pub fn handle_message(
+        &mut self,
+        frame: RangingFrame<R::Ping, R::Request>,
+        timestamp_ms: u32,
+    ) -> Result<Dw1000MessageType, Error> {
+        if self.radio.get_state() != Dw1000State::Ready {
+            return Err(Error::InvalidState);
+        }
 
-            Ok(())
-        } else {
-            Err(Error::InvalidState)
+        let source = match &frame {
+            RangingFrame::Ping(ping) => Some(self.radio.get_ping_source(ping)),
+            RangingFrame::RangingRequest(request) => Some(self.radio.get_request_source(request)),
+            RangingFrame::RangingResponse(source, _)
+            | RangingFrame::Sync(source, _)
+            | RangingFrame::Report(source, _) => Some(*source),
+            RangingFrame::Pairing(..) | RangingFrame::Unknown => None,
+        };
+
+        if let (Some(pairing), Some(source)) = (self.pairing, source) {
+            if !pairing.is_partner(source) {
+                defmt::debug!("Ignoring frame of unpaired node");
+                return Ok(Dw1000MessageType::Unpaired);
+            }
         }
-    }
 
-    pub fn receive_message(&mut self) -> Result<Dw1000MessageType, Error> {
-        let mut delay = get_delay();
+        match frame {
+            RangingFrame::Ping(ping) => {
+                let answer = match (self.ping_filter, self.radio.get_ping_source(&ping)) {
+                    (PingFilter::Any, _) => true,
+                    (PingFilter::Only(address), mac::Address::Short(_, source)) => {
+                        source.0 == address
+                    }
+                    _ => false,
+                };
 
-        if let Some(mut dw1000) = self.dw1000_receiving.take() {
-            let mut buf = [0; 128];
+                if answer {
+                    defmt::debug!("Sending ranging request...");
 
-            defmt::debug!("Receive message");
+                    self.radio.send_request(&ping)?;
 
-            delay.delay_us(1000u32);
+                    if let Some(slot) = self.reply_slot.ping_answered() {
+                        defmt::info!("No responses, moving to reply slot {:?}", slot);
+                        self.radio.set_reply_slot(slot);
+                    }
+                }
+                Ok(Dw1000MessageType::Ping(answer))
+            }
+            RangingFrame::RangingRequest(request) => {
+                defmt::debug!("Sending ranging response...");
 
-            let mut i = 0;
+                self.radio.send_response(&request)?;
 
-            let result = loop {
-                match dw1000.wait(&mut buf) {
-                    Ok(result) => break Ok(result),
-                    Err(nb::Error::WouldBlock) => {
-                        if i == 2 {
-                            break Err(nb::Error::WouldBlock);
-                        } else {
-                            defmt::warn!("receive_message retry");
+                let valid = match self.radio.get_request_timestamps(&request) {
+                    Some((mac::Address::Short(_, addr), timestamps)) => {
+                        self.add_request(addr.0, &timestamps, timestamp_ms)
+                    }
+                    _ => false,
+                };
+                Ok(Dw1000MessageType::RangingRequest(valid))
+            }
+            RangingFrame::RangingResponse(source, distance_mm) => {
+                defmt::debug!("Received ranging response");
+                self.reply_slot.response_received();
+                let mut valid = false;
 
-                            let sys_status = dw1000.ll().sys_status().read().unwrap();
-                            defmt::warn!(
-                                "ldedone: {:?}, rxdfr: {:?}, rxfcg: {:?}, rxfce: {}, ldeerr: {:?}",
-                                sys_status.ldedone(),
-                                sys_status.rxdfr(),
-                                sys_status.rxfcg(),
-                                sys_status.rxfce(),
-                                sys_status.ldeerr()
-                            );
-                            delay.delay_us(1000u32);
-                            i += 1;
+                // If this is not a PAN ID and short address, it doesn't
+                // come from a compatible node. Ignore it.
+                if let mac::Address::Short(_, addr) = source {
+                    valid = self.add_distance(addr.0, distance_mm, timestamp_ms);
+                }
+                Ok(Dw1000MessageType::RangingResponse(valid))
+            }
+            RangingFrame::Pairing(_, message) => Ok(Dw1000MessageType::Pairing(message)),
+            RangingFrame::Sync(_, message) => Ok(Dw1000MessageType::Sync(message)),
+            RangingFrame::Report(source, message) => {
+                let mut valid = false;
+
+                if let mac::Address::Short(_, addr) = source {
+                    match message {
+                        ReportMessage::Distance(report) => {
+                            valid = self.add_report(addr.0, report, timestamp_ms);
+
+                            if valid {
+                                // Bring our battery state to the tag
+                                self.radio.send_report(&ReportMessage::Answer {
+                                    battery: self.battery,
+                                })?;
+                            }
+                        }
+                        ReportMessage::Answer { battery } => {
+                            if let Some(peer) = self.peers.get_mut(addr.0) {
+                                peer.set_battery(battery);
+                            }
                         }
                     }
-                    Err(e) => break Err(e),
                 }
-            };
+                Ok(Dw1000MessageType::Report(valid))
+            }
+            RangingFrame::Unknown => {
+                defmt::warn!("Ignoring unknown message");
+                Ok(Dw1000MessageType::Unknown)
+            }
+        }
+    }
+
+    /// Measure the distance to a tag from its request with single-sided ranging
+    fn add_request(
+        &mut self,
+        address: u16,
+        timestamps: &RequestTimestamps,
+        timestamp_ms: u32,
+    ) -> bool {
+        let peer = self.peers.get_or_insert(address, timestamp_ms);
+        peer.set_answered();
+
+        match peer.drift().update(timestamps) {
+            Some(drift_ppb) => {
+                let distance_mm = timestamps.compute_distance_mm(drift_ppb);
+                self.add_distance(address, distance_mm, timestamp_ms)
+            }
+            None => {
+                defmt::debug!("No clock drift of {:04x} yet", address);
+                false
+            }
+        }
+    }
+
+    fn add_distance(&mut self, address: u16, distance_mm: Option<u64>, timestamp_ms: u32) -> bool {
+        match distance_mm {
+            Some(distance_mm) if distance_mm < 20_000 => {
+                let distance_cm = distance_mm / 10;
+                let corrected_distance = correct_distance(distance_cm);
+                defmt::debug!(
+                    "{:04x} - {} cm - uncorrected {} cm",
+                    address,
+                    corrected_distance as u32,
+                    distance_cm
+                );
 
-            let message = match result {
-                Ok(message) => message,
-                Err(e) => {
-                    self.dw1000_receiving = Some(dw1000);
-                    return Err(Error::from(e));
+                let peer = self.peers.get_or_insert(address, timestamp_ms);
+                if peer.add_distance(corrected_distance, timestamp_ms) {
+                    self.last_peer = Some(address);
+                    true
+                } else {
+                    self.rejected_count = self.rejected_count.wrapping_add(1);
+                    defmt::warn!(
+                        "Rejected outlier: {:?}cm, {:?} rejected in total",
+                        corrected_distance,
+                        self.rejected_count
+                    );
+                    false
                 }
-            };
+            }
+            Some(distance_mm) => {
+                defmt::warn!("Computed distance too large: {:?}mm", distance_mm);
+                false
+            }
+            None => {
+                defmt::warn!("Could not compute distance to {:04x}", address);
+                false
+            }
+        }
+    }
 
-            self.dw1000_receiving = Some(dw1000);
-            self.finish_receiving()?;
+    /// Use the distance report of a tag whose requests we answered
+    fn add_report(&mut self, address: u16, report: DistanceReport, timestamp_ms: u32) -> bool {
+        let peer = match self.peers.get_mut(address) {
+            Some(peer) => peer,
+            None => return false,
+        };
 
-            self.handle_message(message)
+        let lost = peer.get_lost_reports();
+        if peer.add_report(report, timestamp_ms) {
+            self.lost_reports = self
+                .lost_reports
+                .wrapping_add(peer.get_lost_reports().wrapping_sub(lost));
+            true
         } else {
-            Err(Error::InvalidState)
+            false
         }
     }
 
-    fn handle_message(&mut self, message: dw1000::hl::Message) -> Result<Dw1000MessageType, Error> {
-        if let Some(mut dw1000) = self.dw1000_ready.take() {
-            let ping = ranging::Ping::decode::<DwSpiType, DwCsType>(&message);
-            let request = ranging::Request::decode::<DwSpiType, DwCsType>(&message);
-            let response = ranging::Response::decode::<DwSpiType, DwCsType>(&message);
+    fn last_peer(&self) -> Option<&Peer> {
+        self.last_peer.and_then(|address| self.peers.get(address))
+    }
+
+    /// Filtered distance to the peer that was measured last
+    pub fn get_filtered_distance(&self) -> Option<u64> {
+        self.last_peer().and_then(Peer::get_filtered_distance)
+    }
+
+    pub fn get_last_distance(&self) -> u64 {
+        self.last_peer().map_or(0, Peer::get_last_distance)
+    }
+
+    /// Relative velocity to the peer that was measured last in cm/s, positive if the distance
+    /// increases
+    pub fn get_velocity(&self) -> Option<i32> {
+        self.last_peer().and_then(Peer::get_velocity)
+    }
+
+    /// Distance history of the peer with short address `address`
+    pub fn get_peer(&self, address: u16) -> Option<&Peer> {
+        self.peers.get(address)
+    }
+
+    pub fn get_peers(&self) -> &PeerTable {
+        &self.peers
+    }
+
+    /// Most urgent range of all peers
+    pub fn get_worst_range(&self, target_distance: u64, tolerance: u64) -> DistanceRange {
+        self.peers.get_worst_range(target_distance, tolerance)
+    }
+
+    /// Forget peers that were not measured for a while
+    pub fn remove_stale_peers(&mut self, now_ms: u32) {
+        self.peers.remove_stale(now_ms);
+    }
+
+    /// Number of distances rejected as outliers since start
+    pub fn get_rejected_count(&self) -> u32 {
+        self.rejected_count
+    }
+
+    /// Number of distance reports of all peers that were lost since start
+    pub fn get_lost_reports(&self) -> u32 {
+        self.lost_reports
+    }
+
+    /// Forget all measured distances, e.g. after the connection was lost
+    pub fn reset_distance(&mut self) {
+        self.peers.clear();
+        self.last_peer = None;
+    }
+
+    pub fn send_ping(&mut self) -> Result<(), Error> {
+        defmt::debug!("Sending ping...");
+
+        self.radio.send_ping()
+    }
+
+    pub fn send_pairing(&mut self, message: &PairingMessage) -> Result<(), Error> {
+        defmt::debug!("Sending pairing message...");
+
+        self.radio.send_pairing(message)
+    }
+
+    pub fn send_sync(&mut self, message: &SyncMessage) -> Result<(), Error> {
+        defmt::debug!("Sending sync message...");
+
+        self.radio.send_sync(message)
+    }
+
+    /// State of charge of our battery in % that is sent with the distance reports
+    pub fn set_battery(&mut self, battery: Option<u8>) {
+        self.battery = battery;
+    }
+
+    /// Lowest state of charge in % that a peer reported for its battery
+    pub fn get_partner_battery(&self) -> Option<u8> {
+        self.peers.get_lowest_battery()
+    }
+
+    /// Report the filtered distance in cm and the shown range to the node that answered our
+    /// request
+    pub fn send_report(&mut self, distance_cm: u64, range: DistanceRange) -> Result<(), Error> {
+        defmt::debug!("Sending distance report...");
+
+        let report = DistanceReport {
+            sequence: self.report_sequence,
+            distance: distance_cm.min(u16::MAX as u64) as u16,
+            range,
+            battery: self.battery,
+        };
+        self.radio.send_report(&ReportMessage::Distance(report))?;
+        self.report_sequence = self.report_sequence.wrapping_add(1);
+        Ok(())
+    }
+
+    pub fn handle_interrupt(&mut self) -> Result<(), Error> {
+        self.radio.handle_interrupt()
+    }
+
+    pub fn shutdown(&mut self) {
+        let _ = self.finish_sending();
+        let _ = self.finish_receiving();
+    }
+}
+
+/// Simple correction based on https://github.com/braun-embedded/rust-dw1000/issues/105
+///
+/// <corrected distance> = <measured distance> + <range bias>
+/// <range bias> = <base part> + <distance-dependent part>
+///
+/// <basepart> = -23 cm // for 16 MHz PRF, narrow-band channel
+///
+/// Linear Regression:
+///
+/// <measured distance> <= 1200: (30/1200)*x
+/// <measured distance> >  1200: (6/2500) *x + 27.12
+pub fn correct_distance(distance_cm: u64) -> u64 {
+    let dep_part = if distance_cm <= 1200 {
+        (30f32 / 1200f32) * distance_cm as f32
+    } else {
+        (6f32 / 2500f32) * distance_cm as f32 + 27.12f32
+    };
+    (distance_cm as f32 - 23f32 + dep_part).max(0f32) as u64
+}
+
+#[cfg(target_os = "none")]
+mod hardware {
+    use super::Dw1000State;
+    use crate::error::Error;
+    use crate::helper::get_delay;
+    use crate::pairing::PairingMessage;
+    use crate::radio::{RangingFrame, RangingRadio};
+    use crate::report::ReportMessage;
+    use crate::sync::SyncMessage;
+    use crate::twr::{
+        self, RangingMode, RequestTimes, RequestTimestamps, Timestamps, TwrMessage,
+        DEFAULT_RANGING_MODE,
+    };
+    use crate::types::{
+        DwCsType, DwIrqType, DwSpiType, DwTypeReady, DwTypeReceiving, DwTypeSending,
+    };
+    use dw1000::ranging::{self, Message, RxMessage, TxMessage};
+    use dw1000::time::{Duration, Instant};
+    use dw1000::{mac, RxConfig, TxConfig};
+    use embedded_hal::blocking::delay::DelayUs;
+    use stm32f1xx_hal::gpio::ExtiPin;
+
+    /// Received ping or poll
+    pub enum Dw1000Ping {
+        Ranging(RxMessage<ranging::Ping>),
+        Poll { source: mac::Address, rx_time: u64 },
+    }
+
+    /// Received ranging request or response to a poll
+    pub enum Dw1000Request {
+        Ranging(RxMessage<ranging::Request>),
+        Response {
+            source: mac::Address,
+            rx_time: u64,
+            poll_rx: u64,
+            response_tx: u64,
+        },
+    }
+
+    /// [`RangingRadio`] implementation for the DW1000 on SPI1
+    pub struct Dw1000Radio {
+        dw1000_ready: Option<DwTypeReady>,
+        dw1000_sending: Option<DwTypeSending>,
+        dw1000_receiving: Option<DwTypeReceiving>,
+        irq: DwIrqType,
+        ranging_mode: RangingMode,
+        /// TX time of the last poll as initiator of a double-sided exchange
+        poll_tx: Option<u64>,
+        /// RX time of the last poll and TX time of the response as responder
+        response: Option<(u64, u64)>,
+        reply_slot: u8,
+    }
+
+    impl Dw1000Radio {
+        pub fn new(dw1000: DwTypeReady, irq: DwIrqType) -> Self {
+            Dw1000Radio {
+                dw1000_ready: Some(dw1000),
+                dw1000_sending: None,
+                dw1000_receiving: None,
+                irq,
+                ranging_mode: DEFAULT_RANGING_MODE,
+                poll_tx: None,
+                response: None,
+                reply_slot: 0,
+            }
+        }
+
+        fn decode(
+            &mut self,
+            message: &dw1000::hl::Message,
+        ) -> RangingFrame<Dw1000Ping, Dw1000Request> {
+            if let Some(twr_message) = TwrMessage::decode(message.frame.payload) {
+                return self.decode_twr(twr_message, message);
+            }
+            if let Some(pairing_message) = PairingMessage::decode(message.frame.payload) {
+                return RangingFrame::Pairing(message.frame.header.source, pairing_message);
+            }
+            if let Some(sync_message) = SyncMessage::decode(message.frame.payload) {
+                return RangingFrame::Sync(message.frame.header.source, sync_message);
+            }
+            if let Some(report_message) = ReportMessage::decode(message.frame.payload) {
+                return RangingFrame::Report(message.frame.header.source, report_message);
+            }
+
+            let ping = ranging::Ping::decode::<DwSpiType, DwCsType>(message);
+            let request = ranging::Request::decode::<DwSpiType, DwCsType>(message);
+            let response = ranging::Response::decode::<DwSpiType, DwCsType>(message);
 
             if let Ok(Some(ping)) = ping {
-                defmt::debug!("Sending ranging request...");
+                RangingFrame::Ping(Dw1000Ping::Ranging(ping))
+            } else if let Ok(Some(request)) = request {
+                RangingFrame::RangingRequest(Dw1000Request::Ranging(request))
+            } else if let Ok(Some(response)) = response {
+                // Ranging response received. Compute distance.
+                let distance_mm = ranging::compute_distance_mm(&response).ok();
+                RangingFrame::RangingResponse(response.source, distance_mm)
+            } else {
+                RangingFrame::Unknown
+            }
+        }
 
-                let result = ranging::Request::new(&mut dw1000, &ping);
+        fn decode_twr(
+            &mut self,
+            twr_message: TwrMessage,
+            message: &dw1000::hl::Message,
+        ) -> RangingFrame<Dw1000Ping, Dw1000Request> {
+            let source = message.frame.header.source;
+            let rx_time = message.rx_time.value();
 
-                let sending = match result {
-                    Ok(message) => message.send(dw1000),
+            match twr_message {
+                TwrMessage::Poll => RangingFrame::Ping(Dw1000Ping::Poll { source, rx_time }),
+                TwrMessage::Response {
+                    poll_rx,
+                    response_tx,
+                } => RangingFrame::RangingRequest(Dw1000Request::Response {
+                    source,
+                    rx_time,
+                    poll_rx,
+                    response_tx,
+                }),
+                TwrMessage::Final {
+                    poll_tx,
+                    response_rx,
+                    final_tx,
+                } => {
+                    // Final received. Compute distance if we answered the poll.
+                    let distance_mm = self.response.take().and_then(|(poll_rx, response_tx)| {
+                        Timestamps {
+                            poll_tx,
+                            poll_rx,
+                            response_tx,
+                            response_rx,
+                            final_tx,
+                            final_rx: rx_time,
+                        }
+                        .compute_distance_mm()
+                    });
+                    RangingFrame::RangingResponse(source, distance_mm)
+                }
+            }
+        }
+
+        /// Move a request into the reply slot of this node
+        fn delay_request(&self, message: &mut TxMessage<ranging::Request>) -> Result<(), Error> {
+            let times = RequestTimes {
+                tx_time: message.tx_time.value(),
+                request_tx: message.payload.request_tx_time.value(),
+                ping_reply: message.payload.ping_reply_time.value(),
+            }
+            .delay(self.reply_slot);
+
+            message.tx_time = Instant::new(times.tx_time).ok_or(Error::InvalidState)?;
+            message.payload.request_tx_time =
+                Instant::new(times.request_tx).ok_or(Error::InvalidState)?;
+            message.payload.ping_reply_time =
+                Duration::new(times.ping_reply).ok_or(Error::InvalidState)?;
+
+            Ok(())
+        }
+
+        /// Send a frame of the double-sided exchange in reply slot `slot`. `message` gets the TX
+        /// timestamp of the frame.
+        fn send_twr(
+            &mut self,
+            destination: mac::Address,
+            slot: u8,
+            message: impl FnOnce(u64) -> TwrMessage,
+        ) -> Result<u64, Error> {
+            if let Some(mut dw1000) = self.dw1000_ready.take() {
+                let times = dw1000
+                    .sys_time()
+                    .and_then(|now| Ok((now, dw1000.get_tx_antenna_delay()?)));
+                let (now, antenna_delay) = match times {
+                    Ok(times) => times,
                     Err(e) => {
                         self.dw1000_ready = Some(dw1000);
-                        Err(e)
+                        return Err(Error::from(e));
                     }
-                }?;
+                };
+
+                let delayed_tx_time = twr::delayed_tx_time(now.value(), slot);
+                let tx_time = twr::tx_timestamp(delayed_tx_time, antenna_delay.value());
+
+                let delayed_tx_time = match Instant::new(delayed_tx_time) {
+                    Some(delayed_tx_time) => delayed_tx_time,
+                    None => {
+                        self.dw1000_ready = Some(dw1000);
+                        return Err(Error::InvalidState);
+                    }
+                };
+
+                let mut buf = [0; TwrMessage::MAX_LEN];
+                let len = message(tx_time).encode(&mut buf);
+
+                let sending = dw1000.send(
+                    &buf[..len],
+                    destination,
+                    Some(delayed_tx_time),
+                    TxConfig::default(),
+                )?;
 
                 self.dw1000_sending = Some(sending);
-                Ok(Dw1000MessageType::Ping)
-            } else if let Ok(Some(request)) = request {
-                defmt::debug!("Sending ranging response...");
 
-                let result = ranging::Response::new(&mut dw1000, &request);
+                Ok(tx_time)
+            } else {
+                Err(Error::InvalidState)
+            }
+        }
+
+        /// Send `payload` right away to all nodes, e.g. for frames that are not time critical
+        fn broadcast(&mut self, payload: &[u8]) -> Result<(), Error> {
+            if let Some(dw1000) = self.dw1000_ready.take() {
+                let sending = dw1000.send(
+                    payload,
+                    mac::Address::broadcast(&mac::AddressMode::Short),
+                    None,
+                    TxConfig::default(),
+                )?;
+
+                self.dw1000_sending = Some(sending);
+
+                Ok(())
+            } else {
+                Err(Error::InvalidState)
+            }
+        }
+    }
+
+    impl RangingRadio for Dw1000Radio {
+        type Ping = Dw1000Ping;
+        type Request = Dw1000Request;
+
+        fn get_state(&self) -> Dw1000State {
+            let state = (
+                self.dw1000_ready.is_some(),
+                self.dw1000_receiving.is_some(),
+                self.dw1000_sending.is_some(),
+            );
+
+            match state {
+                (true, false, false) => Dw1000State::Ready,
+                (false, true, false) => Dw1000State::Receiving,
+                (false, false, true) => Dw1000State::Sending,
+                state => defmt::panic!("Invalid state: {:?}", state),
+            }
+        }
+
+        fn start_receiving(&mut self) -> Result<(), Error> {
+            if let Some(dw1000) = self.dw1000_ready.take() {
+                defmt::debug!("Start receiving");
+
+                let receiving = dw1000
+                    .receive(RxConfig::default())
+                    .expect("Could not start receiving"); // TODO: Find a way to do proper error handling
+                self.dw1000_receiving = Some(receiving);
+                Ok(())
+            } else if self.dw1000_receiving.is_some() {
+                self.finish_receiving()?;
+                self.start_receiving()
+            } else {
+                Err(Error::InvalidState)
+            }
+        }
+
+        fn finish_receiving(&mut self) -> Result<(), Error> {
+            if let Some(dw1000) = self.dw1000_receiving.take() {
+                defmt::debug!("Finish receiving");
+
+                let ready = dw1000.finish_receiving().map_err(|(receiving, e)| {
+                    self.dw1000_receiving = Some(receiving);
+                    e
+                })?;
+
+                self.dw1000_ready = Some(ready);
+
+                Ok(())
+            } else if self.dw1000_ready.is_some() {
+                Ok(())
+            } else {
+                Err(Error::InvalidState)
+            }
+        }
+
+        fn finish_sending(&mut self) -> Result<(), Error> {
+            if let Some(dw1000) = self.dw1000_sending.take() {
+                defmt::debug!("Finish sending");
+
+                let ready = dw1000.finish_sending().map_err(|(sending, e)| {
+                    self.dw1000_sending = Some(sending);
+                    e
+                })?;
+
+                self.dw1000_ready = Some(ready);
+
+                Ok(())
+            } else {
+                Err(Error::InvalidState)
+            }
+        }
+
+        fn wait_for_frame(&mut self) -> Result<RangingFrame<Self::Ping, Self::Request>, Error> {
+            let mut delay = get_delay();
+
+            if let Some(mut dw1000) = self.dw1000_receiving.take() {
+                let mut buf = [0; 128];
+
+                defmt::debug!("Receive message");
+
+                delay.delay_us(1000u32);
+
+                let mut i = 0;
+
+                let result = loop {
+                    match dw1000.wait(&mut buf) {
+                        Ok(result) => break Ok(result),
+                        Err(nb::Error::WouldBlock) => {
+                            if i == 2 {
+                                break Err(nb::Error::WouldBlock);
+                            } else {
+                                defmt::warn!("receive_message retry");
+
+                                let sys_status = dw1000.ll().sys_status().read().unwrap();
+                                defmt::warn!(
+                                    "ldedone: {:?}, rxdfr: {:?}, rxfcg: {:?}, rxfce: {}, ldeerr: {:?}",
+                                    sys_status.ldedone(),
+                                    sys_status.rxdfr(),
+                                    sys_status.rxfcg(),
+                                    sys_status.rxfce(),
+                                    sys_status.ldeerr()
+                                );
+                                delay.delay_us(1000u32);
+                                i += 1;
+                            }
+                        }
+                        Err(e) => break Err(e),
+                    }
+                };
+
+                let message = match result {
+                    Ok(message) => message,
+                    Err(e) => {
+                        self.dw1000_receiving = Some(dw1000);
+                        return Err(Error::from(e));
+                    }
+                };
+
+                self.dw1000_receiving = Some(dw1000);
+                self.finish_receiving()?;
+
+                Ok(self.decode(&message))
+            } else {
+                Err(Error::InvalidState)
+            }
+        }
+
+        fn send_ping(&mut self) -> Result<(), Error> {
+            if self.ranging_mode == RangingMode::DoubleSided {
+                let broadcast = mac::Address::broadcast(&mac::AddressMode::Short);
+                self.poll_tx = Some(self.send_twr(broadcast, 0, |_| TwrMessage::Poll)?);
+                return Ok(());
+            }
+
+            if let Some(mut dw1000) = self.dw1000_ready.take() {
+                let result = ranging::Ping::new(&mut dw1000);
 
                 let sending = match result {
                     Ok(message) => message.send(dw1000),
@@ -199,121 +833,591 @@ impl Dw1000Wrapper {
                 }?;
 
                 self.dw1000_sending = Some(sending);
-                Ok(Dw1000MessageType::RangingRequest)
-            } else if let Ok(Some(response)) = response {
-                defmt::debug!("Received ranging response");
-                let mut valid = false;
 
-                // If this is not a PAN ID and short address, it doesn't
-                // come from a compatible node. Ignore it.
-                if let mac::Address::Short(pan_id, addr) = response.source {
-                    // Ranging response received. Compute distance.
-                    let distance_mm = ranging::compute_distance_mm(&response);
-
-                    match distance_mm {
-                        Ok(distance_mm) if distance_mm < 20_000 => {
-                            let distance_cm = distance_mm / 10;
-                            let corrected_distance = Dw1000Wrapper::correct_distance(distance_cm);
-                            defmt::debug!(
-                                "{:04x}:{:04x} - {} cm - uncorrected {} cm",
-                                pan_id.0,
-                                addr.0,
-                                corrected_distance as u32,
-                                distance_cm
-                            );
-                            self.update_distance(corrected_distance);
-                            valid = true;
-                        }
-                        Ok(distance_mm) => {
-                            defmt::warn!("Computed distance too large: {:?}mm", distance_mm)
+                Ok(())
+            } else {
+                Err(Error::InvalidState)
+            }
+        }
+
+        fn send_request(&mut self, ping: &Self::Ping) -> Result<(), Error> {
+            let ping = match ping {
+                Dw1000Ping::Ranging(ping) => ping,
+                Dw1000Ping::Poll { source, rx_time } => {
+                    let poll_rx = *rx_time;
+                    let response_tx = self.send_twr(*source, self.reply_slot, |response_tx| {
+                        TwrMessage::Response {
+                            poll_rx,
+                            response_tx,
                         }
-                        Err(_) => {
-                            defmt::warn!(
-                                "Could not compute distance from {:04x}:{:04x}",
-                                pan_id.0,
-                                addr.0
-                            );
+                    })?;
+                    self.response = Some((poll_rx, response_tx));
+                    return Ok(());
+                }
+            };
+
+            if let Some(mut dw1000) = self.dw1000_ready.take() {
+                let result = ranging::Request::new(&mut dw1000, ping);
+
+                let sending = match result {
+                    Ok(mut message) => {
+                        if let Err(e) = self.delay_request(&mut message) {
+                            self.dw1000_ready = Some(dw1000);
+                            return Err(e);
                         }
+                        message.send(dw1000)
                     }
+                    Err(e) => {
+                        self.dw1000_ready = Some(dw1000);
+                        Err(e)
+                    }
+                }?;
+
+                self.dw1000_sending = Some(sending);
+
+                Ok(())
+            } else {
+                Err(Error::InvalidState)
+            }
+        }
+
+        fn send_response(&mut self, request: &Self::Request) -> Result<(), Error> {
+            let request = match request {
+                Dw1000Request::Ranging(request) => request,
+                Dw1000Request::Response {
+                    source, rx_time, ..
+                } => {
+                    let poll_tx = self.poll_tx.ok_or(Error::InvalidState)?;
+                    let response_rx = *rx_time;
+                    self.send_twr(*source, 0, |final_tx| TwrMessage::Final {
+                        poll_tx,
+                        response_rx,
+                        final_tx,
+                    })?;
+                    return Ok(());
                 }
-                self.dw1000_ready = Some(dw1000);
-                Ok(Dw1000MessageType::RangingResponse(valid))
+            };
+
+            if let Some(mut dw1000) = self.dw1000_ready.take() {
+                let result = ranging::Response::new(&mut dw1000, request);
+
+                let sending = match result {
+                    Ok(message) => message.send(dw1000),
+                    Err(e) => {
+                        self.dw1000_ready = Some(dw1000);
+                        Err(e)
+                    }
+                }?;
+
+                self.dw1000_sending = Some(sending);
+
+                Ok(())
             } else {
-                defmt::warn!("Ignoring unknown message");
-                self.dw1000_ready = Some(dw1000);
-                Ok(Dw1000MessageType::Unknown)
+                Err(Error::InvalidState)
             }
-        } else {
-            Err(Error::InvalidState)
-        }
-    }
-
-    /// Simple correction based on https://github.com/braun-embedded/rust-dw1000/issues/105
-    ///
-    /// <corrected distance> = <measured distance> + <range bias>
-    /// <range bias> = <base part> + <distance-dependent part>
-    ///
-    /// <basepart> = -23 cm // for 16 MHz PRF, narrow-band channel
-    ///
-    /// Linear Regression:
-    ///
-    /// <measured distance> <= 1200: (30/1200)*x
-    /// <measured distance> >  1200: (6/2500) *x + 27.12
-    fn correct_distance(distance_cm: u64) -> u64 {
-        let dep_part = if distance_cm <= 1200 {
-            (30f32 / 1200f32) * distance_cm as f32
-        } else {
-            (6f32 / 2500f32) * distance_cm as f32 + 27.12f32
+        }
+
+        fn send_pairing(&mut self, message: &PairingMessage) -> Result<(), Error> {
+            let mut buf = [0; PairingMessage::MAX_LEN];
+            let len = message.encode(&mut buf);
+            self.broadcast(&buf[..len])
+        }
+
+        fn send_sync(&mut self, message: &SyncMessage) -> Result<(), Error> {
+            let mut buf = [0; SyncMessage::MAX_LEN];
+            let len = message.encode(&mut buf);
+            self.broadcast(&buf[..len])
+        }
+
+        fn send_report(&mut self, message: &ReportMessage) -> Result<(), Error> {
+            let mut buf = [0; ReportMessage::MAX_LEN];
+            let len = message.encode(&mut buf);
+            self.broadcast(&buf[..len])
+        }
+
+        fn get_ping_source(&self, ping: &Self::Ping) -> mac::Address {
+            match ping {
+                Dw1000Ping::Ranging(ping) => ping.source,
+                Dw1000Ping::Poll { source, .. } => *source,
+            }
+        }
+
+        fn get_request_source(&self, request: &Self::Request) -> mac::Address {
+            match request {
+                Dw1000Request::Ranging(request) => request.source,
+                Dw1000Request::Response { source, .. } => *source,
+            }
+        }
+
+        fn get_request_timestamps(
+            &self,
+            request: &Self::Request,
+        ) -> Option<(mac::Address, RequestTimestamps)> {
+            match request {
+                Dw1000Request::Ranging(request) => Some((
+                    request.source,
+                    RequestTimestamps {
+                        ping_tx: request.payload.ping_tx_time.value(),
+                        ping_reply: request.payload.ping_reply_time.value(),
+                        request_tx: request.payload.request_tx_time.value(),
+                        request_rx: request.rx_time.value(),
+                    },
+                )),
+                Dw1000Request::Response {
+                    source,
+                    rx_time,
+                    poll_rx,
+                    response_tx,
+                } => self.poll_tx.map(|poll_tx| {
+                    (
+                        *source,
+                        RequestTimestamps {
+                            ping_tx: poll_tx,
+                            ping_reply: twr::duration(*poll_rx, *response_tx),
+                            request_tx: *response_tx,
+                            request_rx: *rx_time,
+                        },
+                    )
+                }),
+            }
+        }
+
+        fn set_ranging_mode(&mut self, mode: RangingMode) {
+            self.ranging_mode = mode;
+            self.poll_tx = None;
+            self.response = None;
+        }
+
+        fn set_reply_slot(&mut self, slot: u8) {
+            self.reply_slot = slot;
+        }
+
+        fn set_address(&mut self, pan_id: u16, address: u16) -> Result<(), Error> {
+            let dw1000 = self.dw1000_ready.as_mut().ok_or(Error::InvalidState)?;
+            dw1000.set_address(mac::PanId(pan_id), mac::ShortAddress(address))?;
+            Ok(())
+        }
+
+        fn handle_interrupt(&mut self) -> Result<(), Error> {
+            if self.irq.check_interrupt() {
+                self.irq.clear_interrupt_pending_bit();
+                Ok(())
+            } else {
+                Err(Error::InvalidState)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::radio::{FakeRadio, FakeRequest, SentFrame};
+    use crate::role::Role;
+    use crate::sync::Version;
+    use crate::twr::RequestTimestamps;
+
+    const ANCHOR: mac::Address = mac::Address::Short(mac::PanId(0x0d57), mac::ShortAddress(0x1234));
+
+    fn receiving_wrapper() -> Dw1000Wrapper<FakeRadio> {
+        let mut dw1000 = Dw1000Wrapper::new(FakeRadio::new());
+        dw1000.start_receiving().unwrap();
+        dw1000
+    }
+
+    #[test]
+    fn ping_is_answered_with_request() {
+        let mut dw1000 = receiving_wrapper();
+
+        assert!(dw1000.radio().deliver(RangingFrame::Ping(ANCHOR)));
+        assert_eq!(dw1000.receive_message(0), Ok(Dw1000MessageType::Ping(true)));
+        assert_eq!(dw1000.get_state(), Dw1000State::Sending);
+        assert_eq!(
+            dw1000.radio().take_sent(),
+            Some(SentFrame::RangingRequest(ANCHOR))
+        );
+    }
+
+    #[test]
+    fn filtered_pings_are_not_answered() {
+        let mut dw1000 = receiving_wrapper();
+        dw1000.set_ping_filter(PingFilter::Only(0x1235));
+
+        dw1000.radio().deliver(RangingFrame::Ping(ANCHOR));
+        assert_eq!(
+            dw1000.receive_message(0),
+            Ok(Dw1000MessageType::Ping(false))
+        );
+        assert_eq!(dw1000.get_state(), Dw1000State::Ready);
+        assert_eq!(dw1000.radio().take_sent(), None);
+
+        dw1000.set_ping_filter(PingFilter::Only(0x1234));
+        dw1000.start_receiving().unwrap();
+        dw1000.radio().deliver(RangingFrame::Ping(ANCHOR));
+        assert_eq!(dw1000.receive_message(0), Ok(Dw1000MessageType::Ping(true)));
+    }
+
+    #[test]
+    fn reply_slot_moves_if_requests_are_lost() {
+        let mut dw1000 = receiving_wrapper();
+        dw1000.set_reply_slot(ReplySlot::new(Role::Tag, 0x2001));
+        assert_eq!(dw1000.radio().get_reply_slot(), 1);
+
+        let answer_ping = |dw1000: &mut Dw1000Wrapper<FakeRadio>| {
+            dw1000.radio().deliver(RangingFrame::Ping(ANCHOR));
+            dw1000.receive_message(0).unwrap();
+            dw1000.handle_interrupt().unwrap();
+            dw1000.finish_sending().unwrap();
+            dw1000.start_receiving().unwrap();
         };
-        (distance_cm as f32 - 23f32 + dep_part).max(0f32) as u64
+
+        // The anchor answers every other request
+        for _ in 0..10 {
+            answer_ping(&mut dw1000);
+            answer_ping(&mut dw1000);
+            dw1000
+                .radio()
+                .deliver(RangingFrame::RangingResponse(ANCHOR, Some(5_000)));
+            dw1000.receive_message(0).unwrap();
+            dw1000.start_receiving().unwrap();
+        }
+        assert_eq!(dw1000.get_reply_slot(), 1);
+
+        // Our requests collide with the ones of another tag
+        for _ in 0..10 {
+            answer_ping(&mut dw1000);
+        }
+        assert_ne!(dw1000.get_reply_slot(), 1);
+        assert_eq!(dw1000.radio().get_reply_slot(), dw1000.get_reply_slot());
     }
 
-    fn update_distance(&mut self, distance_cm: u64) {
-        self.distance_history[..].rotate_right(1);
-        self.distance_history[0] = distance_cm;
+    #[test]
+    fn request_is_answered_with_response() {
+        let mut dw1000 = receiving_wrapper();
+
+        dw1000
+            .radio()
+            .deliver(RangingFrame::RangingRequest(FakeRequest::new(ANCHOR)));
+        assert_eq!(
+            dw1000.receive_message(0),
+            Ok(Dw1000MessageType::RangingRequest(false))
+        );
+        assert_eq!(
+            dw1000.radio().take_sent(),
+            Some(SentFrame::RangingResponse(ANCHOR))
+        );
+
+        dw1000.finish_sending().unwrap();
+        assert_eq!(dw1000.get_state(), Dw1000State::Ready);
     }
 
-    pub fn get_average_distance(&self) -> u64 {
-        self.distance_history.iter().sum::<u64>() / self.distance_history.len() as u64
+    #[test]
+    fn response_updates_distance() {
+        let mut dw1000 = receiving_wrapper();
+
+        dw1000
+            .radio()
+            .deliver(RangingFrame::RangingResponse(ANCHOR, Some(5_000)));
+        assert_eq!(
+            dw1000.receive_message(0),
+            Ok(Dw1000MessageType::RangingResponse(true))
+        );
+        assert_eq!(dw1000.get_last_distance(), correct_distance(500));
+        assert_eq!(dw1000.get_filtered_distance(), Some(correct_distance(500)));
+        assert_eq!(dw1000.get_state(), Dw1000State::Ready);
+        assert_eq!(dw1000.radio().take_sent(), None);
     }
 
-    pub fn get_last_distance(&self) -> u64 {
-        self.distance_history[0]
+    #[test]
+    fn implausible_responses_are_rejected() {
+        for distance_mm in [Some(25_000), None].iter() {
+            let mut dw1000 = receiving_wrapper();
+
+            dw1000
+                .radio()
+                .deliver(RangingFrame::RangingResponse(ANCHOR, *distance_mm));
+            assert_eq!(
+                dw1000.receive_message(0),
+                Ok(Dw1000MessageType::RangingResponse(false))
+            );
+            assert_eq!(dw1000.get_filtered_distance(), None);
+        }
     }
 
-    pub fn send_ping(&mut self) -> Result<(), Error> {
-        if let Some(mut dw1000) = self.dw1000_ready.take() {
-            defmt::debug!("Sending ping...");
+    #[test]
+    fn frames_of_unpaired_nodes_are_ignored() {
+        let mut dw1000 = Dw1000Wrapper::new(FakeRadio::new());
+        let pairing = Pairing::derive(0x1234, 0xabcd);
+        dw1000.set_pairing(pairing).unwrap();
+        assert_eq!(
+            dw1000.radio().get_address(),
+            Some((pairing.pan_id, pairing.address))
+        );
+
+        let partner = mac::Address::Short(
+            mac::PanId(pairing.pan_id),
+            mac::ShortAddress(pairing.partner_address),
+        );
+        let ack = SyncMessage::Ack {
+            version: Version {
+                counter: 1,
+                origin: 0xabcd,
+            },
+        };
+        let frames = [
+            (RangingFrame::Ping(ANCHOR), Dw1000MessageType::Unpaired),
+            (
+                RangingFrame::RangingResponse(ANCHOR, Some(5_000)),
+                Dw1000MessageType::Unpaired,
+            ),
+            (
+                RangingFrame::RangingResponse(partner, Some(5_000)),
+                Dw1000MessageType::RangingResponse(true),
+            ),
+            (RangingFrame::Sync(ANCHOR, ack), Dw1000MessageType::Unpaired),
+            (
+                RangingFrame::Sync(partner, ack),
+                Dw1000MessageType::Sync(ack),
+            ),
+        ];
 
-            let result = ranging::Ping::new(&mut dw1000);
+        for (frame, message_type) in frames.iter() {
+            dw1000.start_receiving().unwrap();
+            dw1000.radio().deliver(*frame);
+            assert_eq!(dw1000.receive_message(0), Ok(*message_type));
+            assert_eq!(dw1000.radio().take_sent(), None);
+        }
 
-            let sending = match result {
-                Ok(message) => message.send(dw1000),
-                Err(e) => {
-                    self.dw1000_ready = Some(dw1000);
-                    Err(e)
-                }
-            }?;
+        dw1000.clear_pairing(0x0d57, 0x2001).unwrap();
+        assert_eq!(dw1000.get_pairing(), None);
+        assert_eq!(dw1000.radio().get_address(), Some((0x0d57, 0x2001)));
+        dw1000.start_receiving().unwrap();
+        dw1000.radio().deliver(RangingFrame::Ping(ANCHOR));
+        assert_eq!(dw1000.receive_message(0), Ok(Dw1000MessageType::Ping(true)));
+    }
 
-            self.dw1000_sending = Some(sending);
+    #[test]
+    fn distances_are_filtered() {
+        let mut dw1000 = receiving_wrapper();
+        dw1000.set_filter(FilterKind::Median);
 
-            Ok(())
-        } else {
-            Err(Error::InvalidState)
+        for (i, distance_mm) in [5_000, 5_400, 5_200].iter().enumerate() {
+            dw1000
+                .radio()
+                .deliver(RangingFrame::RangingResponse(ANCHOR, Some(*distance_mm)));
+            dw1000.receive_message(i as u32 * 300).unwrap();
+            dw1000.start_receiving().unwrap();
         }
+
+        assert_eq!(dw1000.get_last_distance(), correct_distance(520));
+        assert_eq!(dw1000.get_filtered_distance(), Some(correct_distance(520)));
+
+        dw1000.reset_distance();
+        assert_eq!(dw1000.get_filtered_distance(), None);
     }
 
-    pub fn handle_interrupt(&mut self) -> Result<(), Error> {
-        if self.irq.check_interrupt() {
-            self.irq.clear_interrupt_pending_bit();
-            Ok(())
-        } else {
-            Err(Error::InvalidState)
+    #[test]
+    fn outliers_are_rejected() {
+        let mut dw1000 = receiving_wrapper();
+        dw1000.set_filter(FilterKind::MovingAverage);
+
+        for (i, (distance_mm, valid)) in [(5_000, true), (15_000, false), (5_100, true)]
+            .iter()
+            .enumerate()
+        {
+            dw1000
+                .radio()
+                .deliver(RangingFrame::RangingResponse(ANCHOR, Some(*distance_mm)));
+            assert_eq!(
+                dw1000.receive_message(i as u32 * 300),
+                Ok(Dw1000MessageType::RangingResponse(*valid))
+            );
+            dw1000.start_receiving().unwrap();
         }
+
+        assert_eq!(dw1000.get_rejected_count(), 1);
+        // 10 cm in 600 ms
+        assert_eq!(dw1000.get_velocity(), Some(16));
+        assert_eq!(
+            dw1000.get_filtered_distance(),
+            Some((correct_distance(500) + correct_distance(510)) / 2)
+        );
     }
 
-    pub fn shutdown(&mut self) {
-        let _ = self.finish_sending();
-        let _ = self.finish_receiving();
+    /// `n`th request of a tag with a time of flight of `time_of_flight` ticks
+    fn request(address: u16, time_of_flight: u64, n: u64) -> FakeRequest {
+        // 600 ms between two requests, 10 ms reply time
+        let period = 38_338_560_000;
+        let reply = 638_976_000;
+        let ping_tx = n * period;
+
+        FakeRequest {
+            source: mac::Address::Short(mac::PanId(0x0d57), mac::ShortAddress(address)),
+            timestamps: Some(RequestTimestamps {
+                ping_tx,
+                ping_reply: reply,
+                request_tx: 1_000 + ping_tx + reply,
+                request_rx: ping_tx + 2 * time_of_flight + reply,
+            }),
+        }
+    }
+
+    #[test]
+    fn anchor_measures_several_tags() {
+        let mut anchor = receiving_wrapper();
+
+        for n in 0..3 {
+            // 2 m and 5 m
+            for (address, time_of_flight) in [(0x1235, 427), (0x1236, 1_067)].iter() {
+                anchor.radio().deliver(RangingFrame::RangingRequest(request(
+                    *address,
+                    *time_of_flight,
+                    n,
+                )));
+                assert_eq!(
+                    anchor.receive_message(n as u32 * 600),
+                    Ok(Dw1000MessageType::RangingRequest(n > 0))
+                );
+                anchor.handle_interrupt().unwrap();
+                anchor.finish_sending().unwrap();
+                anchor.start_receiving().unwrap();
+            }
+        }
+
+        let distance = |address| {
+            anchor
+                .get_peer(address)
+                .and_then(Peer::get_filtered_distance)
+        };
+        assert_eq!(distance(0x1235), Some(correct_distance(200)));
+        assert_eq!(distance(0x1236), Some(correct_distance(499)));
+        assert_eq!(anchor.get_peers().len(), 2);
+        assert_eq!(anchor.get_worst_range(200, 20), DistanceRange::Long);
+
+        anchor.remove_stale_peers(10_000);
+        assert_eq!(anchor.get_worst_range(200, 20), DistanceRange::OutOfRange);
+    }
+
+    #[test]
+    fn distance_is_reported_to_anchor() {
+        let mut tag = receiving_wrapper();
+        tag.set_battery(Some(15));
+        tag.radio()
+            .deliver(RangingFrame::RangingResponse(ANCHOR, Some(2_000)));
+        tag.receive_message(0).unwrap();
+        tag.send_report(tag.get_filtered_distance().unwrap(), DistanceRange::Short)
+            .unwrap();
+
+        let report = match tag.radio().take_sent() {
+            Some(SentFrame::Report(ReportMessage::Distance(report))) => report,
+            sent => panic!("Unexpected frame {:?}", sent),
+        };
+        assert_eq!(report.distance as u64, correct_distance(200));
+        assert_eq!(report.battery, Some(15));
+
+        let mut anchor = receiving_wrapper();
+        anchor.set_battery(Some(70));
+        let tag_address = mac::Address::Short(mac::PanId(0x0d57), mac::ShortAddress(0x1235));
+
+        // Reports of nodes whose requests we didn't answer are ignored
+        let message = ReportMessage::Distance(report);
+        anchor
+            .radio()
+            .deliver(RangingFrame::Report(tag_address, message));
+        assert_eq!(
+            anchor.receive_message(0),
+            Ok(Dw1000MessageType::Report(false))
+        );
+
+        anchor.start_receiving().unwrap();
+        anchor
+            .radio()
+            .deliver(RangingFrame::RangingRequest(request(0x1235, 427, 0)));
+        anchor.receive_message(0).unwrap();
+        anchor.handle_interrupt().unwrap();
+        anchor.finish_sending().unwrap();
+
+        anchor.start_receiving().unwrap();
+        anchor
+            .radio()
+            .deliver(RangingFrame::Report(tag_address, message));
+        assert_eq!(
+            anchor.receive_message(10),
+            Ok(Dw1000MessageType::Report(true))
+        );
+        assert_eq!(anchor.get_worst_range(200, 20), DistanceRange::Short);
+        assert_eq!(anchor.get_peer(0x1235).unwrap().get_report(), Some(&report));
+        assert_eq!(anchor.get_partner_battery(), Some(15));
+
+        // The anchor answers with its battery state
+        let answer = ReportMessage::Answer { battery: Some(70) };
+        assert_eq!(anchor.radio().take_sent(), Some(SentFrame::Report(answer)));
+
+        tag.handle_interrupt().unwrap();
+        tag.finish_sending().unwrap();
+        tag.start_receiving().unwrap();
+        tag.radio().deliver(RangingFrame::Report(ANCHOR, answer));
+        assert_eq!(
+            tag.receive_message(20),
+            Ok(Dw1000MessageType::Report(false))
+        );
+        assert_eq!(tag.get_partner_battery(), Some(70));
+        assert_eq!(tag.radio().take_sent(), None);
+    }
+
+    #[test]
+    fn full_exchange() {
+        let mut anchor = Dw1000Wrapper::new(FakeRadio::new());
+        let mut tag = receiving_wrapper();
+        let tag_address = mac::Address::Short(mac::PanId(0x0d57), mac::ShortAddress(0x1235));
+
+        anchor.send_ping().unwrap();
+        assert_eq!(anchor.radio().take_sent(), Some(SentFrame::Ping));
+        anchor.handle_interrupt().unwrap();
+        anchor.finish_sending().unwrap();
+        anchor.start_receiving().unwrap();
+
+        tag.radio().deliver(RangingFrame::Ping(ANCHOR));
+        tag.handle_interrupt().unwrap();
+        assert_eq!(tag.receive_message(0), Ok(Dw1000MessageType::Ping(true)));
+        tag.handle_interrupt().unwrap();
+        tag.finish_sending().unwrap();
+        tag.start_receiving().unwrap();
+
+        anchor
+            .radio()
+            .deliver(RangingFrame::RangingRequest(FakeRequest::new(tag_address)));
+        assert_eq!(
+            anchor.receive_message(0),
+            Ok(Dw1000MessageType::RangingRequest(false))
+        );
+        assert_eq!(
+            anchor.radio().take_sent(),
+            Some(SentFrame::RangingResponse(tag_address))
+        );
+
+        tag.radio()
+            .deliver(RangingFrame::RangingResponse(ANCHOR, Some(2_000)));
+        assert_eq!(
+            tag.receive_message(0),
+            Ok(Dw1000MessageType::RangingResponse(true))
+        );
+        assert_eq!(tag.get_last_distance(), correct_distance(200));
+    }
+
+    #[test]
+    fn wait_without_frame_would_block() {
+        let mut dw1000 = receiving_wrapper();
+
+        assert_eq!(dw1000.receive_message(0), Err(Error::WouldBlock));
+        assert_eq!(dw1000.get_state(), Dw1000State::Receiving);
+        assert_eq!(dw1000.handle_interrupt(), Err(Error::InvalidState));
+    }
+
+    #[test]
+    fn send_ping_requires_ready() {
+        let mut dw1000 = receiving_wrapper();
+
+        assert_eq!(dw1000.send_ping(), Err(Error::InvalidState));
     }
 }
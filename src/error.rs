@@ -1,3 +1,4 @@
+#[cfg(target_os = "none")]
 use crate::types::{DwCsType, DwSpiType};
 use defmt::Format;
 
@@ -7,8 +8,11 @@ pub enum Error {
     DW1000(u8),
     InvalidState,
     WouldBlock,
+    Flash,
+    Adc,
 }
 
+#[cfg(target_os = "none")]
 impl From<dw1000::Error<DwSpiType, DwCsType>> for Error {
     fn from(e: dw1000::Error<DwSpiType, DwCsType>) -> Self {
         match e {
@@ -31,6 +35,7 @@ impl From<dw1000::Error<DwSpiType, DwCsType>> for Error {
     }
 }
 
+#[cfg(target_os = "none")]
 impl From<nb::Error<dw1000::Error<DwSpiType, DwCsType>>> for Error {
     fn from(e: nb::Error<dw1000::Error<DwSpiType, DwCsType>>) -> Self {
         match e {
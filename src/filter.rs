@@ -0,0 +1,592 @@
+use defmt::Format;
+
+/// Filter for the distances measured by ranging
+pub trait DistanceFilter {
+    /// Add a distance measured at `timestamp_ms`. Timestamps are in ms and may wrap around.
+    fn update(&mut self, distance_cm: u64, timestamp_ms: u32);
+    /// Filtered distance or `None` if there was no measurement yet
+    fn get_distance(&self) -> Option<u64>;
+    /// Relative velocity in cm/s if the filter estimates it, positive if the distance increases
+    fn get_velocity(&self) -> Option<f32> {
+        None
+    }
+    fn reset(&mut self);
+}
+
+const MOVING_AVERAGE_LEN: usize = 10;
+const MEDIAN_LEN: usize = 5;
+
+/// Mean of the last ten measurements
+pub struct MovingAverageFilter {
+    history: [u64; MOVING_AVERAGE_LEN],
+    len: usize,
+}
+
+impl MovingAverageFilter {
+    pub const fn new() -> Self {
+        MovingAverageFilter {
+            history: [0; MOVING_AVERAGE_LEN],
+            len: 0,
+        }
+    }
+}
+
+impl Default for MovingAverageFilter {
+    fn default() -> Self {
+        MovingAverageFilter::new()
+    }
+}
+
+impl DistanceFilter for MovingAverageFilter {
+    fn update(&mut self, distance_cm: u64, _timestamp_ms: u32) {
+        self.history[..].rotate_right(1);
+        self.history[0] = distance_cm;
+        self.len = (self.len + 1).min(MOVING_AVERAGE_LEN);
+    }
+
+    fn get_distance(&self) -> Option<u64> {
+        if self.len == 0 {
+            None
+        } else {
+            Some(self.history[..self.len].iter().sum::<u64>() / self.len as u64)
+        }
+    }
+
+    fn reset(&mut self) {
+        self.len = 0;
+    }
+}
+
+/// Median of the last five measurements
+pub struct MedianFilter {
+    history: [u64; MEDIAN_LEN],
+    len: usize,
+}
+
+impl MedianFilter {
+    pub const fn new() -> Self {
+        MedianFilter {
+            history: [0; MEDIAN_LEN],
+            len: 0,
+        }
+    }
+}
+
+impl Default for MedianFilter {
+    fn default() -> Self {
+        MedianFilter::new()
+    }
+}
+
+impl DistanceFilter for MedianFilter {
+    fn update(&mut self, distance_cm: u64, _timestamp_ms: u32) {
+        self.history[..].rotate_right(1);
+        self.history[0] = distance_cm;
+        self.len = (self.len + 1).min(MEDIAN_LEN);
+    }
+
+    fn get_distance(&self) -> Option<u64> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let mut sorted = self.history;
+        let sorted = &mut sorted[..self.len];
+        sorted.sort_unstable();
+
+        let mid = self.len / 2;
+        if self.len % 2 == 1 {
+            Some(sorted[mid])
+        } else {
+            Some((sorted[mid - 1] + sorted[mid]) / 2)
+        }
+    }
+
+    fn reset(&mut self) {
+        self.len = 0;
+    }
+}
+
+/// Exponential smoothing with the given weight of a new measurement
+pub struct ExponentialFilter {
+    alpha: f32,
+    distance: Option<f32>,
+}
+
+impl ExponentialFilter {
+    pub const fn new(alpha: f32) -> Self {
+        ExponentialFilter {
+            alpha,
+            distance: None,
+        }
+    }
+}
+
+impl DistanceFilter for ExponentialFilter {
+    fn update(&mut self, distance_cm: u64, _timestamp_ms: u32) {
+        let measurement = distance_cm as f32;
+        self.distance = Some(match self.distance {
+            Some(distance) => distance + self.alpha * (measurement - distance),
+            None => measurement,
+        });
+    }
+
+    fn get_distance(&self) -> Option<u64> {
+        self.distance.map(|d| d.max(0f32) as u64)
+    }
+
+    fn reset(&mut self) {
+        self.distance = None;
+    }
+}
+
+/// One dimensional Kalman filter with a constant velocity model
+///
+/// The state is the distance in cm and the relative velocity in cm/s. Changes of the velocity
+/// are modelled as white noise acceleration.
+pub struct KalmanFilter {
+    /// Variance of the acceleration in (cm/s^2)^2
+    process_noise: f32,
+    /// Variance of a measurement in cm^2
+    measurement_noise: f32,
+    distance: f32,
+    velocity: f32,
+    covariance: [[f32; 2]; 2],
+    last_timestamp_ms: Option<u32>,
+}
+
+impl KalmanFilter {
+    pub const fn new(process_noise: f32, measurement_noise: f32) -> Self {
+        KalmanFilter {
+            process_noise,
+            measurement_noise,
+            distance: 0f32,
+            velocity: 0f32,
+            covariance: [[0f32; 2]; 2],
+            last_timestamp_ms: None,
+        }
+    }
+
+    fn predict(&mut self, dt: f32) {
+        let [[p00, p01], [p10, p11]] = self.covariance;
+        let q = self.process_noise;
+        let dt2 = dt * dt;
+
+        self.distance += self.velocity * dt;
+
+        self.covariance = [
+            [
+                p00 + dt * (p01 + p10) + dt2 * p11 + q * dt2 * dt2 / 4f32,
+                p01 + dt * p11 + q * dt2 * dt / 2f32,
+            ],
+            [p10 + dt * p11 + q * dt2 * dt / 2f32, p11 + q * dt2],
+        ];
+    }
+
+    fn correct(&mut self, measurement: f32) {
+        let [[p00, p01], [p10, p11]] = self.covariance;
+
+        let innovation = measurement - self.distance;
+        let s = p00 + self.measurement_noise;
+        let k0 = p00 / s;
+        let k1 = p10 / s;
+
+        self.distance += k0 * innovation;
+        self.velocity += k1 * innovation;
+
+        self.covariance = [
+            [(1f32 - k0) * p00, (1f32 - k0) * p01],
+            [p10 - k1 * p00, p11 - k1 * p01],
+        ];
+    }
+}
+
+impl DistanceFilter for KalmanFilter {
+    fn update(&mut self, distance_cm: u64, timestamp_ms: u32) {
+        let measurement = distance_cm as f32;
+
+        match self.last_timestamp_ms {
+            Some(last_timestamp_ms) => {
+                let dt = timestamp_ms.wrapping_sub(last_timestamp_ms) as f32 / 1000f32;
+                self.predict(dt);
+                self.correct(measurement);
+            }
+            None => {
+                self.distance = measurement;
+                self.velocity = 0f32;
+                // Unknown velocity, assume up to ~2 m/s
+                self.covariance = [[self.measurement_noise, 0f32], [0f32, 40_000f32]];
+            }
+        }
+
+        self.last_timestamp_ms = Some(timestamp_ms);
+    }
+
+    fn get_distance(&self) -> Option<u64> {
+        self.last_timestamp_ms
+            .map(|_| self.distance.max(0f32) as u64)
+    }
+
+    fn get_velocity(&self) -> Option<f32> {
+        self.last_timestamp_ms.map(|_| self.velocity)
+    }
+
+    fn reset(&mut self) {
+        self.last_timestamp_ms = None;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum FilterKind {
+    MovingAverage,
+    Median,
+    Exponential,
+    Kalman,
+}
+
+/// Filter used unless another one is selected at runtime
+pub const DEFAULT_FILTER: FilterKind = FilterKind::Kalman;
+
+/// Filter that can be selected at runtime
+pub enum SelectableFilter {
+    MovingAverage(MovingAverageFilter),
+    Median(MedianFilter),
+    Exponential(ExponentialFilter),
+    Kalman(KalmanFilter),
+}
+
+impl SelectableFilter {
+    pub const fn new(kind: FilterKind) -> Self {
+        match kind {
+            FilterKind::MovingAverage => {
+                SelectableFilter::MovingAverage(MovingAverageFilter::new())
+            }
+            FilterKind::Median => SelectableFilter::Median(MedianFilter::new()),
+            FilterKind::Exponential => SelectableFilter::Exponential(ExponentialFilter::new(0.3)),
+            FilterKind::Kalman => SelectableFilter::Kalman(KalmanFilter::new(10_000f32, 100f32)),
+        }
+    }
+
+    pub fn get_kind(&self) -> FilterKind {
+        match self {
+            SelectableFilter::MovingAverage(_) => FilterKind::MovingAverage,
+            SelectableFilter::Median(_) => FilterKind::Median,
+            SelectableFilter::Exponential(_) => FilterKind::Exponential,
+            SelectableFilter::Kalman(_) => FilterKind::Kalman,
+        }
+    }
+
+    fn filter(&self) -> &dyn DistanceFilter {
+        match self {
+            SelectableFilter::MovingAverage(f) => f,
+            SelectableFilter::Median(f) => f,
+            SelectableFilter::Exponential(f) => f,
+            SelectableFilter::Kalman(f) => f,
+        }
+    }
+
+    fn filter_mut(&mut self) -> &mut dyn DistanceFilter {
+        match self {
+            SelectableFilter::MovingAverage(f) => f,
+            SelectableFilter::Median(f) => f,
+            SelectableFilter::Exponential(f) => f,
+            SelectableFilter::Kalman(f) => f,
+        }
+    }
+}
+
+impl DistanceFilter for SelectableFilter {
+    fn update(&mut self, distance_cm: u64, timestamp_ms: u32) {
+        self.filter_mut().update(distance_cm, timestamp_ms)
+    }
+
+    fn get_distance(&self) -> Option<u64> {
+        self.filter().get_distance()
+    }
+
+    fn get_velocity(&self) -> Option<f32> {
+        self.filter().get_velocity()
+    }
+
+    fn reset(&mut self) {
+        self.filter_mut().reset()
+    }
+}
+
+const VELOCITY_LEN: usize = 5;
+
+/// Relative velocity as slope of a linear regression over the last five distances
+pub struct VelocityEstimator {
+    samples: [(u64, u32); VELOCITY_LEN],
+    len: usize,
+}
+
+impl VelocityEstimator {
+    pub const fn new() -> Self {
+        VelocityEstimator {
+            samples: [(0, 0); VELOCITY_LEN],
+            len: 0,
+        }
+    }
+
+    pub fn update(&mut self, distance_cm: u64, timestamp_ms: u32) {
+        self.samples[..].rotate_right(1);
+        self.samples[0] = (distance_cm, timestamp_ms);
+        self.len = (self.len + 1).min(VELOCITY_LEN);
+    }
+
+    /// Relative velocity in cm/s, positive if the distance increases
+    pub fn get_velocity(&self) -> Option<f32> {
+        if self.len < 2 {
+            return None;
+        }
+
+        let newest_ms = self.samples[0].1;
+        let samples = &self.samples[..self.len];
+        // Time in s relative to the newest sample
+        let time = |timestamp_ms: u32| -(newest_ms.wrapping_sub(timestamp_ms) as f32) / 1000f32;
+
+        let n = self.len as f32;
+        let mean_t = samples.iter().map(|(_, ts)| time(*ts)).sum::<f32>() / n;
+        let mean_d = samples.iter().map(|(d, _)| *d as f32).sum::<f32>() / n;
+
+        let (covariance, variance) = samples.iter().fold((0f32, 0f32), |(c, v), (d, ts)| {
+            let dt = time(*ts) - mean_t;
+            (c + dt * (*d as f32 - mean_d), v + dt * dt)
+        });
+
+        if variance > 0f32 {
+            Some(covariance / variance)
+        } else {
+            None
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.len = 0;
+    }
+}
+
+impl Default for VelocityEstimator {
+    fn default() -> Self {
+        VelocityEstimator::new()
+    }
+}
+
+/// Rejects distances that changed faster than physically possible since the last accepted one
+///
+/// After `MAX_CONSECUTIVE_REJECTIONS` rejected distances in a row the next one is accepted, so
+/// the gate cannot lock onto an outlier.
+pub struct OutlierGate {
+    /// Maximum relative speed in cm/s
+    max_speed: u32,
+    /// Allowed deviation independent of the elapsed time in cm
+    margin: u32,
+    last: Option<(u64, u32)>,
+    consecutive_rejections: u8,
+}
+
+const MAX_CONSECUTIVE_REJECTIONS: u8 = 3;
+
+impl OutlierGate {
+    pub const fn new(max_speed: u32, margin: u32) -> Self {
+        OutlierGate {
+            max_speed,
+            margin,
+            last: None,
+            consecutive_rejections: 0,
+        }
+    }
+
+    /// Check a distance measured at `timestamp_ms`. Returns `true` if it is plausible.
+    pub fn check(&mut self, distance_cm: u64, timestamp_ms: u32) -> bool {
+        let plausible = match self.last {
+            Some((last_distance, last_timestamp_ms)) => {
+                let dt_ms = timestamp_ms.wrapping_sub(last_timestamp_ms) as u64;
+                let max_change = self.max_speed as u64 * dt_ms / 1000 + self.margin as u64;
+                distance_cm.abs_diff(last_distance) <= max_change
+                    || self.consecutive_rejections >= MAX_CONSECUTIVE_REJECTIONS
+            }
+            None => true,
+        };
+
+        if plausible {
+            self.last = Some((distance_cm, timestamp_ms));
+            self.consecutive_rejections = 0;
+        } else {
+            self.consecutive_rejections += 1;
+        }
+
+        plausible
+    }
+
+    pub fn reset(&mut self) {
+        self.last = None;
+        self.consecutive_rejections = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Distances in cm every 300 ms while the rear rider closes up from 3 m to 1 m, with
+    /// measurement noise and a multipath spike at index 12
+    const APPROACH: [u64; 30] = [
+        302, 296, 305, 291, 288, 284, 281, 270, 268, 262, 255, 249, 410, 240, 231, 228, 219, 214,
+        204, 199, 190, 183, 178, 169, 160, 153, 142, 131, 122, 104,
+    ];
+
+    /// Distances in cm every 300 ms at a constant distance of 1 m
+    const STEADY: [u64; 20] = [
+        98, 103, 101, 96, 100, 104, 99, 97, 102, 100, 95, 101, 103, 99, 98, 102, 100, 97, 101, 104,
+    ];
+
+    fn run(filter: &mut dyn DistanceFilter, sequence: &[u64]) -> u64 {
+        for (i, distance) in sequence.iter().enumerate() {
+            filter.update(*distance, i as u32 * 300);
+        }
+        filter.get_distance().unwrap()
+    }
+
+    #[test]
+    fn no_distance_before_first_sample() {
+        for kind in [
+            FilterKind::MovingAverage,
+            FilterKind::Median,
+            FilterKind::Exponential,
+            FilterKind::Kalman,
+        ]
+        .iter()
+        {
+            let mut filter = SelectableFilter::new(*kind);
+            assert_eq!(filter.get_distance(), None);
+
+            filter.update(250, 0);
+            assert_eq!(filter.get_distance(), Some(250));
+
+            filter.reset();
+            assert_eq!(filter.get_distance(), None);
+        }
+    }
+
+    #[test]
+    fn moving_average_ignores_missing_samples() {
+        let mut filter = MovingAverageFilter::new();
+
+        filter.update(100, 0);
+        filter.update(200, 300);
+        assert_eq!(filter.get_distance(), Some(150));
+
+        assert_eq!(run(&mut filter, &STEADY), 100);
+    }
+
+    #[test]
+    fn median_rejects_spike() {
+        let mut filter = MedianFilter::new();
+
+        assert_eq!(run(&mut filter, &APPROACH[..13]), 262);
+        assert_eq!(run(&mut filter, &STEADY), 101);
+    }
+
+    #[test]
+    fn exponential_smoothing() {
+        let mut filter = ExponentialFilter::new(0.5);
+
+        filter.update(100, 0);
+        filter.update(200, 300);
+        assert_eq!(filter.get_distance(), Some(150));
+
+        let distance = run(&mut filter, &STEADY);
+        assert!((98..=103).contains(&distance));
+    }
+
+    #[test]
+    fn kalman_tracks_approach() {
+        let mut filter = KalmanFilter::new(10_000f32, 100f32);
+
+        let distance = run(&mut filter, &APPROACH);
+        let velocity = filter.get_velocity().unwrap();
+
+        // Lags less behind than the moving average
+        let average = run(&mut MovingAverageFilter::new(), &APPROACH);
+        assert!(distance < average);
+        assert!((91..130).contains(&distance));
+        // Closing up with roughly 25 cm/s
+        assert!(velocity < -10f32 && velocity > -50f32);
+    }
+
+    #[test]
+    fn kalman_settles_on_steady_distance() {
+        let mut filter = KalmanFilter::new(10_000f32, 100f32);
+
+        let distance = run(&mut filter, &STEADY);
+        assert!((97..=104).contains(&distance));
+        assert!(filter.get_velocity().unwrap().abs() < 10f32);
+    }
+
+    #[test]
+    fn kalman_handles_timestamp_wrap_around() {
+        let mut filter = KalmanFilter::new(10_000f32, 100f32);
+
+        filter.update(100, u32::MAX - 100);
+        filter.update(100, 200);
+        filter.update(100, 500);
+        assert_eq!(filter.get_distance(), Some(100));
+    }
+
+    #[test]
+    fn outlier_gate_rejects_spike() {
+        let mut gate = OutlierGate::new(300, 30);
+
+        let rejected: usize = APPROACH
+            .iter()
+            .enumerate()
+            .filter(|(i, distance)| !gate.check(**distance, *i as u32 * 300))
+            .count();
+
+        assert_eq!(rejected, 1);
+    }
+
+    #[test]
+    fn outlier_gate_allows_more_change_after_gap() {
+        let mut gate = OutlierGate::new(300, 30);
+
+        assert!(gate.check(100, 0));
+        assert!(!gate.check(300, 200));
+        assert!(gate.check(300, 1_000));
+    }
+
+    #[test]
+    fn outlier_gate_does_not_lock_onto_outlier() {
+        let mut gate = OutlierGate::new(300, 30);
+
+        assert!(gate.check(1_500, 0));
+        assert!(!gate.check(100, 100));
+        assert!(!gate.check(100, 200));
+        assert!(!gate.check(100, 300));
+        assert!(gate.check(100, 400));
+        assert!(gate.check(105, 500));
+
+        gate.reset();
+        assert!(gate.check(1_500, 600));
+    }
+
+    #[test]
+    fn velocity_estimation() {
+        let mut estimator = VelocityEstimator::new();
+        assert_eq!(estimator.get_velocity(), None);
+
+        for (i, distance) in APPROACH[..12].iter().enumerate() {
+            estimator.update(*distance, i as u32 * 300);
+        }
+        let velocity = estimator.get_velocity().unwrap();
+        assert!(velocity < -10f32 && velocity > -40f32);
+
+        estimator.reset();
+        for (i, distance) in STEADY.iter().enumerate() {
+            estimator.update(*distance, i as u32 * 300);
+        }
+        assert!(estimator.get_velocity().unwrap().abs() < 10f32);
+    }
+}
@@ -1,5 +1,43 @@
 use asm_delay::{bitrate, AsmDelay};
+use rtic::cyccnt::{Instant, U32Ext};
 
 pub fn get_delay() -> AsmDelay {
     AsmDelay::new(bitrate::U32BitrateExt::mhz(64))
 }
+
+const CYCLES_PER_MS: u32 = 64_000;
+
+/// Number of cycles of `ms` milliseconds, e.g. for the periods of the configuration
+pub fn ms_to_cycles(ms: u16) -> u32 {
+    ms as u32 * CYCLES_PER_MS
+}
+
+/// Millisecond clock based on the cycle counter
+///
+/// The cycle counter overflows after ~67 s, so longer gaps between calls of `now_ms` are lost.
+pub struct Clock {
+    last: Instant,
+    ms: u32,
+}
+
+impl Clock {
+    pub fn new(start: Instant) -> Self {
+        Clock { last: start, ms: 0 }
+    }
+
+    pub fn now_ms(&mut self) -> u32 {
+        let elapsed_ms = Instant::now().duration_since(self.last).as_cycles() / CYCLES_PER_MS;
+        self.last += (elapsed_ms * CYCLES_PER_MS).cycles();
+        self.ms = self.ms.wrapping_add(elapsed_ms);
+        self.ms
+    }
+}
+
+/// Address of the 96 bit unique device ID
+const UNIQUE_ID_ADDRESS: usize = 0x1fff_f7e8;
+
+/// 16 bit value derived from the unique device ID, e.g. for the short address of a tag
+pub fn get_unique_short_id() -> u16 {
+    let unique_id = unsafe { core::ptr::read_volatile(UNIQUE_ID_ADDRESS as *const [u16; 6]) };
+    unique_id.iter().fold(0, |id, part| id ^ part)
+}
@@ -1,6 +1,10 @@
+#[cfg(target_os = "none")]
 use crate::types::WsType;
+use core::fmt::{self, Write};
 use defmt::Format;
-use smart_leds::{SmartLedsWrite, RGB8};
+use smart_leds::RGB8;
+#[cfg(target_os = "none")]
+use smart_leds::{brightness, SmartLedsWrite};
 
 #[derive(Debug, Clone, Copy, PartialEq, Format)]
 pub enum DistanceRange {
@@ -10,6 +14,391 @@ pub enum DistanceRange {
     Ok,
     OkShort,
     Short,
+    /// The distance is not short yet but decreases so fast that it will be short soon
+    ClosingFast,
+}
+
+/// Minimum closing speed in cm/s for a closing fast warning
+const CLOSING_FAST_MIN_SPEED: i32 = 50;
+/// Warn if the distance would become short within this time in ms
+const CLOSING_FAST_TIME: u64 = 2_000;
+
+/// Distances below this one are short
+fn short_distance(target_distance: u64, tolerance: u64) -> u64 {
+    target_distance.saturating_sub(tolerance.saturating_mul(2))
+}
+
+impl DistanceRange {
+    pub const ALL: [DistanceRange; 7] = [
+        DistanceRange::OutOfRange,
+        DistanceRange::Long,
+        DistanceRange::OkLong,
+        DistanceRange::Ok,
+        DistanceRange::OkShort,
+        DistanceRange::Short,
+        DistanceRange::ClosingFast,
+    ];
+
+    /// Range of `current_distance`. A profile with a tolerance of at least half the target
+    /// distance has no short range.
+    pub fn from_distance(current_distance: u64, target_distance: u64, tolerance: u64) -> Self {
+        match current_distance {
+            d if d < short_distance(target_distance, tolerance) => DistanceRange::Short,
+            d if d < target_distance.saturating_sub(tolerance) => DistanceRange::OkShort,
+            d if d < target_distance.saturating_add(tolerance) => DistanceRange::Ok,
+            d if d < target_distance.saturating_add(tolerance.saturating_mul(2)) => {
+                DistanceRange::OkLong
+            }
+            _ => DistanceRange::Long,
+        }
+    }
+
+    /// Like [`DistanceRange::from_distance`] but [`DistanceRange::ClosingFast`] if the distance
+    /// decreases with `velocity` in cm/s so fast that it will be short soon
+    pub fn from_distance_and_velocity(
+        current_distance: u64,
+        velocity: Option<i32>,
+        target_distance: u64,
+        tolerance: u64,
+    ) -> Self {
+        let range = DistanceRange::from_distance(current_distance, target_distance, tolerance);
+
+        match velocity {
+            Some(velocity)
+                if range != DistanceRange::Short && velocity <= -CLOSING_FAST_MIN_SPEED =>
+            {
+                let time_to_short = current_distance
+                    .saturating_sub(short_distance(target_distance, tolerance))
+                    .saturating_mul(1000)
+                    / velocity.unsigned_abs() as u64;
+
+                if time_to_short < CLOSING_FAST_TIME {
+                    DistanceRange::ClosingFast
+                } else {
+                    range
+                }
+            }
+            _ => range,
+        }
+    }
+
+    /// The more urgent of two ranges, e.g. to show the worst case of several tags
+    pub fn most_urgent(self, other: Self) -> Self {
+        if other.urgency() > self.urgency() {
+            other
+        } else {
+            self
+        }
+    }
+
+    fn urgency(self) -> u8 {
+        match self {
+            DistanceRange::OutOfRange => 0,
+            DistanceRange::Ok => 1,
+            DistanceRange::OkLong => 2,
+            DistanceRange::OkShort => 3,
+            DistanceRange::Long => 4,
+            DistanceRange::ClosingFast => 5,
+            DistanceRange::Short => 6,
+        }
+    }
+}
+
+/// Warning that is shown on top of the distance range by blinking one LED
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum Overlay {
+    None,
+    LowBattery,
+    /// The unit shuts down soon
+    EmptyBattery,
+    /// The battery of the partner unit is low, it may shut down soon
+    PartnerBatteryLow,
+}
+
+/// Maximum number of LEDs of the strip
+pub const MAX_LEDS: usize = 16;
+
+pub const RED: RGB8 = RGB8 {
+    r: 0xff,
+    g: 0,
+    b: 0,
+};
+pub const GREEN: RGB8 = RGB8 {
+    r: 0,
+    g: 0xff,
+    b: 0,
+};
+pub const BLUE: RGB8 = RGB8 {
+    r: 0,
+    g: 0,
+    b: 0xff,
+};
+pub const AMBER: RGB8 = RGB8 {
+    r: 0xff,
+    g: 0x60,
+    b: 0,
+};
+const OFF: RGB8 = RGB8 { r: 0, g: 0, b: 0 };
+
+/// Number of zones of a [`Pattern`]
+pub const ZONES: usize = 5;
+
+/// Color of a zone of a [`Pattern`]
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum Color {
+    Off,
+    Red,
+    Green,
+    Blue,
+    Amber,
+}
+
+impl Color {
+    pub const ALL: [Color; 5] = [
+        Color::Off,
+        Color::Red,
+        Color::Green,
+        Color::Blue,
+        Color::Amber,
+    ];
+
+    pub fn rgb(self) -> RGB8 {
+        match self {
+            Color::Off => OFF,
+            Color::Red => RED,
+            Color::Green => GREEN,
+            Color::Blue => BLUE,
+            Color::Amber => AMBER,
+        }
+    }
+
+    /// Letter of the color on the console, `-` if the zone is off
+    pub fn letter(self) -> char {
+        match self {
+            Color::Off => '-',
+            Color::Red => 'r',
+            Color::Green => 'g',
+            Color::Blue => 'b',
+            Color::Amber => 'a',
+        }
+    }
+}
+
+/// Colors of the zones of the strip, starting at its short end
+///
+/// The zones have the same length, so a pattern fits strips of any length. An LED belongs to the
+/// zone that contains its centre.
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct Pattern(pub [Color; ZONES]);
+
+/// Bits of a color in an encoded pattern
+const COLOR_BITS: u16 = 3;
+
+impl Pattern {
+    pub const OFF: Pattern = Pattern([Color::Off; ZONES]);
+
+    /// Parse one color letter per zone, e.g. `-rg--`
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut colors = [Color::Off; ZONES];
+        let mut letters = s.chars();
+
+        for color in colors.iter_mut() {
+            let letter = letters.next()?;
+            *color = *Color::ALL.iter().find(|c| c.letter() == letter)?;
+        }
+
+        match letters.next() {
+            Some(_) => None,
+            None => Some(Pattern(colors)),
+        }
+    }
+
+    /// Encode the pattern with [`COLOR_BITS`] per zone, e.g. for the configuration
+    pub fn encode(&self) -> u16 {
+        self.0.iter().rev().fold(0, |code, color| {
+            let index = Color::ALL.iter().position(|c| c == color).unwrap_or(0);
+            code << COLOR_BITS | index as u16
+        })
+    }
+
+    /// Decode an encoded pattern, `None` if it contains an unknown color
+    pub fn decode(code: u16) -> Option<Self> {
+        if code >> (ZONES as u16 * COLOR_BITS) != 0 {
+            return None;
+        }
+
+        let mut colors = [Color::Off; ZONES];
+        for (i, color) in colors.iter_mut().enumerate() {
+            let index = code >> (i as u16 * COLOR_BITS) & ((1 << COLOR_BITS) - 1);
+            *color = *Color::ALL.get(index as usize)?;
+        }
+
+        Some(Pattern(colors))
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0
+            .iter()
+            .try_for_each(|color| f.write_char(color.letter()))
+    }
+}
+
+/// LEDs that are lit for each range
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct RangePatterns {
+    pub out_of_range: Pattern,
+    pub long: Pattern,
+    pub ok_long: Pattern,
+    pub ok: Pattern,
+    pub ok_short: Pattern,
+    pub short: Pattern,
+    pub closing_fast: Pattern,
+}
+
+impl RangePatterns {
+    pub fn get(&self, range: DistanceRange) -> Pattern {
+        match range {
+            DistanceRange::OutOfRange => self.out_of_range,
+            DistanceRange::Long => self.long,
+            DistanceRange::OkLong => self.ok_long,
+            DistanceRange::Ok => self.ok,
+            DistanceRange::OkShort => self.ok_short,
+            DistanceRange::Short => self.short,
+            DistanceRange::ClosingFast => self.closing_fast,
+        }
+    }
+
+    pub fn get_mut(&mut self, range: DistanceRange) -> &mut Pattern {
+        match range {
+            DistanceRange::OutOfRange => &mut self.out_of_range,
+            DistanceRange::Long => &mut self.long,
+            DistanceRange::OkLong => &mut self.ok_long,
+            DistanceRange::Ok => &mut self.ok,
+            DistanceRange::OkShort => &mut self.ok_short,
+            DistanceRange::Short => &mut self.short,
+            DistanceRange::ClosingFast => &mut self.closing_fast,
+        }
+    }
+}
+
+/// Green in the middle of the strip if the distance is ok, red towards the short end and blue
+/// towards the long end
+pub const DEFAULT_PATTERNS: RangePatterns = RangePatterns {
+    out_of_range: Pattern::OFF,
+    long: Pattern([Color::Off, Color::Off, Color::Off, Color::Blue, Color::Blue]),
+    ok_long: Pattern([
+        Color::Off,
+        Color::Off,
+        Color::Green,
+        Color::Blue,
+        Color::Off,
+    ]),
+    ok: Pattern([Color::Off, Color::Off, Color::Green, Color::Off, Color::Off]),
+    ok_short: Pattern([Color::Off, Color::Red, Color::Green, Color::Off, Color::Off]),
+    short: Pattern([Color::Red, Color::Red, Color::Off, Color::Off, Color::Off]),
+    closing_fast: Pattern([
+        Color::Amber,
+        Color::Amber,
+        Color::Amber,
+        Color::Off,
+        Color::Off,
+    ]),
+};
+
+/// Number, orientation and patterns of the LEDs of the strip
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LedLayout {
+    /// Number of LEDs, at most [`MAX_LEDS`]
+    pub count: usize,
+    /// The first LED of the strip is at the long end
+    pub reversed: bool,
+    pub patterns: RangePatterns,
+}
+
+pub const DEFAULT_LAYOUT: LedLayout = LedLayout {
+    count: 5,
+    reversed: false,
+    patterns: DEFAULT_PATTERNS,
+};
+
+/// Number of LEDs that are lit at `percent` state of charge of `leds` LEDs, the next one blinks
+/// while charging
+pub fn charged_leds(percent: u8, leds: usize) -> usize {
+    percent.min(100) as usize * leds / 100
+}
+
+/// What the indicator shows
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LedState {
+    pub range: DistanceRange,
+    /// Shown on the LED at the long end, or the short end for the partner's battery
+    pub overlay: Overlay,
+    /// Whether the blinking LED of the overlay or the charging animation is on
+    pub blink_on: bool,
+    /// State of charge in % while charging, which is shown instead of the range
+    pub charging: Option<u8>,
+}
+
+impl LedState {
+    pub const fn new() -> Self {
+        LedState {
+            range: DistanceRange::OutOfRange,
+            overlay: Overlay::None,
+            blink_on: false,
+            charging: None,
+        }
+    }
+
+    /// Colors of the LEDs of `layout` in the order of the strip. Only the first `layout.count`
+    /// are used.
+    pub fn colors(&self, layout: &LedLayout) -> [RGB8; MAX_LEDS] {
+        let mut data = [OFF; MAX_LEDS];
+        let count = layout.count.min(MAX_LEDS);
+        let leds = &mut data[..count];
+
+        if let Some(percent) = self.charging {
+            let lit = charged_leds(percent, count);
+            for led in leds[..lit].iter_mut() {
+                *led = GREEN;
+            }
+            if lit < count && self.blink_on {
+                leds[lit] = GREEN;
+            }
+        } else {
+            let pattern = layout.patterns.get(self.range);
+            for (i, led) in leds.iter_mut().enumerate() {
+                // Zone of the centre of the LED
+                let zone = (2 * i + 1) * ZONES / (2 * count);
+                *led = pattern.0[zone].rgb();
+            }
+
+            if self.blink_on {
+                let warning = match self.overlay {
+                    Overlay::None => None,
+                    Overlay::LowBattery => leds.last_mut().map(|led| (led, AMBER)),
+                    Overlay::EmptyBattery => leds.last_mut().map(|led| (led, RED)),
+                    Overlay::PartnerBatteryLow => leds.first_mut().map(|led| (led, AMBER)),
+                };
+                if let Some((led, color)) = warning {
+                    *led = color;
+                }
+            }
+        }
+
+        if layout.reversed {
+            leds.reverse();
+        }
+
+        data
+    }
+}
+
+impl Default for LedState {
+    fn default() -> Self {
+        LedState::new()
+    }
 }
 
 pub trait DistanceIndicator {
@@ -18,6 +407,7 @@ pub trait DistanceIndicator {
     fn update_range(
         &mut self,
         current_distance: u64,
+        velocity: Option<i32>,
         target_distance: u64,
         tolerance: u64,
     ) -> Result<DistanceRange, Self::Error>;
@@ -26,104 +416,408 @@ pub trait DistanceIndicator {
     }
     fn get_range(&self) -> DistanceRange;
     fn set_range(&mut self, range: DistanceRange);
+    fn set_overlay(&mut self, overlay: Overlay);
+    /// Toggle the blinking LED of the overlay, called periodically
+    fn blink(&mut self);
+    /// Dim the LEDs, e.g. to measure the battery without their load
+    fn set_dimmed(&mut self, dimmed: bool);
+    /// Show the charging animation up to the state of charge `percent` instead of the range,
+    /// `None` to show the range again
+    fn set_charging(&mut self, percent: Option<u8>);
     fn shutdown(&mut self);
 }
 
+#[cfg(target_os = "none")]
 pub struct LedIndicator {
     ws: WsType,
-    range: DistanceRange,
+    layout: LedLayout,
+    state: LedState,
+    dimmed: bool,
 }
 
-impl LedIndicator {
-    pub fn new(mut ws: WsType) -> Self {
-        let data: [RGB8; 5] = [RGB8::default(); 5];
-        ws.write(data.iter().cloned()).unwrap();
+/// Brightness of dimmed LEDs out of 255
+#[cfg(target_os = "none")]
+const DIMMED_BRIGHTNESS: u8 = 16;
 
-        LedIndicator {
+#[cfg(target_os = "none")]
+impl LedIndicator {
+    pub fn new(ws: WsType, layout: LedLayout) -> Self {
+        let mut indicator = LedIndicator {
             ws,
-            range: DistanceRange::OutOfRange,
-        }
+            layout,
+            state: LedState::new(),
+            dimmed: false,
+        };
+        indicator.update_leds();
+        indicator
     }
 
     fn update_leds(&mut self) {
-        let mut data: [RGB8; 5] = [RGB8::default(); 5];
+        let data = self.state.colors(&self.layout);
+        self.write(&data);
+    }
 
-        let red = RGB8::new(0xff, 0, 0);
-        let green = RGB8::new(0, 0xff, 0);
-        let blue = RGB8::new(0, 0, 0xff);
+    fn write(&mut self, data: &[RGB8; MAX_LEDS]) {
+        let leds = data[..self.layout.count.min(MAX_LEDS)].iter().cloned();
 
-        match self.range {
-            DistanceRange::OutOfRange => {}
-            DistanceRange::Long => {
-                data[3] = blue;
-                data[4] = blue;
-            }
-            DistanceRange::OkLong => {
-                data[2] = green;
-                data[3] = blue;
-            }
-            DistanceRange::Ok => {
-                data[2] = green;
-            }
-            DistanceRange::OkShort => {
-                data[1] = red;
-                data[2] = green;
-            }
-            DistanceRange::Short => {
-                data[0] = red;
-                data[1] = red;
-            }
+        if self.dimmed {
+            self.ws.write(brightness(leds, DIMMED_BRIGHTNESS)).unwrap();
+        } else {
+            self.ws.write(leds).unwrap();
         }
-
-        self.ws.write(data.iter().cloned()).unwrap();
     }
 }
 
+#[cfg(target_os = "none")]
 impl DistanceIndicator for LedIndicator {
     type Error = ();
 
     fn update_range(
         &mut self,
         current_distance: u64,
+        velocity: Option<i32>,
         target_distance: u64,
         tolerance: u64,
     ) -> Result<DistanceRange, Self::Error> {
-        let range = match current_distance {
-            d if d < target_distance - 2 * tolerance => DistanceRange::Short,
-            d if d < target_distance - tolerance => DistanceRange::OkShort,
-            d if d < target_distance + tolerance => DistanceRange::Ok,
-            d if d < target_distance + 2 * tolerance => DistanceRange::OkLong,
-            _ => DistanceRange::Long,
-        };
+        let range = DistanceRange::from_distance_and_velocity(
+            current_distance,
+            velocity,
+            target_distance,
+            tolerance,
+        );
+
+        self.set_range(range);
 
-        if range != self.range {
-            self.range = range;
+        Ok(range)
+    }
+
+    fn get_range(&self) -> DistanceRange {
+        self.state.range
+    }
+
+    fn set_range(&mut self, range: DistanceRange) {
+        if range != self.state.range {
+            self.state.range = range;
             self.update_leds();
         }
+    }
 
-        Ok(range)
+    fn set_overlay(&mut self, overlay: Overlay) {
+        if overlay != self.state.overlay {
+            self.state.overlay = overlay;
+            self.update_leds();
+        }
     }
 
-    fn set_out_of_range(&mut self) {
-        self.set_range(DistanceRange::OutOfRange);
-        self.update_leds();
+    fn blink(&mut self) {
+        self.state.blink_on = !self.state.blink_on;
+        if self.state.overlay != Overlay::None || self.state.charging.is_some() {
+            self.update_leds();
+        }
     }
 
-    fn get_range(&self) -> DistanceRange {
-        self.range
+    fn set_dimmed(&mut self, dimmed: bool) {
+        if dimmed != self.dimmed {
+            self.dimmed = dimmed;
+            self.update_leds();
+        }
     }
 
-    fn set_range(&mut self, range: DistanceRange) {
-        self.range = range
+    fn set_charging(&mut self, percent: Option<u8>) {
+        if percent != self.state.charging {
+            self.state.charging = percent;
+            self.update_leds();
+        }
     }
 
     fn shutdown(&mut self) {
-        let mut data: [RGB8; 5] = [RGB8::default(); 5];
+        let mut data = [OFF; MAX_LEDS];
+        let count = self.layout.count.min(MAX_LEDS);
 
+        // Both ends
         let red = RGB8::new(10, 0, 0);
         data[0] = red;
-        data[4] = red;
+        data[count.saturating_sub(1)] = red;
 
-        self.ws.write(data.iter().cloned()).unwrap();
+        self.dimmed = false;
+        self.write(&data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges() {
+        let ranges = [
+            (50, DistanceRange::Short),
+            (70, DistanceRange::OkShort),
+            (100, DistanceRange::Ok),
+            (130, DistanceRange::OkLong),
+            (150, DistanceRange::Long),
+        ];
+
+        for (distance, range) in ranges.iter() {
+            assert_eq!(DistanceRange::from_distance(*distance, 100, 20), *range);
+            assert_eq!(
+                DistanceRange::from_distance_and_velocity(*distance, None, 100, 20),
+                *range
+            );
+        }
+    }
+
+    #[test]
+    fn closing_fast() {
+        // 2 s until short
+        assert_eq!(
+            DistanceRange::from_distance_and_velocity(260, Some(-99), 100, 20),
+            DistanceRange::Long
+        );
+        assert_eq!(
+            DistanceRange::from_distance_and_velocity(260, Some(-101), 100, 20),
+            DistanceRange::ClosingFast
+        );
+        // Too slow
+        assert_eq!(
+            DistanceRange::from_distance_and_velocity(70, Some(-40), 100, 20),
+            DistanceRange::OkShort
+        );
+        // Already short
+        assert_eq!(
+            DistanceRange::from_distance_and_velocity(50, Some(-200), 100, 20),
+            DistanceRange::Short
+        );
+        // Moving apart
+        assert_eq!(
+            DistanceRange::from_distance_and_velocity(100, Some(200), 100, 20),
+            DistanceRange::Ok
+        );
+    }
+
+    #[test]
+    fn tolerance_of_half_the_target() {
+        // Profiles like this are rejected, but must not panic
+        for (tolerance, short_range) in
+            [(49, DistanceRange::Short), (50, DistanceRange::OkShort)].iter()
+        {
+            assert_eq!(
+                DistanceRange::from_distance(0, 100, *tolerance),
+                *short_range
+            );
+        }
+        assert_eq!(
+            DistanceRange::from_distance(0, 100, 60),
+            DistanceRange::OkShort
+        );
+        assert_eq!(DistanceRange::from_distance(30, 0, 60), DistanceRange::Ok);
+        assert_eq!(
+            DistanceRange::from_distance_and_velocity(30, Some(-200), 100, 60),
+            DistanceRange::ClosingFast
+        );
+        assert_eq!(
+            DistanceRange::from_distance_and_velocity(300, Some(i32::MIN), 100, 60),
+            DistanceRange::ClosingFast
+        );
+        assert_eq!(
+            DistanceRange::from_distance(u64::MAX, u64::MAX, u64::MAX),
+            DistanceRange::Long
+        );
+    }
+
+    #[test]
+    fn most_urgent() {
+        assert_eq!(
+            DistanceRange::Ok.most_urgent(DistanceRange::Long),
+            DistanceRange::Long
+        );
+        assert_eq!(
+            DistanceRange::Short.most_urgent(DistanceRange::ClosingFast),
+            DistanceRange::Short
+        );
+        assert_eq!(
+            DistanceRange::OutOfRange.most_urgent(DistanceRange::Ok),
+            DistanceRange::Ok
+        );
+    }
+
+    #[test]
+    fn charged_leds_follow_state_of_charge() {
+        assert_eq!(charged_leds(0, 5), 0);
+        assert_eq!(charged_leds(19, 5), 0);
+        assert_eq!(charged_leds(20, 5), 1);
+        assert_eq!(charged_leds(99, 5), 4);
+        assert_eq!(charged_leds(100, 5), 5);
+        assert_eq!(charged_leds(255, 5), 5);
+    }
+
+    fn layout(count: usize, reversed: bool) -> LedLayout {
+        LedLayout {
+            count,
+            reversed,
+            patterns: DEFAULT_PATTERNS,
+        }
+    }
+
+    fn colors(state: LedState, layout: &LedLayout) -> Vec<RGB8> {
+        state.colors(layout)[..layout.count].to_vec()
+    }
+
+    fn range(range: DistanceRange) -> LedState {
+        LedState {
+            range,
+            ..LedState::new()
+        }
+    }
+
+    #[test]
+    fn default_patterns() {
+        let patterns = [
+            (DistanceRange::OutOfRange, [OFF, OFF, OFF, OFF, OFF]),
+            (DistanceRange::Long, [OFF, OFF, OFF, BLUE, BLUE]),
+            (DistanceRange::OkLong, [OFF, OFF, GREEN, BLUE, OFF]),
+            (DistanceRange::Ok, [OFF, OFF, GREEN, OFF, OFF]),
+            (DistanceRange::OkShort, [OFF, RED, GREEN, OFF, OFF]),
+            (DistanceRange::Short, [RED, RED, OFF, OFF, OFF]),
+            (DistanceRange::ClosingFast, [AMBER, AMBER, AMBER, OFF, OFF]),
+        ];
+
+        for (r, leds) in patterns.iter() {
+            assert_eq!(colors(range(*r), &DEFAULT_LAYOUT), leds);
+        }
+    }
+
+    #[test]
+    fn patterns_fit_the_strip() {
+        let strip = layout(8, false);
+        assert_eq!(
+            colors(range(DistanceRange::OkShort), &strip),
+            [OFF, OFF, RED, GREEN, GREEN, OFF, OFF, OFF]
+        );
+        assert_eq!(
+            colors(range(DistanceRange::Long), &strip),
+            [OFF, OFF, OFF, OFF, OFF, BLUE, BLUE, BLUE]
+        );
+
+        let reversed = layout(8, true);
+        assert_eq!(
+            colors(range(DistanceRange::Long), &reversed),
+            [BLUE, BLUE, BLUE, OFF, OFF, OFF, OFF, OFF]
+        );
+
+        // Too many LEDs are limited
+        assert_eq!(
+            range(DistanceRange::Ok).colors(&layout(100, false)).len(),
+            MAX_LEDS
+        );
+        assert!(colors(range(DistanceRange::Ok), &layout(0, false)).is_empty());
+    }
+
+    #[test]
+    fn custom_patterns() {
+        let strip = LedLayout {
+            count: 3,
+            reversed: false,
+            patterns: RangePatterns {
+                out_of_range: Pattern::parse("bbbbb").unwrap(),
+                ..DEFAULT_PATTERNS
+            },
+        };
+
+        assert_eq!(
+            colors(range(DistanceRange::OutOfRange), &strip),
+            [BLUE, BLUE, BLUE]
+        );
+        assert_eq!(colors(range(DistanceRange::Ok), &strip), [OFF, GREEN, OFF]);
+    }
+
+    #[test]
+    fn patterns_are_parsed_and_encoded() {
+        let pattern = Pattern::parse("-rgba").unwrap();
+        assert_eq!(
+            pattern,
+            Pattern([
+                Color::Off,
+                Color::Red,
+                Color::Green,
+                Color::Blue,
+                Color::Amber
+            ])
+        );
+        assert_eq!(format!("{}", pattern), "-rgba");
+
+        for range in DistanceRange::ALL.iter() {
+            let pattern = DEFAULT_PATTERNS.get(*range);
+            assert_eq!(Pattern::decode(pattern.encode()), Some(pattern));
+        }
+        assert_eq!(Pattern::decode(pattern.encode()), Some(pattern));
+
+        assert_eq!(Pattern::parse("-rgb"), None);
+        assert_eq!(Pattern::parse("-rgbaa"), None);
+        assert_eq!(Pattern::parse("-rgbx"), None);
+        // Unknown color and unused bits
+        assert_eq!(Pattern::decode(5), None);
+        assert_eq!(Pattern::decode(1 << 15), None);
+    }
+
+    #[test]
+    fn overlays_blink() {
+        let state = LedState {
+            range: DistanceRange::Ok,
+            overlay: Overlay::LowBattery,
+            blink_on: true,
+            charging: None,
+        };
+        assert_eq!(
+            colors(state, &DEFAULT_LAYOUT),
+            [OFF, OFF, GREEN, OFF, AMBER]
+        );
+        assert_eq!(
+            colors(state, &layout(5, true)),
+            [AMBER, OFF, GREEN, OFF, OFF]
+        );
+
+        let state = LedState {
+            overlay: Overlay::PartnerBatteryLow,
+            ..state
+        };
+        assert_eq!(
+            colors(state, &DEFAULT_LAYOUT),
+            [AMBER, OFF, GREEN, OFF, OFF]
+        );
+        assert_eq!(
+            colors(
+                LedState {
+                    blink_on: false,
+                    ..state
+                },
+                &DEFAULT_LAYOUT
+            ),
+            [OFF, OFF, GREEN, OFF, OFF]
+        );
+    }
+
+    #[test]
+    fn charging_animation() {
+        let state = LedState {
+            range: DistanceRange::Short,
+            overlay: Overlay::LowBattery,
+            blink_on: false,
+            charging: Some(45),
+        };
+        assert_eq!(
+            colors(state, &DEFAULT_LAYOUT),
+            [GREEN, GREEN, OFF, OFF, OFF]
+        );
+        assert_eq!(
+            colors(
+                LedState {
+                    blink_on: true,
+                    ..state
+                },
+                &layout(5, true)
+            ),
+            [OFF, OFF, GREEN, GREEN, GREEN]
+        );
     }
 }
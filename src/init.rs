@@ -1,26 +1,40 @@
 use crate::battery::BatteryMonitor;
+use crate::config::{Config, ConfigStore, Stm32Flash};
 use crate::helper::get_delay;
-use crate::indicator::LedIndicator;
-use crate::types::{DwIrqType, DwTypeReady, Led1Type};
+use crate::indicator::{LedIndicator, LedLayout};
+use crate::types::{
+    BatteryMonitorType, ButtonType, DwIrqType, DwTypeReady, Led1Type, SerialRxType, SerialTxType,
+};
 use dw1000::DW1000;
 use embedded_hal::digital::v2::{InputPin, OutputPin};
 use embedded_hal::spi::MODE_0;
 use stm32f1xx_hal::adc::Adc;
 use stm32f1xx_hal::pac::SPI1;
+use stm32f1xx_hal::serial::{self, Serial};
 use stm32f1xx_hal::spi::Spi;
 use stm32f1xx_hal::{gpio::*, pac::Peripherals, prelude::*};
 use ws2812_spi::{Ws2812, MODE as WS_MODE};
 
-pub fn init_hardware(
-    dp: Peripherals,
-    _cp: rtic::Peripherals,
-) -> (
-    DwTypeReady,
-    DwIrqType,
-    Led1Type,
-    LedIndicator,
-    BatteryMonitor,
-) {
+pub struct Hardware {
+    pub dw1000: DwTypeReady,
+    pub irq: DwIrqType,
+    pub led1: Led1Type,
+    pub led_indicator: LedIndicator,
+    pub battery_monitor: BatteryMonitorType,
+    /// Console, see [`crate::cli`]
+    pub serial_tx: SerialTxType,
+    pub serial_rx: SerialRxType,
+    pub config_store: ConfigStore<Stm32Flash>,
+    /// Configuration that was loaded from the flash
+    pub config: Config,
+    /// Strap pin for the anchor role is grounded
+    pub anchor_strap: bool,
+    pub button: ButtonType,
+    /// Button was held at boot to start the pairing mode
+    pub pairing_button: bool,
+}
+
+pub fn init_hardware(dp: Peripherals, _cp: rtic::Peripherals) -> Hardware {
     defmt::info!("Init hardware");
 
     // Workaround for probe-run wfi issue
@@ -87,6 +101,37 @@ pub fn init_hardware(
 
     let bat_pin = gpioa.pa3.into_analog(&mut gpioa.crl);
 
+    // Strap pin for the role, grounded for the anchor
+    let strap = gpiob.pb1.into_pull_up_input(&mut gpiob.crl);
+    let anchor_strap = strap.is_low().unwrap();
+
+    // Button that is held at boot for pairing mode, see `crate::button` for the other functions
+    let button = gpioa.pa0.into_pull_up_input(&mut gpioa.crl);
+    let pairing_button = button.is_low().unwrap();
+
+    defmt::info!("Load configuration");
+
+    let mut config_store = ConfigStore::new(Stm32Flash::new(flash));
+    let config = config_store.load_or_default();
+
+    defmt::info!("Init serial console");
+
+    let serial_pins = (
+        gpioa.pa9.into_alternate_push_pull(&mut gpioa.crh),
+        gpioa.pa10,
+    );
+
+    let mut serial = Serial::usart1(
+        dp.USART1,
+        serial_pins,
+        &mut afio.mapr,
+        serial::Config::default().baudrate(115_200.bps()),
+        clocks,
+        &mut rcc.apb2,
+    );
+    serial.listen(serial::Event::Rxne);
+    let (serial_tx, serial_rx) = serial.split();
+
     defmt::info!("Init ADC");
 
     let adc = Adc::adc1(dp.ADC1, &mut rcc.apb2, clocks);
@@ -111,7 +156,12 @@ pub fn init_hardware(
 
     defmt::info!("Init Indicator");
 
-    let led_indicator = LedIndicator::new(ws);
+    let layout = LedLayout {
+        count: config.led_count as usize,
+        reversed: config.leds_reversed,
+        patterns: config.led_patterns,
+    };
+    let led_indicator = LedIndicator::new(ws, layout);
 
     defmt::info!("Init battery monitor");
 
@@ -138,17 +188,24 @@ pub fn init_hardware(
         .enable_rx_interrupts()
         .expect("Failed to enable RX interrupts");
 
-    // These are the hardcoded calibration values from the dwm1001-examples
-    // repository[1]. Ideally, the calibration values would be determined using
-    // the proper calibration procedure, but hopefully those are good enough for
-    // now.
-    //
-    // [1] https://github.com/Decawave/dwm1001-examples
     dw1000
-        .set_antenna_delay(16456, 16300)
+        .set_antenna_delay(config.rx_antenna_delay, config.tx_antenna_delay)
         .expect("Failed to set antenna delay");
 
     defmt::info!("Init hardware finished");
 
-    (dw1000, irq, led1, led_indicator, battery_monitor)
+    Hardware {
+        dw1000,
+        irq,
+        led1,
+        led_indicator,
+        battery_monitor,
+        serial_tx,
+        serial_rx,
+        config_store,
+        config,
+        anchor_strap,
+        button,
+        pairing_button,
+    }
 }
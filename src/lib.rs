@@ -1,22 +1,41 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
 pub mod battery;
+pub mod button;
+pub mod cli;
+pub mod config;
+pub mod control;
 pub mod dw1000;
 pub mod error;
+pub mod filter;
+#[cfg(target_os = "none")]
 pub mod helper;
 pub mod indicator;
+#[cfg(target_os = "none")]
 pub mod init;
+pub mod pairing;
+pub mod peers;
+pub mod radio;
+pub mod report;
+pub mod role;
+pub mod sync;
+pub mod twr;
+#[cfg(target_os = "none")]
 pub mod types;
 
 use core::sync::atomic::{AtomicUsize, Ordering};
 
+#[cfg(target_os = "none")]
 use defmt_rtt as _; // global logger
+#[cfg(target_os = "none")]
 use stm32f1xx_hal as _; // memory layout
 
+#[cfg(target_os = "none")]
 use panic_probe as _;
 
 // same panicking *behavior* as `panic-probe` but doesn't print a panic message
 // this prevents the panic message being printed *twice* when `defmt::panic` is invoked
+#[cfg(target_os = "none")]
 #[defmt::panic_handler]
 fn panic() -> ! {
     cortex_m::asm::udf()
@@ -31,8 +50,34 @@ defmt::timestamp!("{=usize}", {
 });
 
 /// Terminates the application and makes `probe-run` exit with exit-code = 0
+#[cfg(target_os = "none")]
 pub fn exit() -> ! {
     loop {
         cortex_m::asm::bkpt();
     }
 }
+
+/// Logger that discards everything so that the hardware independent parts can be built and
+/// tested on the host
+#[cfg(not(target_os = "none"))]
+mod host_logger {
+    use core::ptr::NonNull;
+
+    #[defmt::global_logger]
+    struct Logger;
+
+    struct Sink;
+
+    unsafe impl defmt::Logger for Logger {
+        fn acquire() -> Option<NonNull<dyn defmt::Write>> {
+            // A dangling pointer is valid for a zero-sized type
+            Some(NonNull::<Sink>::dangling() as NonNull<dyn defmt::Write>)
+        }
+
+        unsafe fn release(_: NonNull<dyn defmt::Write>) {}
+    }
+
+    impl defmt::Write for Sink {
+        fn write(&mut self, _bytes: &[u8]) {}
+    }
+}
@@ -0,0 +1,317 @@
+//! Pairing of two units so that they only range with each other
+//!
+//! Units in pairing mode broadcast their unique ID with an announce frame on the default PAN. A
+//! unit that hears an announce answers with a confirm frame that carries both IDs. A unit that
+//! receives a confirm with its own ID knows that the partner heard it and is paired. Confirms of
+//! units that are not paired yet are answered, so a lost confirm is repeated. Both units derive
+//! the same private PAN ID and distinct short addresses from the two IDs.
+
+use defmt::Format;
+use dw1000::mac;
+
+/// PAN ID of units that are not paired
+pub const DEFAULT_PAN_ID: u16 = 0x0d57;
+
+const PRELUDE: [u8; 4] = *b"BDIP";
+
+/// Frame of the pairing exchange
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum PairingMessage {
+    Announce {
+        unique_id: u16,
+    },
+    Confirm {
+        unique_id: u16,
+        partner_id: u16,
+        /// The sender received a confirm of the partner
+        paired: bool,
+    },
+}
+
+impl PairingMessage {
+    /// Maximum length of an encoded message
+    pub const MAX_LEN: usize = PRELUDE.len() + 1 + 2 * 2 + 1;
+
+    /// Encode the message into `buf` and return the length
+    pub fn encode(&self, buf: &mut [u8; Self::MAX_LEN]) -> usize {
+        buf[..PRELUDE.len()].copy_from_slice(&PRELUDE);
+
+        let (message_type, ids): (u8, &[u16]) = match self {
+            PairingMessage::Announce { unique_id } => (0, &[*unique_id]),
+            PairingMessage::Confirm {
+                unique_id,
+                partner_id,
+                ..
+            } => (1, &[*unique_id, *partner_id]),
+        };
+
+        buf[PRELUDE.len()] = message_type;
+
+        let offset = PRELUDE.len() + 1;
+        for (i, id) in ids.iter().enumerate() {
+            buf[offset + 2 * i..offset + 2 * i + 2].copy_from_slice(&id.to_le_bytes());
+        }
+
+        let mut len = offset + ids.len() * 2;
+        if let PairingMessage::Confirm { paired, .. } = self {
+            buf[len] = *paired as u8;
+            len += 1;
+        }
+
+        len
+    }
+
+    /// Decode a frame payload, `None` if it is not a pairing message
+    pub fn decode(payload: &[u8]) -> Option<Self> {
+        if !payload.starts_with(&PRELUDE) {
+            return None;
+        }
+
+        let offset = PRELUDE.len() + 1;
+        let id =
+            |i: usize| u16::from_le_bytes([payload[offset + 2 * i], payload[offset + 2 * i + 1]]);
+        let len = |ids: usize| offset + ids * 2;
+
+        match *payload.get(PRELUDE.len())? {
+            0 if payload.len() == len(1) => Some(PairingMessage::Announce { unique_id: id(0) }),
+            1 if payload.len() == len(2) + 1 => Some(PairingMessage::Confirm {
+                unique_id: id(0),
+                partner_id: id(1),
+                paired: payload[len(2)] != 0,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Private PAN ID and short addresses of two paired units
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct Pairing {
+    pub pan_id: u16,
+    pub address: u16,
+    pub partner_address: u16,
+}
+
+impl Pairing {
+    /// Pairing of the unit with `unique_id` and its partner. Both units derive the same PAN ID
+    /// and swapped addresses.
+    pub fn derive(unique_id: u16, partner_id: u16) -> Self {
+        let (low, high) = if unique_id < partner_id {
+            (unique_id, partner_id)
+        } else {
+            (partner_id, unique_id)
+        };
+
+        let hash = (((low as u32) << 16) | high as u32).wrapping_mul(0x9e37_79b9) >> 16;
+        let pan_id = match hash as u16 {
+            // Broadcast PAN ID and PAN ID of unpaired units
+            0xffff | DEFAULT_PAN_ID => hash as u16 ^ 0x0100,
+            pan_id => pan_id,
+        };
+
+        let (address, partner_address) = if unique_id < partner_id {
+            (1, 2)
+        } else {
+            (2, 1)
+        };
+
+        Pairing {
+            pan_id,
+            address,
+            partner_address,
+        }
+    }
+
+    /// Whether a frame from `source` was sent by the partner
+    pub fn is_partner(&self, source: mac::Address) -> bool {
+        match source {
+            mac::Address::Short(pan_id, address) => {
+                pan_id.0 == self.pan_id && address.0 == self.partner_address
+            }
+            _ => false,
+        }
+    }
+}
+
+/// State of the pairing exchange of one unit
+pub struct PairingSession {
+    unique_id: u16,
+    partner_id: Option<u16>,
+    confirmed: bool,
+}
+
+impl PairingSession {
+    pub const fn new(unique_id: u16) -> Self {
+        PairingSession {
+            unique_id,
+            partner_id: None,
+            confirmed: false,
+        }
+    }
+
+    /// Frame to broadcast in the next pairing cycle
+    pub fn step(&self) -> Option<PairingMessage> {
+        match (self.partner_id, self.confirmed) {
+            (None, _) => Some(PairingMessage::Announce {
+                unique_id: self.unique_id,
+            }),
+            (Some(partner_id), false) => Some(self.confirm(partner_id)),
+            (Some(_), true) => None,
+        }
+    }
+
+    /// Handle a received pairing frame and return the answer, if any
+    pub fn handle(&mut self, message: PairingMessage) -> Option<PairingMessage> {
+        match message {
+            PairingMessage::Announce { unique_id } if unique_id != self.unique_id => {
+                let partner_id = *self.partner_id.get_or_insert(unique_id);
+                if partner_id == unique_id {
+                    Some(self.confirm(partner_id))
+                } else {
+                    None
+                }
+            }
+            PairingMessage::Confirm {
+                unique_id,
+                partner_id,
+                paired,
+            } if partner_id == self.unique_id && unique_id != self.unique_id => {
+                match self.partner_id {
+                    Some(id) if id != unique_id => None,
+                    _ => {
+                        self.partner_id = Some(unique_id);
+                        self.confirmed = true;
+                        if paired {
+                            None
+                        } else {
+                            Some(self.confirm(unique_id))
+                        }
+                    }
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Pairing with the partner, `None` until the partner confirmed our ID
+    pub fn get_pairing(&self) -> Option<Pairing> {
+        match (self.partner_id, self.confirmed) {
+            (Some(partner_id), true) => Some(Pairing::derive(self.unique_id, partner_id)),
+            _ => None,
+        }
+    }
+
+    fn confirm(&self, partner_id: u16) -> PairingMessage {
+        PairingMessage::Confirm {
+            unique_id: self.unique_id,
+            partner_id,
+            paired: self.confirmed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_roundtrip() {
+        let messages = [
+            PairingMessage::Announce { unique_id: 0x1234 },
+            PairingMessage::Confirm {
+                unique_id: 0xffff,
+                partner_id: 0,
+                paired: true,
+            },
+        ];
+
+        for message in messages.iter() {
+            let mut buf = [0; PairingMessage::MAX_LEN];
+            let len = message.encode(&mut buf);
+            assert_eq!(PairingMessage::decode(&buf[..len]), Some(*message));
+        }
+
+        assert_eq!(PairingMessage::decode(b"BDI2\x00\x34\x12"), None);
+        assert_eq!(PairingMessage::decode(b"BDIP\x01\x34\x12\x00"), None);
+    }
+
+    #[test]
+    fn both_units_derive_the_same_pairing() {
+        let a = Pairing::derive(0x1234, 0xabcd);
+        let b = Pairing::derive(0xabcd, 0x1234);
+
+        assert_eq!(a.pan_id, b.pan_id);
+        assert_ne!(a.pan_id, DEFAULT_PAN_ID);
+        assert_eq!(a.address, b.partner_address);
+        assert_eq!(a.partner_address, b.address);
+        assert_ne!(Pairing::derive(0x1234, 0xabce).pan_id, a.pan_id);
+
+        let partner = mac::Address::Short(mac::PanId(a.pan_id), mac::ShortAddress(b.address));
+        let foreign = mac::Address::Short(mac::PanId(DEFAULT_PAN_ID), mac::ShortAddress(b.address));
+        assert!(a.is_partner(partner));
+        assert!(!a.is_partner(foreign));
+    }
+
+    /// Deliver all frames between two sessions until nothing is left
+    fn exchange(a: &mut PairingSession, b: &mut PairingSession, message: PairingMessage) {
+        let mut message = Some(message);
+        let sessions = [b, a];
+        let mut receiver = 0;
+
+        while let Some(m) = message {
+            message = sessions[receiver].handle(m);
+            receiver = 1 - receiver;
+        }
+    }
+
+    #[test]
+    fn pairing_exchange() {
+        let mut a = PairingSession::new(0x1234);
+        let mut b = PairingSession::new(0xabcd);
+
+        let announce = a.step().unwrap();
+        assert_eq!(announce, PairingMessage::Announce { unique_id: 0x1234 });
+        exchange(&mut a, &mut b, announce);
+
+        assert_eq!(a.get_pairing(), Some(Pairing::derive(0x1234, 0xabcd)));
+        assert_eq!(b.get_pairing(), Some(Pairing::derive(0xabcd, 0x1234)));
+        assert_eq!(a.step(), None);
+        assert_eq!(b.step(), None);
+    }
+
+    #[test]
+    fn lost_confirm_is_repeated() {
+        let mut a = PairingSession::new(0x1234);
+        let mut b = PairingSession::new(0xabcd);
+
+        // The confirm of b is lost
+        b.handle(a.step().unwrap());
+        assert_eq!(b.get_pairing(), None);
+
+        let confirm = b.step().unwrap();
+        exchange(&mut b, &mut a, confirm);
+        assert!(a.get_pairing().is_some());
+        assert!(b.get_pairing().is_some());
+    }
+
+    #[test]
+    fn third_unit_is_ignored() {
+        let mut a = PairingSession::new(0x1234);
+        let mut b = PairingSession::new(0xabcd);
+        let mut c = PairingSession::new(0x5678);
+
+        let announce = a.step().unwrap();
+        exchange(&mut a, &mut b, announce);
+        assert_eq!(a.handle(c.step().unwrap()), None);
+        assert_eq!(
+            c.handle(PairingMessage::Confirm {
+                unique_id: 0x1234,
+                partner_id: 0xabcd,
+                paired: true
+            }),
+            None
+        );
+        assert_eq!(a.get_pairing(), Some(Pairing::derive(0x1234, 0xabcd)));
+        assert_eq!(c.get_pairing(), None);
+    }
+}
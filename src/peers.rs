@@ -0,0 +1,373 @@
+use crate::filter::{DistanceFilter, FilterKind, OutlierGate, SelectableFilter, VelocityEstimator};
+use crate::indicator::DistanceRange;
+use crate::report::{DistanceReport, ReceivedReports};
+use crate::twr::DriftEstimator;
+
+/// Maximum number of nodes we range with at the same time, e.g. tags of one anchor
+pub const MAX_PEERS: usize = 4;
+/// A peer is forgotten if there was no valid distance for this time in ms
+pub const PEER_TIMEOUT: u32 = 3_000;
+
+/// Maximum relative speed of the two nodes in cm/s
+const MAX_RELATIVE_SPEED: u32 = 300;
+/// Maximum change of the distance between two measurements independent of the elapsed time in cm
+const MAX_DISTANCE_JITTER: u32 = 30;
+
+/// Distance history of a single node identified by its short address
+pub struct Peer {
+    address: u16,
+    filter: SelectableFilter,
+    gate: OutlierGate,
+    velocity: VelocityEstimator,
+    drift: DriftEstimator,
+    reports: ReceivedReports,
+    /// We answered requests of this peer, so it measures the distance as well and reports it
+    answered: bool,
+    /// State of charge of the peer's battery in % according to its last report
+    battery: Option<u8>,
+    last_distance: u64,
+    last_seen_ms: u32,
+}
+
+impl Peer {
+    fn new(address: u16, filter: FilterKind, timestamp_ms: u32) -> Self {
+        Peer {
+            address,
+            filter: SelectableFilter::new(filter),
+            gate: OutlierGate::new(MAX_RELATIVE_SPEED, MAX_DISTANCE_JITTER),
+            velocity: VelocityEstimator::new(),
+            drift: DriftEstimator::new(),
+            reports: ReceivedReports::new(),
+            answered: false,
+            battery: None,
+            last_distance: 0,
+            last_seen_ms: timestamp_ms,
+        }
+    }
+
+    pub fn get_address(&self) -> u16 {
+        self.address
+    }
+
+    /// Add a corrected distance. Returns `false` if it was rejected as outlier.
+    pub fn add_distance(&mut self, distance_cm: u64, timestamp_ms: u32) -> bool {
+        if self.gate.check(distance_cm, timestamp_ms) {
+            self.last_seen_ms = timestamp_ms;
+            self.last_distance = distance_cm;
+            self.filter.update(distance_cm, timestamp_ms);
+            self.velocity.update(distance_cm, timestamp_ms);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn get_filtered_distance(&self) -> Option<u64> {
+        self.filter.get_distance()
+    }
+
+    pub fn get_last_distance(&self) -> u64 {
+        self.last_distance
+    }
+
+    /// Relative velocity in cm/s, positive if the distance increases
+    ///
+    /// Uses the estimate of the filter if it has one.
+    pub fn get_velocity(&self) -> Option<i32> {
+        self.filter
+            .get_velocity()
+            .or_else(|| self.velocity.get_velocity())
+            .map(|v| v as i32)
+    }
+
+    /// Add a report of the distance measured by the peer. Returns `false` if the report was
+    /// ignored because we don't answer requests of the peer or it is outdated. The battery state
+    /// of the peer is taken from every report.
+    pub fn add_report(&mut self, report: DistanceReport, timestamp_ms: u32) -> bool {
+        self.battery = report.battery;
+
+        if self.answered && self.reports.update(report, timestamp_ms) {
+            self.last_seen_ms = timestamp_ms;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Last report of the peer unless it expired
+    pub fn get_report(&self) -> Option<&DistanceReport> {
+        self.reports.get()
+    }
+
+    /// Number of reports of the peer that were lost
+    pub fn get_lost_reports(&self) -> u32 {
+        self.reports.get_lost_count()
+    }
+
+    /// State of charge of the peer's battery in % if it reported it
+    pub fn get_battery(&self) -> Option<u8> {
+        self.battery
+    }
+
+    /// Keep the state of charge of the peer's battery in % from an answer to our report
+    pub fn set_battery(&mut self, battery: Option<u8>) {
+        self.battery = battery;
+    }
+
+    /// Mark the peer as one whose requests we answer
+    pub fn set_answered(&mut self) {
+        self.answered = true;
+    }
+
+    /// Range the peer reported or the range of the filtered distance if there is no current
+    /// report, `None` if there is neither
+    pub fn get_range(&self, target_distance: u64, tolerance: u64) -> Option<DistanceRange> {
+        if let Some(report) = self.get_report() {
+            return Some(report.range);
+        }
+
+        self.get_filtered_distance().map(|distance| {
+            DistanceRange::from_distance_and_velocity(
+                distance,
+                self.get_velocity(),
+                target_distance,
+                tolerance,
+            )
+        })
+    }
+
+    /// Clock drift of the peer for single-sided ranging
+    pub fn drift(&mut self) -> &mut DriftEstimator {
+        &mut self.drift
+    }
+}
+
+/// Distance histories of all nodes we range with
+pub struct PeerTable {
+    peers: [Option<Peer>; MAX_PEERS],
+    filter: FilterKind,
+}
+
+impl PeerTable {
+    pub const fn new(filter: FilterKind) -> Self {
+        PeerTable {
+            peers: [None, None, None, None],
+            filter,
+        }
+    }
+
+    /// Use a new filter of `kind` for all peers
+    pub fn set_filter(&mut self, kind: FilterKind) {
+        if kind != self.filter {
+            self.filter = kind;
+            for peer in self.peers.iter_mut().flatten() {
+                peer.filter = SelectableFilter::new(kind);
+            }
+        }
+    }
+
+    pub fn get(&self, address: u16) -> Option<&Peer> {
+        self.iter().find(|peer| peer.address == address)
+    }
+
+    /// Get the peer with `address` or add it at `timestamp_ms`. If the table is full the peer
+    /// that was not measured for the longest time is replaced.
+    pub fn get_or_insert(&mut self, address: u16, timestamp_ms: u32) -> &mut Peer {
+        let index = match self.position(address) {
+            Some(index) => index,
+            None => {
+                let index = self
+                    .peers
+                    .iter()
+                    .position(|peer| peer.is_none())
+                    .unwrap_or_else(|| self.least_recently_seen(timestamp_ms));
+                self.peers[index] = Some(Peer::new(address, self.filter, timestamp_ms));
+                index
+            }
+        };
+
+        self.peers[index].as_mut().unwrap()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Peer> {
+        self.peers.iter().flatten()
+    }
+
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get_mut(&mut self, address: u16) -> Option<&mut Peer> {
+        self.peers
+            .iter_mut()
+            .flatten()
+            .find(|peer| peer.address == address)
+    }
+
+    /// Forget peers without a valid distance or report for [`PEER_TIMEOUT`] and reports older
+    /// than [`crate::report::REPORT_TIMEOUT`]
+    pub fn remove_stale(&mut self, now_ms: u32) {
+        for peer in self.peers.iter_mut() {
+            if let Some(p) = peer {
+                if now_ms.wrapping_sub(p.last_seen_ms) > PEER_TIMEOUT {
+                    *peer = None;
+                } else {
+                    p.reports.expire(now_ms);
+                }
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        for peer in self.peers.iter_mut() {
+            *peer = None;
+        }
+    }
+
+    /// Most urgent range of all peers, [`DistanceRange::OutOfRange`] without any distance
+    pub fn get_worst_range(&self, target_distance: u64, tolerance: u64) -> DistanceRange {
+        self.iter()
+            .filter_map(|peer| peer.get_range(target_distance, tolerance))
+            .fold(DistanceRange::OutOfRange, DistanceRange::most_urgent)
+    }
+
+    /// Lowest state of charge in % that a peer reported for its battery
+    pub fn get_lowest_battery(&self) -> Option<u8> {
+        self.iter().filter_map(Peer::get_battery).min()
+    }
+
+    fn position(&self, address: u16) -> Option<usize> {
+        self.peers
+            .iter()
+            .position(|peer| matches!(peer, Some(peer) if peer.address == address))
+    }
+
+    fn least_recently_seen(&self, now_ms: u32) -> usize {
+        (0..MAX_PEERS)
+            .max_by_key(|i| {
+                self.peers[*i]
+                    .as_ref()
+                    .map_or(0, |peer| now_ms.wrapping_sub(peer.last_seen_ms))
+            })
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peers_are_tracked_separately() {
+        let mut peers = PeerTable::new(FilterKind::MovingAverage);
+
+        peers.get_or_insert(0x1235, 0).add_distance(100, 0);
+        peers.get_or_insert(0x1236, 0).add_distance(300, 0);
+        peers.get_or_insert(0x1235, 100).add_distance(110, 100);
+
+        assert_eq!(peers.len(), 2);
+        assert_eq!(
+            peers.get(0x1235).unwrap().get_filtered_distance(),
+            Some(105)
+        );
+        assert_eq!(
+            peers.get(0x1236).unwrap().get_filtered_distance(),
+            Some(300)
+        );
+        assert!(peers.get(0x1237).is_none());
+    }
+
+    #[test]
+    fn least_recently_seen_peer_is_replaced() {
+        let mut peers = PeerTable::new(FilterKind::MovingAverage);
+
+        for address in 0..MAX_PEERS as u16 {
+            peers.get_or_insert(address, 0);
+        }
+        for address in 1..MAX_PEERS as u16 {
+            peers.get_or_insert(address, 100).add_distance(100, 100);
+        }
+        peers.get_or_insert(0x1235, 200);
+
+        assert_eq!(peers.len(), MAX_PEERS);
+        assert!(peers.get(0).is_none());
+        assert!(peers.get(0x1235).is_some());
+    }
+
+    #[test]
+    fn stale_peers_are_removed() {
+        let mut peers = PeerTable::new(FilterKind::MovingAverage);
+
+        peers.get_or_insert(0x1235, 0).add_distance(100, 0);
+        peers.get_or_insert(0x1236, 2_000).add_distance(100, 2_000);
+
+        peers.remove_stale(PEER_TIMEOUT + 1);
+        assert!(peers.get(0x1235).is_none());
+        assert!(peers.get(0x1236).is_some());
+
+        peers.clear();
+        assert!(peers.is_empty());
+    }
+
+    #[test]
+    fn worst_range() {
+        let mut peers = PeerTable::new(FilterKind::MovingAverage);
+        assert_eq!(peers.get_worst_range(100, 20), DistanceRange::OutOfRange);
+
+        peers.get_or_insert(0x1235, 0).add_distance(100, 0);
+        assert_eq!(peers.get_worst_range(100, 20), DistanceRange::Ok);
+
+        peers.get_or_insert(0x1236, 0).add_distance(300, 0);
+        assert_eq!(peers.get_worst_range(100, 20), DistanceRange::Long);
+
+        peers.get_or_insert(0x1237, 0).add_distance(20, 0);
+        assert_eq!(peers.get_worst_range(100, 20), DistanceRange::Short);
+    }
+
+    #[test]
+    fn reported_range_is_preferred() {
+        let mut peers = PeerTable::new(FilterKind::MovingAverage);
+        let report = DistanceReport {
+            sequence: 0,
+            distance: 130,
+            range: DistanceRange::OkLong,
+            battery: Some(40),
+        };
+
+        let peer = peers.get_or_insert(0x1235, 0);
+        peer.add_distance(100, 0);
+        // Only peers whose requests we answer report their distance, but all their battery
+        assert!(!peer.add_report(report, 0));
+        assert_eq!(peer.get_battery(), Some(40));
+        peer.set_answered();
+        assert!(peer.add_report(report, 0));
+        assert_eq!(peers.get_worst_range(100, 20), DistanceRange::OkLong);
+
+        // Back to our own measurement if the reports go missing
+        peers.get_mut(0x1235).unwrap().add_distance(100, 1_000);
+        peers.remove_stale(1_000);
+        assert_eq!(peers.get_worst_range(100, 20), DistanceRange::Ok);
+    }
+
+    #[test]
+    fn lowest_battery() {
+        let mut peers = PeerTable::new(FilterKind::MovingAverage);
+        assert_eq!(peers.get_lowest_battery(), None);
+
+        for (address, battery) in [(0x1235, Some(60)), (0x1236, Some(15)), (0x1237, None)].iter() {
+            let report = DistanceReport {
+                sequence: 0,
+                distance: 100,
+                range: DistanceRange::Ok,
+                battery: *battery,
+            };
+            peers.get_or_insert(*address, 0).add_report(report, 0);
+        }
+
+        assert_eq!(peers.get_lowest_battery(), Some(15));
+    }
+}
@@ -0,0 +1,291 @@
+use crate::dw1000::Dw1000State;
+use crate::error::Error;
+use crate::pairing::PairingMessage;
+use crate::report::ReportMessage;
+use crate::sync::SyncMessage;
+use crate::twr::{RangingMode, RequestTimestamps, DEFAULT_RANGING_MODE};
+use dw1000::mac;
+
+/// A received frame, decoded as far as the ranging logic needs it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RangingFrame<P, Q> {
+    Ping(P),
+    RangingRequest(Q),
+    /// Source of the response and the computed distance in mm if it could be computed
+    RangingResponse(mac::Address, Option<u64>),
+    Pairing(mac::Address, PairingMessage),
+    Sync(mac::Address, SyncMessage),
+    Report(mac::Address, ReportMessage),
+    Unknown,
+}
+
+/// Everything the ranging logic needs from a radio.
+///
+/// The radio is a state machine with the states of [`Dw1000State`]. Sending and receiving is
+/// only possible from [`Dw1000State::Ready`].
+pub trait RangingRadio {
+    /// Received ping that can be answered with a ranging request
+    type Ping;
+    /// Received ranging request that can be answered with a ranging response
+    type Request;
+
+    fn get_state(&self) -> Dw1000State;
+    fn start_receiving(&mut self) -> Result<(), Error>;
+    fn finish_receiving(&mut self) -> Result<(), Error>;
+    fn finish_sending(&mut self) -> Result<(), Error>;
+
+    /// Get the received frame. The radio is ready afterwards if a frame was received.
+    fn wait_for_frame(&mut self) -> Result<RangingFrame<Self::Ping, Self::Request>, Error>;
+
+    fn send_ping(&mut self) -> Result<(), Error>;
+    fn send_request(&mut self, ping: &Self::Ping) -> Result<(), Error>;
+    fn send_response(&mut self, request: &Self::Request) -> Result<(), Error>;
+    /// Broadcast a frame of the pairing exchange
+    fn send_pairing(&mut self, message: &PairingMessage) -> Result<(), Error>;
+    /// Broadcast a frame of the configuration sync
+    fn send_sync(&mut self, message: &SyncMessage) -> Result<(), Error>;
+    /// Broadcast a frame of the distance reporting
+    fn send_report(&mut self, message: &ReportMessage) -> Result<(), Error>;
+
+    fn get_ping_source(&self, ping: &Self::Ping) -> mac::Address;
+    fn get_request_source(&self, request: &Self::Request) -> mac::Address;
+
+    /// Source and timestamps of a received request, which allow ranging on the anchor
+    fn get_request_timestamps(
+        &self,
+        request: &Self::Request,
+    ) -> Option<(mac::Address, RequestTimestamps)>;
+
+    /// Select the frames used for pings, requests and responses
+    fn set_ranging_mode(&mut self, mode: RangingMode);
+    /// Delay requests by `slot` times [`crate::twr::REPLY_SLOT`]
+    fn set_reply_slot(&mut self, slot: u8);
+    /// Change PAN ID and short address, only possible from [`Dw1000State::Ready`]
+    fn set_address(&mut self, pan_id: u16, address: u16) -> Result<(), Error>;
+
+    /// Acknowledge the radio interrupt. Fails if no interrupt is pending.
+    fn handle_interrupt(&mut self) -> Result<(), Error>;
+}
+
+/// Frame sent by a [`FakeRadio`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SentFrame {
+    Ping,
+    RangingRequest(mac::Address),
+    RangingResponse(mac::Address),
+    Pairing(PairingMessage),
+    Sync(SyncMessage),
+    Report(ReportMessage),
+}
+
+/// Request received by a [`FakeRadio`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FakeRequest {
+    pub source: mac::Address,
+    pub timestamps: Option<RequestTimestamps>,
+}
+
+impl FakeRequest {
+    /// Request without timestamps, the anchor can't measure the distance
+    pub fn new(source: mac::Address) -> Self {
+        FakeRequest {
+            source,
+            timestamps: None,
+        }
+    }
+}
+
+/// In-memory radio that mirrors the state machine of the DW1000
+///
+/// Frames are injected with [`FakeRadio::deliver`] and sent frames can be taken with
+/// [`FakeRadio::take_sent`]. Pings are identified by their source address.
+pub struct FakeRadio {
+    state: Dw1000State,
+    rx_frame: Option<RangingFrame<mac::Address, FakeRequest>>,
+    sent: Option<SentFrame>,
+    interrupt_pending: bool,
+    ranging_mode: RangingMode,
+    reply_slot: u8,
+    address: Option<(u16, u16)>,
+}
+
+impl FakeRadio {
+    pub fn new() -> Self {
+        FakeRadio {
+            state: Dw1000State::Ready,
+            rx_frame: None,
+            sent: None,
+            interrupt_pending: false,
+            ranging_mode: DEFAULT_RANGING_MODE,
+            reply_slot: 0,
+            address: None,
+        }
+    }
+
+    /// Deliver a frame to the radio. Returns `false` if the radio was not receiving and the
+    /// frame is lost.
+    pub fn deliver(&mut self, frame: RangingFrame<mac::Address, FakeRequest>) -> bool {
+        if self.state == Dw1000State::Receiving && self.rx_frame.is_none() {
+            self.rx_frame = Some(frame);
+            self.interrupt_pending = true;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn take_sent(&mut self) -> Option<SentFrame> {
+        self.sent.take()
+    }
+
+    pub fn get_ranging_mode(&self) -> RangingMode {
+        self.ranging_mode
+    }
+
+    pub fn get_reply_slot(&self) -> u8 {
+        self.reply_slot
+    }
+
+    /// PAN ID and short address if they were changed with `set_address`
+    pub fn get_address(&self) -> Option<(u16, u16)> {
+        self.address
+    }
+
+    fn send(&mut self, frame: SentFrame) -> Result<(), Error> {
+        if self.state == Dw1000State::Ready {
+            self.state = Dw1000State::Sending;
+            self.sent = Some(frame);
+            self.interrupt_pending = true;
+            Ok(())
+        } else {
+            Err(Error::InvalidState)
+        }
+    }
+}
+
+impl Default for FakeRadio {
+    fn default() -> Self {
+        FakeRadio::new()
+    }
+}
+
+impl RangingRadio for FakeRadio {
+    type Ping = mac::Address;
+    type Request = FakeRequest;
+
+    fn get_state(&self) -> Dw1000State {
+        self.state
+    }
+
+    fn start_receiving(&mut self) -> Result<(), Error> {
+        match self.state {
+            Dw1000State::Ready | Dw1000State::Receiving => {
+                self.rx_frame = None;
+                self.state = Dw1000State::Receiving;
+                Ok(())
+            }
+            Dw1000State::Sending => Err(Error::InvalidState),
+        }
+    }
+
+    fn finish_receiving(&mut self) -> Result<(), Error> {
+        match self.state {
+            Dw1000State::Ready | Dw1000State::Receiving => {
+                self.rx_frame = None;
+                self.state = Dw1000State::Ready;
+                Ok(())
+            }
+            Dw1000State::Sending => Err(Error::InvalidState),
+        }
+    }
+
+    fn finish_sending(&mut self) -> Result<(), Error> {
+        if self.state == Dw1000State::Sending {
+            self.state = Dw1000State::Ready;
+            Ok(())
+        } else {
+            Err(Error::InvalidState)
+        }
+    }
+
+    fn wait_for_frame(&mut self) -> Result<RangingFrame<mac::Address, FakeRequest>, Error> {
+        if self.state != Dw1000State::Receiving {
+            return Err(Error::InvalidState);
+        }
+
+        match self.rx_frame.take() {
+            Some(frame) => {
+                self.state = Dw1000State::Ready;
+                Ok(frame)
+            }
+            None => Err(Error::WouldBlock),
+        }
+    }
+
+    fn send_ping(&mut self) -> Result<(), Error> {
+        self.send(SentFrame::Ping)
+    }
+
+    fn send_request(&mut self, ping: &mac::Address) -> Result<(), Error> {
+        self.send(SentFrame::RangingRequest(*ping))
+    }
+
+    fn send_response(&mut self, request: &FakeRequest) -> Result<(), Error> {
+        self.send(SentFrame::RangingResponse(request.source))
+    }
+
+    fn send_pairing(&mut self, message: &PairingMessage) -> Result<(), Error> {
+        self.send(SentFrame::Pairing(*message))
+    }
+
+    fn send_sync(&mut self, message: &SyncMessage) -> Result<(), Error> {
+        self.send(SentFrame::Sync(*message))
+    }
+
+    fn send_report(&mut self, message: &ReportMessage) -> Result<(), Error> {
+        self.send(SentFrame::Report(*message))
+    }
+
+    fn get_ping_source(&self, ping: &mac::Address) -> mac::Address {
+        *ping
+    }
+
+    fn get_request_source(&self, request: &FakeRequest) -> mac::Address {
+        request.source
+    }
+
+    fn get_request_timestamps(
+        &self,
+        request: &FakeRequest,
+    ) -> Option<(mac::Address, RequestTimestamps)> {
+        request
+            .timestamps
+            .map(|timestamps| (request.source, timestamps))
+    }
+
+    fn set_ranging_mode(&mut self, mode: RangingMode) {
+        self.ranging_mode = mode;
+    }
+
+    fn set_reply_slot(&mut self, slot: u8) {
+        self.reply_slot = slot;
+    }
+
+    fn set_address(&mut self, pan_id: u16, address: u16) -> Result<(), Error> {
+        if self.state == Dw1000State::Ready {
+            self.address = Some((pan_id, address));
+            Ok(())
+        } else {
+            Err(Error::InvalidState)
+        }
+    }
+
+    fn handle_interrupt(&mut self) -> Result<(), Error> {
+        if self.interrupt_pending {
+            self.interrupt_pending = false;
+            Ok(())
+        } else {
+            Err(Error::InvalidState)
+        }
+    }
+}
@@ -0,0 +1,252 @@
+//! Reports of the measured distance to the node that answered the ranging request
+//!
+//! Only the node that receives the ranging response knows the distance measured with it. It
+//! broadcasts the filtered distance and the range it shows in a report frame so that the other
+//! node can show the same. Every report carries a sequence number, which allows counting lost
+//! reports. The receiver only uses the last report for [`REPORT_TIMEOUT`] and falls back to its
+//! own measurement if no further reports arrive.
+//!
+//! The receiver answers with the state of charge of its battery, which the report carries as
+//! well, so each unit knows the battery of its partner.
+
+use crate::indicator::DistanceRange;
+use defmt::Format;
+
+const PRELUDE: [u8; 4] = *b"BDIR";
+/// Encoded battery state of charge if it is unknown
+const BATTERY_UNKNOWN: u8 = 0xff;
+
+/// A received report is used for this time in ms
+pub const REPORT_TIMEOUT: u32 = 500;
+
+/// Distance measured by the sender of the report
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct DistanceReport {
+    pub sequence: u8,
+    /// Filtered distance in cm
+    pub distance: u16,
+    /// Range the sender shows
+    pub range: DistanceRange,
+    /// State of charge of the sender's battery in %
+    pub battery: Option<u8>,
+}
+
+/// Frame of the distance reporting
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum ReportMessage {
+    Distance(DistanceReport),
+    /// Answer to a distance report with the state of charge of the sender's battery in %
+    Answer {
+        battery: Option<u8>,
+    },
+}
+
+impl ReportMessage {
+    /// Maximum length of an encoded message
+    pub const MAX_LEN: usize = PRELUDE.len() + 1 + 1 + 1 + 2 + 1;
+
+    /// Encode the message into `buf` and return the length
+    pub fn encode(&self, buf: &mut [u8; Self::MAX_LEN]) -> usize {
+        buf[..PRELUDE.len()].copy_from_slice(&PRELUDE);
+
+        let offset = PRELUDE.len() + 2;
+        match self {
+            ReportMessage::Distance(report) => {
+                buf[PRELUDE.len()] = 0;
+                buf[PRELUDE.len() + 1] = encode_battery(report.battery);
+                buf[offset] = report.sequence;
+                buf[offset + 1..offset + 3].copy_from_slice(&report.distance.to_le_bytes());
+                buf[offset + 3] = encode_range(report.range);
+                Self::MAX_LEN
+            }
+            ReportMessage::Answer { battery } => {
+                buf[PRELUDE.len()] = 1;
+                buf[PRELUDE.len() + 1] = encode_battery(*battery);
+                offset
+            }
+        }
+    }
+
+    /// Decode a frame payload, `None` if it is not a report message
+    pub fn decode(payload: &[u8]) -> Option<Self> {
+        if !payload.starts_with(&PRELUDE) {
+            return None;
+        }
+
+        let offset = PRELUDE.len() + 2;
+        let battery = match *payload.get(PRELUDE.len() + 1)? {
+            BATTERY_UNKNOWN => None,
+            percent => Some(percent),
+        };
+
+        match payload[PRELUDE.len()] {
+            0 if payload.len() == Self::MAX_LEN => Some(ReportMessage::Distance(DistanceReport {
+                sequence: payload[offset],
+                distance: u16::from_le_bytes([payload[offset + 1], payload[offset + 2]]),
+                range: decode_range(payload[offset + 3])?,
+                battery,
+            })),
+            1 if payload.len() == offset => Some(ReportMessage::Answer { battery }),
+            _ => None,
+        }
+    }
+}
+
+fn encode_battery(battery: Option<u8>) -> u8 {
+    battery.unwrap_or(BATTERY_UNKNOWN)
+}
+
+fn encode_range(range: DistanceRange) -> u8 {
+    match range {
+        DistanceRange::OutOfRange => 0,
+        DistanceRange::Long => 1,
+        DistanceRange::OkLong => 2,
+        DistanceRange::Ok => 3,
+        DistanceRange::OkShort => 4,
+        DistanceRange::Short => 5,
+        DistanceRange::ClosingFast => 6,
+    }
+}
+
+fn decode_range(code: u8) -> Option<DistanceRange> {
+    match code {
+        0 => Some(DistanceRange::OutOfRange),
+        1 => Some(DistanceRange::Long),
+        2 => Some(DistanceRange::OkLong),
+        3 => Some(DistanceRange::Ok),
+        4 => Some(DistanceRange::OkShort),
+        5 => Some(DistanceRange::Short),
+        6 => Some(DistanceRange::ClosingFast),
+        _ => None,
+    }
+}
+
+/// Reports received from one peer
+pub struct ReceivedReports {
+    last: Option<DistanceReport>,
+    received_ms: u32,
+    lost: u32,
+}
+
+impl ReceivedReports {
+    pub const fn new() -> Self {
+        ReceivedReports {
+            last: None,
+            received_ms: 0,
+            lost: 0,
+        }
+    }
+
+    /// Add a report received at `timestamp_ms`. Returns `false` if it is a repeated or older
+    /// report.
+    pub fn update(&mut self, report: DistanceReport, timestamp_ms: u32) -> bool {
+        if let Some(last) = self.last {
+            let diff = report.sequence.wrapping_sub(last.sequence);
+            if diff == 0 || diff > u8::MAX / 2 {
+                return false;
+            }
+            self.lost = self.lost.wrapping_add(diff as u32 - 1);
+        }
+
+        self.last = Some(report);
+        self.received_ms = timestamp_ms;
+        true
+    }
+
+    /// Forget the last report if it is older than [`REPORT_TIMEOUT`]
+    pub fn expire(&mut self, now_ms: u32) {
+        if now_ms.wrapping_sub(self.received_ms) > REPORT_TIMEOUT {
+            self.last = None;
+        }
+    }
+
+    /// Last report unless it expired
+    pub fn get(&self) -> Option<&DistanceReport> {
+        self.last.as_ref()
+    }
+
+    /// Number of reports that were lost between two received ones
+    pub fn get_lost_count(&self) -> u32 {
+        self.lost
+    }
+}
+
+impl Default for ReceivedReports {
+    fn default() -> Self {
+        ReceivedReports::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(sequence: u8) -> DistanceReport {
+        DistanceReport {
+            sequence,
+            distance: 123,
+            range: DistanceRange::OkShort,
+            battery: Some(80),
+        }
+    }
+
+    #[test]
+    fn messages_roundtrip() {
+        let mut messages: Vec<_> = (0..7)
+            .map(|code| {
+                ReportMessage::Distance(DistanceReport {
+                    sequence: 0xab,
+                    distance: 0x1234,
+                    range: decode_range(code).unwrap(),
+                    battery: if code == 0 { None } else { Some(code * 10) },
+                })
+            })
+            .collect();
+        messages.push(ReportMessage::Answer { battery: Some(42) });
+        messages.push(ReportMessage::Answer { battery: None });
+
+        for message in messages.iter() {
+            let mut buf = [0; ReportMessage::MAX_LEN];
+            let len = message.encode(&mut buf);
+            assert_eq!(ReportMessage::decode(&buf[..len]), Some(*message));
+        }
+
+        assert_eq!(ReportMessage::decode(b"BDIR\x00\x50\x01\x34\x12\x07"), None);
+        assert_eq!(ReportMessage::decode(b"BDIR\x00\x50\x01\x34\x12"), None);
+        assert_eq!(ReportMessage::decode(b"BDIR\x01"), None);
+        assert_eq!(ReportMessage::decode(b"BDIS\x01\x50"), None);
+    }
+
+    #[test]
+    fn lost_reports_are_counted() {
+        let mut reports = ReceivedReports::new();
+        assert_eq!(reports.get(), None);
+
+        assert!(reports.update(report(254), 0));
+        assert!(reports.update(report(255), 100));
+        // Repeated and older reports are ignored
+        assert!(!reports.update(report(255), 150));
+        assert!(!reports.update(report(250), 150));
+        // Two reports are lost while the sequence wraps
+        assert!(reports.update(report(2), 400));
+
+        assert_eq!(reports.get(), Some(&report(2)));
+        assert_eq!(reports.get_lost_count(), 2);
+    }
+
+    #[test]
+    fn reports_expire() {
+        let mut reports = ReceivedReports::new();
+
+        reports.update(report(1), 1_000);
+        reports.expire(1_000 + REPORT_TIMEOUT);
+        assert!(reports.get().is_some());
+
+        reports.expire(1_001 + REPORT_TIMEOUT);
+        assert!(reports.get().is_none());
+
+        // The sequence starts over after a timeout, e.g. after a reboot of the sender
+        assert!(reports.update(report(0), 2_000));
+        assert_eq!(reports.get_lost_count(), 0);
+    }
+}
@@ -0,0 +1,324 @@
+use crate::control::convoy_address;
+use crate::dw1000::PingFilter;
+use crate::peers::MAX_PEERS;
+use defmt::Format;
+
+/// Short address of the anchor
+pub const ANCHOR_ADDRESS: u16 = 0x1234;
+/// Tags get an address from the unique device ID so that one anchor can range with several tags
+pub const TAG_ADDRESS_BASE: u16 = 0x2000;
+
+/// Role of a node, selected at boot
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum Role {
+    Anchor,
+    Tag,
+    /// Node at the given position in a convoy, 0 is the head
+    Convoy(u8),
+}
+
+/// Number of pings a tag answers in a row without getting a response before it moves to another
+/// reply slot, 3 s at the default anchor period
+const MOVE_SLOT_PINGS: u8 = 5;
+
+/// Minimum back-off of the role negotiation in control cycles of the tag, longer than the ping
+/// period of the anchor
+const NEGOTIATION_MIN_CYCLES: u8 = 20;
+
+impl Role {
+    /// Role at boot. A grounded strap pin forces the anchor role, otherwise the persisted role
+    /// is used. `None` if the role has to be negotiated.
+    pub fn select(anchor_strap: bool, persisted: Option<Role>) -> Option<Self> {
+        if anchor_strap {
+            Some(Role::Anchor)
+        } else {
+            persisted
+        }
+    }
+
+    /// Short address of the node, `unique_id` is derived from the unique device ID
+    pub fn address(self, unique_id: u16) -> u16 {
+        match self {
+            Role::Anchor => ANCHOR_ADDRESS,
+            Role::Tag => TAG_ADDRESS_BASE | (unique_id & 0x0fff),
+            Role::Convoy(position) => convoy_address(position),
+        }
+    }
+
+    /// Reply slot for requests. Convoy nodes only answer their predecessor and don't need one.
+    pub fn reply_slot(self, address: u16) -> u8 {
+        match self {
+            Role::Convoy(_) => 0,
+            _ => (address % MAX_PEERS as u16) as u8,
+        }
+    }
+
+    /// Pings that are answered. A convoy node acts as tag only towards its predecessor.
+    pub fn ping_filter(self) -> PingFilter {
+        match self {
+            Role::Convoy(0) => PingFilter::Ignore,
+            Role::Convoy(position) => PingFilter::Only(convoy_address(position - 1)),
+            _ => PingFilter::Any,
+        }
+    }
+
+    pub fn convoy_position(self) -> Option<u8> {
+        match self {
+            Role::Convoy(position) => Some(position),
+            _ => None,
+        }
+    }
+}
+
+/// Reply slot of a node that moves if its requests keep getting lost
+///
+/// Tags start in the slot derived from their address, so tags with addresses that are equal
+/// modulo [`MAX_PEERS`] share a slot and their requests collide at the anchor. A tag that answers
+/// several pings in a row without getting a response moves to one of the other slots. The slot is
+/// picked pseudo randomly from the address, so two tags that collide split up even if both move.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReplySlot {
+    slot: u8,
+    unanswered: u8,
+    /// State of the random generator, `None` if the slot never moves
+    random: Option<u32>,
+}
+
+impl ReplySlot {
+    pub fn new(role: Role, address: u16) -> Self {
+        ReplySlot {
+            slot: role.reply_slot(address),
+            unanswered: 0,
+            random: match role {
+                Role::Convoy(_) => None,
+                _ => Some(address as u32),
+            },
+        }
+    }
+
+    /// Slot that never moves
+    pub const fn fixed(slot: u8) -> Self {
+        ReplySlot {
+            slot,
+            unanswered: 0,
+            random: None,
+        }
+    }
+
+    pub fn get(&self) -> u8 {
+        self.slot
+    }
+
+    /// Count a ping that was answered. Returns the new slot if the node moves.
+    pub fn ping_answered(&mut self) -> Option<u8> {
+        let random = self.random.as_mut()?;
+
+        self.unanswered += 1;
+        if self.unanswered < MOVE_SLOT_PINGS {
+            return None;
+        }
+        self.unanswered = 0;
+
+        // Linear congruential generator of Numerical Recipes
+        *random = random.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        let offset = 1 + (*random >> 16) % (MAX_PEERS as u32 - 1);
+        self.slot = (self.slot + offset as u8) % MAX_PEERS as u8;
+
+        Some(self.slot)
+    }
+
+    /// A response to one of our requests arrived
+    pub fn response_received(&mut self) {
+        self.unanswered = 0;
+    }
+}
+
+/// Negotiation of the role between units without a selected role
+///
+/// An undecided unit listens for pings for a back-off period derived from its unique ID. If it
+/// hears a ping it becomes a tag, otherwise an anchor. An anchor that hears the ping of another
+/// anchor, e.g. because both chose the same back-off, becomes a tag as well.
+pub struct RoleNegotiation {
+    role: Option<Role>,
+    cycles_left: u8,
+    negotiable: bool,
+}
+
+impl RoleNegotiation {
+    pub fn new(unique_id: u16) -> Self {
+        // Spread similar IDs over 32 back-offs
+        let backoff = (unique_id as u32).wrapping_mul(0x9e37_79b9) >> 27;
+
+        RoleNegotiation {
+            role: None,
+            cycles_left: NEGOTIATION_MIN_CYCLES + backoff as u8,
+            negotiable: true,
+        }
+    }
+
+    /// Role that was selected at boot and never changes
+    pub const fn fixed(role: Role) -> Self {
+        RoleNegotiation {
+            role: Some(role),
+            cycles_left: 0,
+            negotiable: false,
+        }
+    }
+
+    /// `None` while the role is still negotiated
+    pub fn get_role(&self) -> Option<Role> {
+        self.role
+    }
+
+    /// Run one control cycle. Returns the new role if it changed.
+    pub fn step(&mut self, ping_seen: bool) -> Option<Role> {
+        if !self.negotiable {
+            return None;
+        }
+
+        let role = match self.role {
+            None if ping_seen => Role::Tag,
+            None if self.cycles_left == 0 => Role::Anchor,
+            None => {
+                self.cycles_left -= 1;
+                return None;
+            }
+            Some(Role::Anchor) if ping_seen => Role::Tag,
+            Some(_) => return None,
+        };
+
+        self.role = Some(role);
+        Some(role)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strap_overrides_configuration() {
+        assert_eq!(
+            Role::select(true, Some(Role::Convoy(1))),
+            Some(Role::Anchor)
+        );
+        assert_eq!(
+            Role::select(false, Some(Role::Convoy(1))),
+            Some(Role::Convoy(1))
+        );
+        assert_eq!(Role::select(false, None), None);
+    }
+
+    fn negotiate(negotiation: &mut RoleNegotiation, ping_cycle: Option<u8>) -> (u8, Role) {
+        for cycle in 0..=255 {
+            if let Some(role) = negotiation.step(ping_cycle == Some(cycle)) {
+                return (cycle, role);
+            }
+        }
+        panic!("No role negotiated");
+    }
+
+    #[test]
+    fn negotiation_without_ping() {
+        let mut negotiation = RoleNegotiation::new(0x1234);
+        assert_eq!(negotiation.get_role(), None);
+
+        let (cycles, role) = negotiate(&mut negotiation, None);
+        assert_eq!(role, Role::Anchor);
+        assert!(cycles >= NEGOTIATION_MIN_CYCLES);
+        assert_eq!(negotiation.get_role(), Some(Role::Anchor));
+
+        // Another anchor is pinging
+        assert_eq!(negotiation.step(true), Some(Role::Tag));
+        assert_eq!(negotiation.step(true), None);
+    }
+
+    #[test]
+    fn negotiation_with_ping() {
+        let mut negotiation = RoleNegotiation::new(0x1234);
+
+        assert_eq!(negotiate(&mut negotiation, Some(5)), (5, Role::Tag));
+        assert_eq!(negotiation.get_role(), Some(Role::Tag));
+    }
+
+    #[test]
+    fn similar_ids_use_different_backoffs() {
+        let (a, _) = negotiate(&mut RoleNegotiation::new(0x0a31), None);
+        let (b, _) = negotiate(&mut RoleNegotiation::new(0x0a32), None);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn fixed_role_is_not_negotiated() {
+        let mut negotiation = RoleNegotiation::fixed(Role::Anchor);
+
+        assert_eq!(negotiation.step(true), None);
+        assert_eq!(negotiation.get_role(), Some(Role::Anchor));
+    }
+
+    #[test]
+    fn addresses() {
+        assert_eq!(Role::Anchor.address(0xabcd), ANCHOR_ADDRESS);
+        assert_eq!(Role::Tag.address(0xabcd), 0x2bcd);
+        assert_eq!(Role::Convoy(2).address(0xabcd), convoy_address(2));
+
+        assert_eq!(Role::Tag.reply_slot(0x2bcd), 1);
+        assert_eq!(Role::Convoy(2).reply_slot(convoy_address(2)), 0);
+    }
+
+    fn move_slot(slot: &mut ReplySlot) -> Option<u8> {
+        (0..MOVE_SLOT_PINGS)
+            .map(|_| slot.ping_answered())
+            .last()
+            .unwrap()
+    }
+
+    #[test]
+    fn reply_slot_moves_without_responses() {
+        let mut slot = ReplySlot::new(Role::Tag, 0x2bcd);
+        assert_eq!(slot.get(), 1);
+
+        // Responses keep the slot
+        for _ in 0..3 * MOVE_SLOT_PINGS {
+            assert_eq!(slot.ping_answered(), None);
+            slot.response_received();
+        }
+        assert_eq!(slot.get(), 1);
+
+        let moved = move_slot(&mut slot).unwrap();
+        assert_ne!(moved, 1);
+        assert!((moved as usize) < MAX_PEERS);
+        assert_eq!(slot.get(), moved);
+
+        let mut convoy = ReplySlot::new(Role::Convoy(2), convoy_address(2));
+        assert_eq!(move_slot(&mut convoy), None);
+        assert_eq!(convoy.get(), 0);
+    }
+
+    #[test]
+    fn colliding_tags_split_up() {
+        let mut a = ReplySlot::new(Role::Tag, 0x2001);
+        let mut b = ReplySlot::new(Role::Tag, 0x2005);
+        assert_eq!(a.get(), b.get());
+
+        // Both lose all their requests until they use different slots
+        for _ in 0..4 {
+            if a.get() != b.get() {
+                break;
+            }
+            move_slot(&mut a);
+            move_slot(&mut b);
+        }
+        assert_ne!(a.get(), b.get());
+    }
+
+    #[test]
+    fn convoy_ping_filter() {
+        assert_eq!(Role::Tag.ping_filter(), PingFilter::Any);
+        assert_eq!(Role::Convoy(0).ping_filter(), PingFilter::Ignore);
+        assert_eq!(
+            Role::Convoy(2).ping_filter(),
+            PingFilter::Only(convoy_address(1))
+        );
+    }
+}
@@ -0,0 +1,486 @@
+//! Synchronization of the distance profile between two units
+//!
+//! A local change of the profile is broadcast as update frame until the partner acknowledges it
+//! or the retries are used up. Every update carries a version, which is a counter and the unique
+//! ID of the unit that made the change. A unit only applies updates with a newer version than
+//! its own, so the units end up with the same settings even if both changed them at the same
+//! time. An update with an older version is answered with our own update, e.g. if the partner was
+//! switched off while the settings changed. The version is stored with the configuration, so it
+//! doesn't start over after a reboot.
+
+use crate::config::{Config, Profile, PROFILES};
+use defmt::Format;
+
+const PRELUDE: [u8; 4] = *b"BDIS";
+
+/// Number of times an update is sent without acknowledgement
+pub const SYNC_RETRIES: u8 = 10;
+
+/// Settings that both units of a pair share
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct SharedSettings {
+    /// Index of the active profile
+    pub profile: u8,
+    pub target_distance: u16,
+    pub tolerance: u16,
+}
+
+impl SharedSettings {
+    pub fn from_config(config: &Config) -> Self {
+        let profile = config.get_profile();
+
+        SharedSettings {
+            profile: config.profile,
+            target_distance: profile.target_distance,
+            tolerance: profile.tolerance,
+        }
+    }
+
+    fn get_profile(&self) -> Profile {
+        Profile {
+            target_distance: self.target_distance,
+            tolerance: self.tolerance,
+        }
+    }
+
+    /// Whether the settings can be applied, like the profiles of the configuration
+    pub fn is_valid(&self) -> bool {
+        (self.profile as usize) < PROFILES && self.get_profile().is_valid()
+    }
+
+    /// Activate the settings in `config`. Returns `false` if they are invalid and `config` is
+    /// unchanged.
+    pub fn apply(&self, config: &mut Config) -> bool {
+        if !self.is_valid() || !config.select_profile(self.profile) {
+            return false;
+        }
+
+        *config.get_profile_mut() = self.get_profile();
+        true
+    }
+}
+
+/// Version of the shared settings, newer versions win
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct Version {
+    pub counter: u16,
+    /// Unique ID of the unit that made the change, decides between concurrent changes
+    pub origin: u16,
+}
+
+/// Frame of the configuration sync
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum SyncMessage {
+    Update {
+        version: Version,
+        settings: SharedSettings,
+    },
+    Ack {
+        version: Version,
+    },
+}
+
+impl SyncMessage {
+    /// Maximum length of an encoded message
+    pub const MAX_LEN: usize = PRELUDE.len() + 1 + 2 * 2 + 1 + 2 * 2;
+
+    /// Encode the message into `buf` and return the length
+    pub fn encode(&self, buf: &mut [u8; Self::MAX_LEN]) -> usize {
+        buf[..PRELUDE.len()].copy_from_slice(&PRELUDE);
+
+        let version = match self {
+            SyncMessage::Update { version, .. } | SyncMessage::Ack { version } => version,
+        };
+
+        let offset = PRELUDE.len() + 1;
+        buf[offset..offset + 2].copy_from_slice(&version.counter.to_le_bytes());
+        buf[offset + 2..offset + 4].copy_from_slice(&version.origin.to_le_bytes());
+
+        match self {
+            SyncMessage::Update { settings, .. } => {
+                buf[PRELUDE.len()] = 0;
+                buf[offset + 4] = settings.profile;
+                buf[offset + 5..offset + 7]
+                    .copy_from_slice(&settings.target_distance.to_le_bytes());
+                buf[offset + 7..offset + 9].copy_from_slice(&settings.tolerance.to_le_bytes());
+                Self::MAX_LEN
+            }
+            SyncMessage::Ack { .. } => {
+                buf[PRELUDE.len()] = 1;
+                offset + 4
+            }
+        }
+    }
+
+    /// Decode a frame payload, `None` if it is not a sync message
+    pub fn decode(payload: &[u8]) -> Option<Self> {
+        if !payload.starts_with(&PRELUDE) {
+            return None;
+        }
+
+        let offset = PRELUDE.len() + 1;
+        let u16_at = |i: usize| u16::from_le_bytes([payload[i], payload[i + 1]]);
+
+        match *payload.get(PRELUDE.len())? {
+            0 if payload.len() == Self::MAX_LEN => Some(SyncMessage::Update {
+                version: Version {
+                    counter: u16_at(offset),
+                    origin: u16_at(offset + 2),
+                },
+                settings: SharedSettings {
+                    profile: payload[offset + 4],
+                    target_distance: u16_at(offset + 5),
+                    tolerance: u16_at(offset + 7),
+                },
+            }),
+            1 if payload.len() == offset + 4 => Some(SyncMessage::Ack {
+                version: Version {
+                    counter: u16_at(offset),
+                    origin: u16_at(offset + 2),
+                },
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Sync state of one unit
+pub struct ConfigSync {
+    unique_id: u16,
+    version: Version,
+    /// Settings of `version`
+    settings: SharedSettings,
+    /// Remaining retries of the update of `version`
+    retries_left: u8,
+}
+
+impl ConfigSync {
+    /// `config` is the configuration at boot, which is not sent until it is changed
+    pub fn new(unique_id: u16, config: &Config) -> Self {
+        ConfigSync {
+            unique_id,
+            version: config.sync_version,
+            settings: SharedSettings::from_config(config),
+            retries_left: 0,
+        }
+    }
+
+    /// Start sending the shared settings if they were changed locally. Returns `true` if the
+    /// settings changed, the new version is stored in `config`.
+    pub fn check(&mut self, config: &mut Config) -> bool {
+        let settings = SharedSettings::from_config(config);
+        if settings == self.settings {
+            return false;
+        }
+
+        self.settings = settings;
+        self.version = Version {
+            counter: self.version.counter.wrapping_add(1),
+            origin: self.unique_id,
+        };
+        config.sync_version = self.version;
+        self.retries_left = SYNC_RETRIES;
+        true
+    }
+
+    /// Update to send in the next sync cycle, `None` if there is nothing left to send
+    pub fn step(&mut self) -> Option<SyncMessage> {
+        if self.retries_left == 0 {
+            return None;
+        }
+
+        self.retries_left -= 1;
+        Some(SyncMessage::Update {
+            version: self.version,
+            settings: self.settings,
+        })
+    }
+
+    /// Handle a received sync frame and apply received settings to `config`. Returns the
+    /// answer, if any, and whether `config` changed.
+    pub fn handle(
+        &mut self,
+        message: SyncMessage,
+        config: &mut Config,
+    ) -> (Option<SyncMessage>, bool) {
+        match message {
+            // Our own update
+            SyncMessage::Update { version, .. }
+                if version == self.version && version.origin == self.unique_id =>
+            {
+                (None, false)
+            }
+            // Not acknowledged, so the partner notices that it was dropped
+            SyncMessage::Update { settings, .. } if !settings.is_valid() => (None, false),
+            // The same version with other settings is a change we lost, e.g. because it wasn't
+            // saved before a reboot
+            SyncMessage::Update { version, settings }
+                if self.is_newer(version)
+                    || (version == self.version && settings != self.settings) =>
+            {
+                self.version = version;
+                self.settings = settings;
+                // A concurrent change of our own is superseded
+                self.retries_left = 0;
+
+                settings.apply(config);
+                config.sync_version = version;
+                (Some(SyncMessage::Ack { version }), true)
+            }
+            // Repeated update whose ack was lost
+            SyncMessage::Update { version, .. } if version == self.version => {
+                (Some(SyncMessage::Ack { version }), false)
+            }
+            // The partner missed our settings
+            SyncMessage::Update { .. } => (
+                Some(SyncMessage::Update {
+                    version: self.version,
+                    settings: self.settings,
+                }),
+                false,
+            ),
+            SyncMessage::Ack { version } => {
+                if version == self.version {
+                    self.retries_left = 0;
+                }
+                (None, false)
+            }
+        }
+    }
+
+    /// Whether `version` is newer than ours, the counter wraps around
+    fn is_newer(&self, version: Version) -> bool {
+        let diff = version.counter.wrapping_sub(self.version.counter) as i16;
+        diff > 0 || (diff == 0 && version.origin > self.version.origin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DEFAULT_CONFIG;
+
+    #[test]
+    fn messages_roundtrip() {
+        let version = Version {
+            counter: 0x1234,
+            origin: 0xabcd,
+        };
+        let messages = [
+            SyncMessage::Update {
+                version,
+                settings: SharedSettings {
+                    profile: 2,
+                    target_distance: 800,
+                    tolerance: 100,
+                },
+            },
+            SyncMessage::Ack { version },
+        ];
+
+        for message in messages.iter() {
+            let mut buf = [0; SyncMessage::MAX_LEN];
+            let len = message.encode(&mut buf);
+            assert_eq!(SyncMessage::decode(&buf[..len]), Some(*message));
+        }
+
+        assert_eq!(SyncMessage::decode(b"BDIP\x01\x34\x12\xcd\xab"), None);
+        assert_eq!(SyncMessage::decode(b"BDIS\x01\x34\x12\xcd"), None);
+    }
+
+    /// Deliver all frames between two units and apply the received settings
+    fn exchange(
+        a: (&mut ConfigSync, &mut Config),
+        b: (&mut ConfigSync, &mut Config),
+        message: SyncMessage,
+    ) {
+        let mut units = [b, a];
+        let mut message = Some(message);
+        let mut receiver = 0;
+
+        while let Some(m) = message {
+            let (sync, config) = &mut units[receiver];
+            message = sync.handle(m, config).0;
+            receiver = 1 - receiver;
+        }
+    }
+
+    #[test]
+    fn change_is_acknowledged() {
+        let (mut config_a, mut config_b) = (DEFAULT_CONFIG, DEFAULT_CONFIG);
+        let mut a = ConfigSync::new(0x1234, &config_a);
+        let mut b = ConfigSync::new(0xabcd, &config_b);
+
+        assert!(!a.check(&mut config_a));
+        assert_eq!(a.step(), None);
+
+        config_a.next_profile();
+        config_a.get_profile_mut().tolerance = 70;
+        assert!(a.check(&mut config_a));
+
+        let update = a.step().unwrap();
+        exchange((&mut a, &mut config_a), (&mut b, &mut config_b), update);
+
+        assert_eq!(config_b, config_a);
+        assert_eq!(a.step(), None);
+        // The received settings are not sent back
+        assert!(!b.check(&mut config_b));
+    }
+
+    #[test]
+    fn lost_update_is_repeated() {
+        let (mut config_a, mut config_b) = (DEFAULT_CONFIG, DEFAULT_CONFIG);
+        let mut a = ConfigSync::new(0x1234, &config_a);
+        let mut b = ConfigSync::new(0xabcd, &config_b);
+
+        config_a.next_profile();
+        a.check(&mut config_a);
+
+        for _ in 0..SYNC_RETRIES - 1 {
+            a.step().unwrap();
+        }
+        let update = a.step().unwrap();
+        assert_eq!(a.step(), None);
+
+        // The ack is lost, a repeated update is only applied once
+        assert!(b.handle(update, &mut config_b).1);
+        assert_eq!(
+            b.handle(update, &mut config_b),
+            (Some(SyncMessage::Ack { version: a.version }), false)
+        );
+        assert_eq!(config_b.profile, 1);
+    }
+
+    #[test]
+    fn concurrent_changes_converge() {
+        let (mut config_a, mut config_b) = (DEFAULT_CONFIG, DEFAULT_CONFIG);
+        let mut a = ConfigSync::new(0x1234, &config_a);
+        let mut b = ConfigSync::new(0xabcd, &config_b);
+
+        config_a.select_profile(1);
+        config_b.select_profile(2);
+        a.check(&mut config_a);
+        b.check(&mut config_b);
+
+        let update_a = a.step().unwrap();
+        let update_b = b.step().unwrap();
+        exchange((&mut a, &mut config_a), (&mut b, &mut config_b), update_a);
+        exchange((&mut b, &mut config_b), (&mut a, &mut config_a), update_b);
+
+        // The unit with the higher unique ID wins
+        assert_eq!(config_a.profile, 2);
+        assert_eq!(config_b.profile, 2);
+        assert_eq!(config_a.sync_version, config_b.sync_version);
+        assert_eq!(a.step(), None);
+        assert_eq!(b.step(), None);
+    }
+
+    #[test]
+    fn changes_after_reboot_are_applied() {
+        let (mut config_a, mut config_b) = (DEFAULT_CONFIG, DEFAULT_CONFIG);
+        let mut a = ConfigSync::new(0x1234, &config_a);
+        let mut b = ConfigSync::new(0xabcd, &config_b);
+
+        config_a.next_profile();
+        a.check(&mut config_a);
+        let update = a.step().unwrap();
+        exchange((&mut a, &mut config_a), (&mut b, &mut config_b), update);
+
+        // A reboots with its saved configuration and continues with the next version
+        let mut a = ConfigSync::new(0x1234, &config_a);
+        config_a.next_profile();
+        a.check(&mut config_a);
+        let update = a.step().unwrap();
+        exchange((&mut a, &mut config_a), (&mut b, &mut config_b), update);
+        assert_eq!(config_b.profile, 2);
+        assert_eq!(config_b, config_a);
+
+        // A reboots without having saved the change and makes another one with the same version
+        let mut config_a = Config {
+            profile: 1,
+            sync_version: Version {
+                counter: 1,
+                origin: 0x1234,
+            },
+            ..DEFAULT_CONFIG
+        };
+        let mut a = ConfigSync::new(0x1234, &config_a);
+        config_a.get_profile_mut().tolerance = 30;
+        a.check(&mut config_a);
+        assert_eq!(a.version, b.version);
+        let update = a.step().unwrap();
+        exchange((&mut a, &mut config_a), (&mut b, &mut config_b), update);
+        assert_eq!(config_b, config_a);
+        assert_eq!(a.step(), None);
+    }
+
+    #[test]
+    fn older_update_is_answered_with_ours() {
+        let (mut config_a, mut config_b) = (DEFAULT_CONFIG, DEFAULT_CONFIG);
+        let mut a = ConfigSync::new(0x1234, &config_a);
+        let mut b = ConfigSync::new(0xabcd, &config_b);
+
+        // B is switched off while A changes its profile several times
+        for _ in 0..3 {
+            config_a.next_profile();
+            a.check(&mut config_a);
+        }
+        config_b.get_profile_mut().tolerance = 30;
+        b.check(&mut config_b);
+
+        let update = b.step().unwrap();
+        assert_eq!(
+            a.handle(update, &mut config_a),
+            (
+                Some(SyncMessage::Update {
+                    version: a.version,
+                    settings: SharedSettings::from_config(&config_a),
+                }),
+                false
+            )
+        );
+        exchange((&mut b, &mut config_b), (&mut a, &mut config_a), update);
+        assert_eq!(config_b, config_a);
+        assert_eq!(b.step(), None);
+    }
+
+    #[test]
+    fn invalid_update_is_dropped() {
+        let mut config = DEFAULT_CONFIG;
+        let mut sync = ConfigSync::new(0x1234, &config);
+
+        for (profile, target_distance, tolerance) in [(0, 100, 50), (0, 0, 0), (3, 300, 50)].iter()
+        {
+            let update = SyncMessage::Update {
+                version: Version {
+                    counter: 1,
+                    origin: 0xabcd,
+                },
+                settings: SharedSettings {
+                    profile: *profile,
+                    target_distance: *target_distance,
+                    tolerance: *tolerance,
+                },
+            };
+            assert_eq!(sync.handle(update, &mut config), (None, false));
+        }
+        assert_eq!(config, DEFAULT_CONFIG);
+    }
+
+    #[test]
+    fn version_counter_wraps() {
+        let mut config = DEFAULT_CONFIG;
+        let mut sync = ConfigSync::new(0x1234, &config);
+        sync.version.counter = u16::MAX;
+
+        let update = SyncMessage::Update {
+            version: Version {
+                counter: 0,
+                origin: 0xabcd,
+            },
+            settings: SharedSettings::from_config(&DEFAULT_CONFIG),
+        };
+        assert!(sync.handle(update, &mut config).1);
+        assert_eq!(config.sync_version.counter, 0);
+    }
+}
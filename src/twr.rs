@@ -0,0 +1,479 @@
+//! Asymmetric double-sided two-way ranging
+//!
+//! The initiator (anchor) sends a poll, the responder (tag) answers with a response and the
+//! initiator finishes the exchange with a final frame that carries its three timestamps. The
+//! responder then knows all six timestamps and computes the time of flight according to the
+//! DW1000 user manual, section 12.3.2. Drift of the two clocks cancels out independent of the
+//! reply times.
+//!
+//! All timestamps are raw 40 bit DW1000 timestamps with a tick of 1/(128 * 499.2 MHz).
+
+use defmt::Format;
+
+/// Ranging scheme used by a radio
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum RangingMode {
+    /// Ping, request and response of `dw1000::ranging`
+    Dw1000,
+    /// Poll, response and final frames of this module
+    DoubleSided,
+}
+
+/// Ranging scheme used unless another one is selected at runtime
+pub const DEFAULT_RANGING_MODE: RangingMode = RangingMode::Dw1000;
+
+/// DW1000 timestamps wrap around after 40 bits
+pub const TIMESTAMP_MASK: u64 = (1 << 40) - 1;
+/// The DW1000 ignores the lower 9 bits of a delayed TX time
+const DELAYED_TX_MASK: u64 = !0x1ff;
+/// Delay between reading the system time and sending a frame, 5 ms
+const TX_DELAY: u64 = 5 * 63_897_600;
+
+/// Width of a reply slot, 20 ms. Tags answer a ping in different slots to avoid collisions, so
+/// a slot has to be longer than the delay of the anchor's response.
+pub const REPLY_SLOT: u64 = 20 * 63_897_600;
+/// Accept clock drifts between two nodes up to 100 ppm
+const MAX_DRIFT_PPB: i64 = 100_000;
+
+const PRELUDE: [u8; 4] = *b"BDI2";
+const TIMESTAMP_LEN: usize = 5;
+
+/// Time from `start` to `end` in ticks, taking the wrap around of the timer into account
+pub fn duration(start: u64, end: u64) -> u64 {
+    end.wrapping_sub(start) & TIMESTAMP_MASK
+}
+
+/// Delayed TX time to program for a frame that is sent in reply slot `slot` after [`TX_DELAY`]
+/// from the system time `now`
+///
+/// This is the value of the DX_TIME register, the TX timestamp of the frame is
+/// [`tx_timestamp`].
+pub fn delayed_tx_time(now: u64, slot: u8) -> u64 {
+    now.wrapping_add(TX_DELAY + slot as u64 * REPLY_SLOT) & TIMESTAMP_MASK & DELAYED_TX_MASK
+}
+
+/// Actual TX timestamp of a frame sent at the delayed TX time `delayed_tx_time`, which can be
+/// embedded into the frame
+///
+/// The DW1000 ignores the lower 9 bits of the delayed TX time and the frame leaves the antenna
+/// `antenna_delay` ticks later.
+pub fn tx_timestamp(delayed_tx_time: u64, antenna_delay: u64) -> u64 {
+    (delayed_tx_time & DELAYED_TX_MASK).wrapping_add(antenna_delay) & TIMESTAMP_MASK
+}
+
+/// TX time, embedded TX timestamp and reply time of a `dw1000::ranging::Request`
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct RequestTimes {
+    /// Delayed TX time
+    pub tx_time: u64,
+    /// TX timestamp embedded into the request, the TX time plus the antenna delay
+    pub request_tx: u64,
+    /// Duration between reception of the ping and sending of the request
+    pub ping_reply: u64,
+}
+
+impl RequestTimes {
+    /// Move the request into reply slot `slot`
+    pub fn delay(&self, slot: u8) -> Self {
+        let delay = slot as u64 * REPLY_SLOT;
+
+        RequestTimes {
+            tx_time: self.tx_time.wrapping_add(delay) & TIMESTAMP_MASK,
+            request_tx: self.request_tx.wrapping_add(delay) & TIMESTAMP_MASK,
+            ping_reply: self.ping_reply + delay,
+        }
+    }
+}
+
+/// Timestamps of one double-sided exchange
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct Timestamps {
+    pub poll_tx: u64,
+    pub poll_rx: u64,
+    pub response_tx: u64,
+    pub response_rx: u64,
+    pub final_tx: u64,
+    pub final_rx: u64,
+}
+
+impl Timestamps {
+    /// Distance in mm or `None` if the timestamps are inconsistent
+    pub fn compute_distance_mm(&self) -> Option<u64> {
+        let initiator_round_trip = duration(self.poll_tx, self.response_rx) as u128;
+        let initiator_reply = duration(self.response_rx, self.final_tx) as u128;
+        let responder_reply = duration(self.poll_rx, self.response_tx) as u128;
+        let responder_round_trip = duration(self.response_tx, self.final_rx) as u128;
+
+        let round_trips = initiator_round_trip * responder_round_trip;
+        let replies = initiator_reply * responder_reply;
+        let sum = initiator_round_trip + initiator_reply + responder_reply + responder_round_trip;
+
+        if sum == 0 {
+            return None;
+        }
+
+        let time_of_flight = round_trips.checked_sub(replies)? / sum;
+
+        // A tick is nominally 1/64 ns
+        Some((time_of_flight * 299_792_458 / 64 / 1_000_000) as u64)
+    }
+}
+
+/// Timestamps of a ranging request as seen by the anchor
+///
+/// The tag reports the TX time of the ping, its reply time and the TX time of the request.
+/// Together with the RX time of the request this allows single-sided ranging on the anchor.
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct RequestTimestamps {
+    pub ping_tx: u64,
+    /// Duration between reception of the ping and sending of the request in ticks of the tag
+    pub ping_reply: u64,
+    pub request_tx: u64,
+    pub request_rx: u64,
+}
+
+impl RequestTimestamps {
+    /// Distance in mm, compensating the clock of the tag running `drift_ppb` faster than the own
+    /// clock
+    pub fn compute_distance_mm(&self, drift_ppb: i64) -> Option<u64> {
+        let round_trip = duration(self.ping_tx, self.request_rx) as i128;
+        let reply = self.ping_reply as i128;
+        let reply = reply - reply * drift_ppb as i128 / 1_000_000_000;
+
+        let time_of_flight = (round_trip - reply) / 2;
+
+        if time_of_flight < 0 {
+            None
+        } else {
+            // A tick is nominally 1/64 ns
+            Some((time_of_flight * 299_792_458 / 64 / 1_000_000) as u64)
+        }
+    }
+}
+
+/// Estimates the drift of the clock of a tag relative to the own clock from the TX and RX
+/// times of consecutive requests
+///
+/// The reply time of the tag is several ms, so without compensation a drift of a few ppm
+/// already causes an error of meters.
+pub struct DriftEstimator {
+    last: Option<(u64, u64)>,
+    drift_ppb: Option<i64>,
+}
+
+impl DriftEstimator {
+    pub const fn new() -> Self {
+        DriftEstimator {
+            last: None,
+            drift_ppb: None,
+        }
+    }
+
+    /// Update the estimate with a new request and return the drift in ppb
+    pub fn update(&mut self, timestamps: &RequestTimestamps) -> Option<i64> {
+        if let Some((last_tx, last_rx)) = self.last {
+            let tx = duration(last_tx, timestamps.request_tx) as i64;
+            let rx = duration(last_rx, timestamps.request_rx) as i64;
+
+            if tx > 0 {
+                let drift_ppb = ((tx - rx) as i128 * 1_000_000_000 / rx.max(1) as i128) as i64;
+
+                if drift_ppb.abs() <= MAX_DRIFT_PPB {
+                    self.drift_ppb = Some(drift_ppb);
+                }
+            }
+        }
+
+        self.last = Some((timestamps.request_tx, timestamps.request_rx));
+        self.drift_ppb
+    }
+
+    pub fn get_drift_ppb(&self) -> Option<i64> {
+        self.drift_ppb
+    }
+
+    pub fn reset(&mut self) {
+        self.last = None;
+        self.drift_ppb = None;
+    }
+}
+
+impl Default for DriftEstimator {
+    fn default() -> Self {
+        DriftEstimator::new()
+    }
+}
+
+/// Payload of a frame of the double-sided exchange
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum TwrMessage {
+    Poll,
+    /// The responder's timestamps allow single-sided ranging on the initiator
+    Response {
+        poll_rx: u64,
+        response_tx: u64,
+    },
+    Final {
+        poll_tx: u64,
+        response_rx: u64,
+        final_tx: u64,
+    },
+}
+
+impl TwrMessage {
+    /// Maximum length of an encoded message
+    pub const MAX_LEN: usize = PRELUDE.len() + 1 + 3 * TIMESTAMP_LEN;
+
+    /// Encode the message into `buf` and return the length
+    pub fn encode(&self, buf: &mut [u8; Self::MAX_LEN]) -> usize {
+        buf[..PRELUDE.len()].copy_from_slice(&PRELUDE);
+
+        let (message_type, timestamps): (u8, &[u64]) = match self {
+            TwrMessage::Poll => (0, &[]),
+            TwrMessage::Response {
+                poll_rx,
+                response_tx,
+            } => (1, &[*poll_rx, *response_tx]),
+            TwrMessage::Final {
+                poll_tx,
+                response_rx,
+                final_tx,
+            } => (2, &[*poll_tx, *response_rx, *final_tx]),
+        };
+
+        buf[PRELUDE.len()] = message_type;
+
+        let offset = PRELUDE.len() + 1;
+        for (i, timestamp) in timestamps.iter().enumerate() {
+            let start = offset + i * TIMESTAMP_LEN;
+            buf[start..start + TIMESTAMP_LEN]
+                .copy_from_slice(&timestamp.to_le_bytes()[..TIMESTAMP_LEN]);
+        }
+
+        offset + timestamps.len() * TIMESTAMP_LEN
+    }
+
+    /// Decode a frame payload, `None` if it is not a message of the double-sided exchange
+    pub fn decode(payload: &[u8]) -> Option<Self> {
+        if !payload.starts_with(&PRELUDE) {
+            return None;
+        }
+
+        let offset = PRELUDE.len() + 1;
+        let timestamp = |i: usize| {
+            let start = offset + i * TIMESTAMP_LEN;
+            let mut bytes = [0; 8];
+            bytes[..TIMESTAMP_LEN].copy_from_slice(&payload[start..start + TIMESTAMP_LEN]);
+            u64::from_le_bytes(bytes)
+        };
+        let len = |timestamps: usize| offset + timestamps * TIMESTAMP_LEN;
+
+        match *payload.get(PRELUDE.len())? {
+            0 if payload.len() == len(0) => Some(TwrMessage::Poll),
+            1 if payload.len() == len(2) => Some(TwrMessage::Response {
+                poll_rx: timestamp(0),
+                response_tx: timestamp(1),
+            }),
+            2 if payload.len() == len(3) => Some(TwrMessage::Final {
+                poll_tx: timestamp(0),
+                response_rx: timestamp(1),
+                final_tx: timestamp(2),
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICKS_PER_SECOND: f64 = 499.2e6 * 128.0;
+
+    /// Local clock of a node with a drift in ppm and an offset in s
+    fn local(t: f64, drift_ppm: f64, offset: f64) -> u64 {
+        ((t * (1.0 + drift_ppm * 1e-6) + offset) * TICKS_PER_SECOND) as u64 & TIMESTAMP_MASK
+    }
+
+    /// Exchange over `distance` m with the given reply times of initiator and responder in s
+    fn exchange(
+        distance: f64,
+        initiator: (f64, f64),
+        responder: (f64, f64),
+        replies: (f64, f64),
+    ) -> Timestamps {
+        let time_of_flight = distance / 299_792_458.0;
+        let poll_tx = 0.1;
+        let poll_rx = poll_tx + time_of_flight;
+        let response_tx = poll_rx + replies.1;
+        let response_rx = response_tx + time_of_flight;
+        let final_tx = response_rx + replies.0;
+        let final_rx = final_tx + time_of_flight;
+
+        Timestamps {
+            poll_tx: local(poll_tx, initiator.0, initiator.1),
+            poll_rx: local(poll_rx, responder.0, responder.1),
+            response_tx: local(response_tx, responder.0, responder.1),
+            response_rx: local(response_rx, initiator.0, initiator.1),
+            final_tx: local(final_tx, initiator.0, initiator.1),
+            final_rx: local(final_rx, responder.0, responder.1),
+        }
+    }
+
+    #[test]
+    fn messages_roundtrip() {
+        let messages = [
+            TwrMessage::Poll,
+            TwrMessage::Response {
+                poll_rx: 1,
+                response_tx: 0xff_ffff_fffe,
+            },
+            TwrMessage::Final {
+                poll_tx: 0x12_3456_789a,
+                response_rx: TIMESTAMP_MASK,
+                final_tx: 0,
+            },
+        ];
+
+        for message in messages.iter() {
+            let mut buf = [0; TwrMessage::MAX_LEN];
+            let len = message.encode(&mut buf);
+            assert_eq!(TwrMessage::decode(&buf[..len]), Some(*message));
+        }
+    }
+
+    #[test]
+    fn foreign_payloads_are_ignored() {
+        assert_eq!(TwrMessage::decode(&[]), None);
+        assert_eq!(TwrMessage::decode(b"RANGING PING"), None);
+        assert_eq!(TwrMessage::decode(b"BDI2\x03"), None);
+        assert_eq!(TwrMessage::decode(b"BDI2\x02\x00"), None);
+    }
+
+    #[test]
+    fn distance_with_perfect_clocks() {
+        let timestamps = exchange(5.0, (0.0, 0.0), (0.0, 0.0), (3e-3, 1e-3));
+
+        // 0.16% too short because a tick is slightly longer than 1/64 ns
+        let distance_mm = timestamps.compute_distance_mm().unwrap();
+        assert!((4_985..=4_995).contains(&distance_mm), "{}", distance_mm);
+    }
+
+    #[test]
+    fn drift_cancels_out() {
+        // Very asymmetric reply times, which would be off by meters with single-sided ranging
+        let timestamps = exchange(5.0, (20.0, 0.3), (-20.0, 7.0), (5e-3, 1e-3));
+
+        let distance_mm = timestamps.compute_distance_mm().unwrap();
+        assert!((4_980..=5_000).contains(&distance_mm), "{}", distance_mm);
+    }
+
+    #[test]
+    fn timer_wrap_around() {
+        let wrap = (TIMESTAMP_MASK + 1) as f64 / TICKS_PER_SECOND;
+        // The timer of the initiator wraps between the poll and the response
+        let timestamps = exchange(2.0, (10.0, wrap - 0.1 - 1e-3), (0.0, 3.0), (2e-3, 2e-3));
+        assert!(timestamps.response_rx < timestamps.poll_tx);
+
+        let distance_mm = timestamps.compute_distance_mm().unwrap();
+        assert!((1_985..=2_000).contains(&distance_mm), "{}", distance_mm);
+    }
+
+    #[test]
+    fn inconsistent_timestamps() {
+        let mut timestamps = exchange(2.0, (0.0, 0.0), (0.0, 0.0), (2e-3, 2e-3));
+        timestamps.final_rx = timestamps.response_tx;
+
+        assert_eq!(timestamps.compute_distance_mm(), None);
+    }
+
+    #[test]
+    fn delayed_tx_time_wraps() {
+        let tx_time = delayed_tx_time(TIMESTAMP_MASK, 0);
+        assert_eq!(tx_time & 0x1ff, 0);
+        assert_eq!(duration(TIMESTAMP_MASK, tx_time), TX_DELAY - 0x1ff);
+
+        let tx_time = delayed_tx_time(TIMESTAMP_MASK, 2);
+        assert_eq!(
+            duration(TIMESTAMP_MASK, tx_time),
+            TX_DELAY + 2 * REPLY_SLOT - 0x1ff
+        );
+    }
+
+    #[test]
+    fn tx_timestamp_includes_antenna_delay() {
+        assert_eq!(tx_timestamp(0x1234_5678, 16_300), 0x1234_5600 + 16_300);
+        // The lower bits of the delayed TX time are truncated before the antenna delay is added
+        assert_eq!(tx_timestamp(0x1ff, 0x1ff), 0x1ff);
+        assert_eq!(tx_timestamp(TIMESTAMP_MASK, 0x200), 0);
+    }
+
+    #[test]
+    fn delayed_request_keeps_antenna_delay() {
+        let times = RequestTimes {
+            tx_time: TIMESTAMP_MASK - 1_000,
+            // Antenna delay of 16300 ticks
+            request_tx: 15_299,
+            ping_reply: TX_DELAY,
+        };
+
+        let delayed = times.delay(3);
+        assert_eq!(
+            duration(delayed.tx_time, delayed.request_tx),
+            duration(times.tx_time, times.request_tx)
+        );
+        assert_eq!(duration(times.tx_time, delayed.tx_time), 3 * REPLY_SLOT);
+        assert_eq!(delayed.ping_reply, TX_DELAY + 3 * REPLY_SLOT);
+
+        assert_eq!(times.delay(0), times);
+    }
+
+    /// Request of a tag `distance` m away that answers the ping at true time `t` s
+    fn request(t: f64, distance: f64, tag_drift_ppm: f64, reply: f64) -> RequestTimestamps {
+        let time_of_flight = distance / 299_792_458.0;
+        let ping_rx = t + time_of_flight;
+        let request_tx = ping_rx + reply;
+
+        RequestTimestamps {
+            ping_tx: local(t, 0.0, 0.0),
+            ping_reply: local(request_tx, tag_drift_ppm, 5.0) - local(ping_rx, tag_drift_ppm, 5.0),
+            request_tx: local(request_tx, tag_drift_ppm, 5.0),
+            request_rx: local(request_tx + time_of_flight, 0.0, 0.0),
+        }
+    }
+
+    #[test]
+    fn single_sided_with_drift_compensation() {
+        let mut estimator = DriftEstimator::new();
+
+        let first = request(1.0, 3.0, -20.0, 15e-3);
+        assert_eq!(estimator.update(&first), None);
+        // 15 ms * 20 ppm is 45 m
+        let distance_mm = first.compute_distance_mm(0).unwrap();
+        assert!(distance_mm > 40_000, "{}", distance_mm);
+
+        let second = request(1.6, 3.0, -20.0, 15e-3);
+        let drift_ppb = estimator.update(&second).unwrap();
+        assert!((-20_010..=-19_990).contains(&drift_ppb), "{}", drift_ppb);
+
+        let distance_mm = second.compute_distance_mm(drift_ppb).unwrap();
+        assert!((2_980..=3_010).contains(&distance_mm), "{}", distance_mm);
+    }
+
+    #[test]
+    fn implausible_drift_is_ignored() {
+        let mut estimator = DriftEstimator::new();
+
+        estimator.update(&request(1.0, 3.0, 20.0, 15e-3));
+        estimator.update(&request(1.6, 3.0, 20.0, 15e-3));
+        // Tag rebooted, its clock jumped
+        let mut jumped = request(2.2, 3.0, 20.0, 15e-3);
+        jumped.request_tx += 1_000_000_000;
+
+        let drift_ppb = estimator.update(&jumped).unwrap();
+        assert!((19_990..=20_010).contains(&drift_ppb), "{}", drift_ppb);
+
+        estimator.reset();
+        assert_eq!(estimator.get_drift_ppb(), None);
+    }
+}
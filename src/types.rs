@@ -1,9 +1,12 @@
+use crate::battery::BatteryMonitor;
+use crate::dw1000::{Dw1000Radio, Dw1000Wrapper};
 use dw1000::{Ready, Receiving, Sending, DW1000};
 use stm32f1xx_hal::adc::Adc;
-use stm32f1xx_hal::gpio::gpioa::{PA2, PA3, PA4, PA5, PA6, PA7};
+use stm32f1xx_hal::gpio::gpioa::{PA0, PA2, PA3, PA4, PA5, PA6, PA7};
 use stm32f1xx_hal::gpio::gpiob::{PB0, PB13, PB14, PB15};
-use stm32f1xx_hal::gpio::{Alternate, Analog, Floating, Input, Output, PushPull};
-use stm32f1xx_hal::pac::ADC1;
+use stm32f1xx_hal::gpio::{Alternate, Analog, Floating, Input, Output, PullUp, PushPull};
+use stm32f1xx_hal::pac::{ADC1, USART1};
+use stm32f1xx_hal::serial::{Rx, Tx};
 use stm32f1xx_hal::spi::{Spi1NoRemap, Spi2NoRemap};
 
 pub type DwSpiType = stm32f1xx_hal::spi::Spi<
@@ -27,7 +30,10 @@ pub type DwTypeReceiving = DW1000<DwSpiType, DwCsType, Receiving>;
 
 pub type DwIrqType = PB0<Input<Floating>>;
 
+pub type DwWrapperType = Dw1000Wrapper<Dw1000Radio>;
+
 pub type Led1Type = PA2<Output<PushPull>>;
+pub type ButtonType = PA0<Input<PullUp>>;
 pub type WsType = ws2812_spi::Ws2812<
     stm32f1xx_hal::spi::Spi<
         stm32f1xx_hal::pac::SPI2,
@@ -43,3 +49,7 @@ pub type WsType = ws2812_spi::Ws2812<
 
 pub type BatteryAdcType = Adc<ADC1>;
 pub type BatteryChType = PA3<Analog>;
+pub type BatteryMonitorType = BatteryMonitor<BatteryAdcType, BatteryChType, ADC1>;
+
+pub type SerialTxType = Tx<USART1>;
+pub type SerialRxType = Rx<USART1>;